@@ -0,0 +1,95 @@
+// Merge throughput for large books, run with `cargo bench --bench merge`.
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use rust_decimal::Decimal;
+
+use ob_aggregator_rs::helpers::orderbook_merger::{merge_asks, merge_bids};
+use ob_aggregator_rs::{Exchange, OrderBook};
+
+const LEVELS: u32 = 100_000;
+
+// Two exchanges with interleaving prices, asks ascending and bids descending
+fn books() -> (Vec<Vec<OrderBook>>, Vec<Vec<OrderBook>>) {
+    let side = |source: Exchange, offset: u32, ascending: bool| -> Vec<OrderBook> {
+        (0..LEVELS)
+            .map(|i| {
+                let step = if ascending { i } else { LEVELS - i };
+                OrderBook { price: Decimal::new((step * 2 + offset) as i64, 2), size: Decimal::ONE, source }
+            })
+            .collect()
+    };
+
+    (
+        vec![side(Exchange::Coinbase, 0, true), side(Exchange::Gemini, 1, true)],
+        vec![side(Exchange::Coinbase, 0, false), side(Exchange::Gemini, 1, false)],
+    )
+}
+
+fn bench_merge(c: &mut Criterion) {
+    let mut group = c.benchmark_group("merge_both_sides");
+    group.sample_size(20);
+
+    group.bench_function(BenchmarkId::new("sequential", LEVELS), |b| {
+        b.iter_batched(
+            books,
+            |(asks, bids)| (merge_asks(asks, false, false), merge_bids(bids, false, false)),
+            criterion::BatchSize::LargeInput,
+        )
+    });
+
+    // Mirrors main, which merges each side on its own blocking thread
+    group.bench_function(BenchmarkId::new("parallel", LEVELS), |b| {
+        b.iter_batched(
+            books,
+            |(asks, bids)| {
+                std::thread::scope(|scope| {
+                    let asks = scope.spawn(|| merge_asks(asks, false, false));
+                    let bids = scope.spawn(|| merge_bids(bids, false, false));
+                    (asks.join().unwrap(), bids.join().unwrap())
+                })
+            },
+            criterion::BatchSize::LargeInput,
+        )
+    });
+
+    group.finish();
+}
+
+// Exchange books normally arrive sorted, so the pre-sort is pure overhead
+fn bench_presort(c: &mut Criterion) {
+    let mut group = c.benchmark_group("merge_asks_sorted_input");
+    group.sample_size(20);
+
+    for (name, assume_sorted) in [("sort_first", false), ("assume_sorted", true)] {
+        group.bench_function(BenchmarkId::new(name, LEVELS), |b| {
+            b.iter_batched(|| books().0, |asks| merge_asks(asks, false, assume_sorted), criterion::BatchSize::LargeInput)
+        });
+    }
+
+    group.finish();
+}
+
+// Books that arrive out of order, large enough to take the parallel sort with --features rayon
+fn bench_unsorted(c: &mut Criterion) {
+    let mut group = c.benchmark_group("merge_asks_unsorted_input");
+    group.sample_size(20);
+
+    // A fixed shuffle, so every run sorts the same input
+    let shuffled = || {
+        books().0.into_iter().map(|mut book| {
+            let len = book.len();
+            for i in 0..len {
+                book.swap(i, (i * 7919 + 13) % len);
+            }
+            book
+        }).collect::<Vec<_>>()
+    };
+    let feature = if cfg!(feature = "rayon") { "rayon" } else { "sequential" };
+    group.bench_function(BenchmarkId::new(feature, LEVELS), |b| {
+        b.iter_batched(shuffled, |asks| merge_asks(asks, false, false), criterion::BatchSize::LargeInput)
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_merge, bench_presort, bench_unsorted);
+criterion_main!(benches);
@@ -1,11 +1,91 @@
 // Use reqwest to make HTTP requests
+use anyhow::{anyhow, Result};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
 use reqwest::Client;
+use std::env;
 use std::time::Duration;
 
-// Create a client to fetch the data from the APIs
-pub fn create_client() -> Client {
-    Client::builder()
-        .timeout(Duration::from_secs(30))
-        .build()
-        .expect("Failed to create HTTP client")
-}
\ No newline at end of file
+use super::types::Exchange;
+
+// Timeout used when none is configured
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Some exchanges throttle or reject requests without a recognisable User-Agent
+pub const USER_AGENT: &str = concat!(
+    env!("CARGO_PKG_NAME"),
+    "/",
+    env!("CARGO_PKG_VERSION"),
+    " (+https://github.com/Jkrish1011/orderbook-aggregator-rust)"
+);
+
+// Create a client to fetch the data from the APIs.
+// `timeout` applies to every request made with this client and `headers` are sent along with each one.
+// A User-Agent in `headers` replaces the default one.
+pub fn create_client(timeout: Duration, headers: HeaderMap) -> Client {
+    let builder = Client::builder()
+        .timeout(timeout)
+        .user_agent(USER_AGENT)
+        .default_headers(headers);
+
+    // Sends Accept-Encoding: gzip, br and decompresses the books that come back encoded
+    #[cfg(feature = "compression")]
+    let builder = builder.gzip(true).brotli(true);
+
+    builder.build().expect("Failed to create HTTP client")
+}
+
+// Parses a `Name: value` header given on the command line
+pub fn parse_header(s: &str) -> Result<(HeaderName, HeaderValue), String> {
+    let (name, value) = s.split_once(':').ok_or_else(|| format!("Expected Name: value, got {}", s))?;
+    let name = HeaderName::from_bytes(name.trim().as_bytes()).map_err(|e| format!("Invalid header name {}: {}", name.trim(), e))?;
+    let value = HeaderValue::from_str(value.trim()).map_err(|e| format!("Invalid value for header {}: {}", name, e))?;
+    Ok((name, value))
+}
+
+// Exchange API keys, only ever sent to the exchange they belong to.
+// Authenticated requests get higher rate limits on the public book endpoints.
+#[derive(Debug, Clone, Default)]
+pub struct ApiKeys {
+    pub coinbase: Option<HeaderValue>,
+    pub gemini: Option<HeaderValue>,
+    pub binance: Option<HeaderValue>,
+}
+
+impl ApiKeys {
+    // Reads COINBASE_API_KEY, GEMINI_API_KEY and BINANCE_API_KEY, all optional. Blank ones are ignored.
+    pub fn from_env() -> Result<Self> {
+        let key = |name: &str| -> Result<Option<HeaderValue>> {
+            match env::var(name) {
+                Ok(key) if !key.trim().is_empty() => {
+                    let mut value = HeaderValue::from_str(key.trim()).map_err(|e| anyhow!("{} is not a valid header value: {}", name, e))?;
+                    value.set_sensitive(true);
+                    Ok(Some(value))
+                }
+                _ => Ok(None),
+            }
+        };
+
+        Ok(ApiKeys {
+            coinbase: key("COINBASE_API_KEY")?,
+            gemini: key("GEMINI_API_KEY")?,
+            binance: key("BINANCE_API_KEY")?,
+        })
+    }
+
+    // Headers to add to requests going to `exchange`. Empty when it has no key.
+    pub fn headers(&self, exchange: Exchange) -> HeaderMap {
+        let (name, key) = match exchange {
+            Exchange::Coinbase => ("cb-access-key", &self.coinbase),
+            Exchange::Gemini => ("x-gemini-apikey", &self.gemini),
+            Exchange::Binance => ("x-mbx-apikey", &self.binance),
+            // The public book needs no key
+            Exchange::Bitstamp => return HeaderMap::new(),
+        };
+
+        let mut headers = HeaderMap::new();
+        if let Some(key) = key {
+            headers.insert(HeaderName::from_static(name), key.clone());
+        }
+        headers
+    }
+}
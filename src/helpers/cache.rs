@@ -0,0 +1,80 @@
+use std::fs;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use tracing::{info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use super::types::Exchange;
+
+// On-disk copy of the last successful response per exchange and symbol.
+// Lets a transient fetch failure reuse the previous snapshot instead of an empty book.
+#[derive(Debug, Clone)]
+pub struct BookCache {
+    dir: PathBuf,
+    stale_after: Duration,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct CacheEntry {
+    fetched_at_ms: u64,
+    data: Value,
+}
+
+// A cached book that was used in place of a failed fetch
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CacheHit {
+    pub exchange: Exchange,
+    pub age: Duration,
+    pub stale: bool,
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or_default()
+}
+
+impl BookCache {
+    pub fn new(dir: PathBuf, stale_after: Duration) -> Self {
+        BookCache { dir, stale_after }
+    }
+
+    fn path(&self, exchange: Exchange, symbol: &str) -> PathBuf {
+        self.dir.join(format!("{}-{}.json", exchange.to_string().to_lowercase(), symbol))
+    }
+
+    pub fn store(&self, exchange: Exchange, symbol: &str, data: &Value) -> Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        let entry = CacheEntry { fetched_at_ms: now_ms(), data: data.clone() };
+        fs::write(self.path(exchange, symbol), serde_json::to_vec(&entry)?)?;
+        Ok(())
+    }
+
+    pub fn load(&self, exchange: Exchange, symbol: &str) -> Option<(Value, CacheHit)> {
+        let bytes = fs::read(self.path(exchange, symbol)).ok()?;
+        let entry: CacheEntry = serde_json::from_slice(&bytes).ok()?;
+        let age = Duration::from_millis(now_ms().saturating_sub(entry.fetched_at_ms));
+
+        Some((entry.data, CacheHit { exchange, age, stale: age > self.stale_after }))
+    }
+
+    // Falls back to the cached response when the fetch failed, returning the original error if there is nothing cached.
+    // A successful response is passed through as is. It's up to the caller to store it once it's known to be usable.
+    pub fn with_fallback(&self, exchange: Exchange, symbol: &str, result: Result<Value>) -> (Result<Value>, Option<CacheHit>) {
+        match result {
+            Ok(data) => (Ok(data), None),
+            Err(e) => match self.load(exchange, symbol) {
+                Some((data, hit)) => {
+                    if hit.stale {
+                        warn!("{} fetch failed ({}), using a stale cached book from {:?} ago", exchange, e, hit.age);
+                    } else {
+                        info!("{} fetch failed ({}), using the cached book from {:?} ago", exchange, e, hit.age);
+                    }
+                    (Ok(data), Some(hit))
+                }
+                None => (Err(e), None),
+            },
+        }
+    }
+}
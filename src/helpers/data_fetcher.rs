@@ -1,24 +1,220 @@
-use reqwest::Client;
+use reqwest::{header::{HeaderMap, HeaderName}, Client, StatusCode};
 use serde_json::Value;
-use anyhow::{Result, bail};
-use std::time::Duration;
+use anyhow::Result;
+use std::fmt;
+use std::time::{Duration, Instant};
+use tracing::{debug, info};
+
+use crate::helpers::metrics;
+use crate::helpers::rate_limiter::RateLimiter;
+
+// How many times a 429 is retried in adaptive mode before giving up
+const MAX_RATE_LIMITED_RETRIES: u32 = 3;
+// First wait of get_data_with_retries, doubled on every further attempt up to MAX_BACKOFF
+const BASE_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_BACKOFF: Duration = Duration::from_secs(10);
+// Longest Retry-After taken from an exchange, so a bogus header can't stall every later request
+pub const MAX_RETRY_AFTER: Duration = Duration::from_secs(300);
+
+// Error returned when the exchange answers with a non-success status
+#[derive(Debug)]
+pub struct RequestFailed {
+    pub status: StatusCode,
+    pub body: String,
+    // Parsed from the Retry-After header (delay in seconds) when the exchange sends one, at most MAX_RETRY_AFTER
+    pub retry_after: Option<Duration>,
+}
+
+impl fmt::Display for RequestFailed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Request failed: {} - {}", self.status, self.body)
+    }
+}
+
+impl std::error::Error for RequestFailed {}
+
+// Rate limit headers kept by get_data_detailed, when the exchange sends them
+pub const RATE_LIMIT_HEADERS: [&str; 3] = ["x-ratelimit-limit", "x-ratelimit-remaining", "x-ratelimit-reset"];
+
+// A successful response along with what's needed to see how much request quota is left
+#[derive(Debug)]
+pub struct DetailedResponse {
+    pub data: Value,
+    pub status: StatusCode,
+    // Only the RATE_LIMIT_HEADERS the exchange sent
+    pub headers: HeaderMap,
+}
+
+impl DetailedResponse {
+    // Requests left in the current window, from X-RateLimit-Remaining
+    pub fn rate_limit_remaining(&self) -> Option<u64> {
+        self.headers.get("x-ratelimit-remaining")?.to_str().ok()?.trim().parse().ok()
+    }
+}
+
+// True if the error is a 429 Too Many Requests from the exchange
+pub fn is_rate_limited(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<RequestFailed>()
+        .is_some_and(|e| e.status == StatusCode::TOO_MANY_REQUESTS)
+}
+
+// True if the body arrived but was not the JSON expected, from the exchange or a book file
+pub fn is_parse_error(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<serde_json::Error>().is_some()
+        || err.downcast_ref::<reqwest::Error>().is_some_and(reqwest::Error::is_decode)
+}
+
+// 429s, 5xxs and network failures are worth retrying. Other 4xxs and bad JSON are not.
+fn is_retryable(err: &anyhow::Error) -> bool {
+    if let Some(e) = err.downcast_ref::<RequestFailed>() {
+        return e.status == StatusCode::TOO_MANY_REQUESTS || e.status.is_server_error();
+    }
+    err.downcast_ref::<reqwest::Error>().is_some_and(|e| !e.is_decode())
+}
 
 /*
     Taking parameters as &str is more memory efficient and doesn't require ownership movement.
+    `headers` are added to this request on top of the client's default headers.
 */
-pub async fn get_data(client: &Client, url: &str) -> Result<Value> {
+pub async fn get_data(client: &Client, url: &str, headers: &HeaderMap) -> Result<Value> {
+    get_data_detailed(client, url, headers).await.map(|response| response.data)
+}
+
+// Same as get_data, but also returns the status and the rate limit headers
+pub async fn get_data_detailed(client: &Client, url: &str, headers: &HeaderMap) -> Result<DetailedResponse> {
+    let started = Instant::now();
+    let result = fetch(client, url, headers).await;
+
+    // Requests are labelled by host, which tells the exchanges apart
+    let host = reqwest::Url::parse(url).ok().and_then(|url| url.host_str().map(str::to_string)).unwrap_or_default();
+    metrics::observe_fetch(&host, started.elapsed(), result.is_ok());
+    debug!("GET {} finished in {:?}, ok: {}", url, started.elapsed(), result.is_ok());
+
+    result
+}
+
+async fn fetch(client: &Client, url: &str, headers: &HeaderMap) -> Result<DetailedResponse> {
+    // The request timeout comes from the client, see api_client::create_client
     let response = client
         .get(url)
-        .timeout(Duration::from_secs(60))
+        .headers(headers.clone())
         .send()
         .await?;
 
     if !response.status().is_success() {
         let status = response.status();
-        let text = response.text().await.unwrap_or_default();
-        bail!("Request failed: {} - {}", status, text);
+        let retry_after = response
+            .headers()
+            .get(reqwest::header::RETRY_AFTER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.trim().parse::<u64>().ok())
+            .map(|secs| Duration::from_secs(secs).min(MAX_RETRY_AFTER));
+        let body = response.text().await.unwrap_or_default();
+        return Err(RequestFailed { status, body, retry_after }.into());
     }
 
+    let status = response.status();
+    let rate_limit_headers = RATE_LIMIT_HEADERS
+        .iter()
+        .filter_map(|name| Some((HeaderName::from_static(name), response.headers().get(*name)?.clone())))
+        .collect();
     let data = response.json::<Value>().await?;
-    Ok(data)
-}
\ No newline at end of file
+    Ok(DetailedResponse { data, status, headers: rate_limit_headers })
+}
+
+// Wait before retry number `attempt + 1`, without overflowing however many retries were asked for
+pub fn backoff(attempt: u32) -> Duration {
+    let factor = 2u32.checked_pow(attempt).unwrap_or(u32::MAX);
+    BASE_BACKOFF.saturating_mul(factor).min(MAX_BACKOFF)
+}
+
+/*
+    Retries transient failures (429, 5xx, network errors) up to `max_retries` times.
+    Waits 100ms, 200ms, 400ms, ... up to 10s between attempts unless the exchange sent a Retry-After,
+    which is waited out for at most MAX_RETRY_AFTER.
+*/
+pub async fn get_data_with_retries(client: &Client, url: &str, headers: &HeaderMap, max_retries: u32) -> Result<Value> {
+    let mut attempt = 0;
+
+    loop {
+        match get_data_detailed(client, url, headers).await {
+            Ok(response) => {
+                if let Some(remaining) = response.rate_limit_remaining() {
+                    debug!("{} answered {} with {} requests left", url, response.status, remaining);
+                }
+                return Ok(response.data);
+            }
+            Err(e) if attempt < max_retries && is_retryable(&e) => {
+                let wait = e
+                    .downcast_ref::<RequestFailed>()
+                    .and_then(|failed| failed.retry_after)
+                    .map_or_else(|| backoff(attempt), |retry_after| retry_after.min(MAX_RETRY_AFTER));
+                attempt += 1;
+                info!("Request to {} failed: {}. Retrying in {:?} ({}/{})", url, e, wait, attempt, max_retries);
+                tokio::time::sleep(wait).await;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/*
+    Waits on the rate limiter before every request, retries included, so no retry skips the token bucket.
+    Transient failures are retried up to `max_retries` times. A Retry-After on a failure is passed on to
+    the limiter, see RateLimiter::penalize, and the next acquire waits it out. Without one the usual backoff applies.
+    With `adaptive` set, every 429 tightens the limiter before it is retried, at most MAX_RATE_LIMITED_RETRIES times
+    and never out of `max_retries`, and a success relaxes it back towards its configured rate.
+*/
+pub async fn get_data_rate_limited(
+    client: &Client,
+    url: &str,
+    headers: &HeaderMap,
+    rate_limiter: &RateLimiter,
+    adaptive: bool,
+    max_retries: u32,
+) -> Result<Value> {
+    let mut attempt = 0;
+    let mut rate_limited = 0;
+
+    loop {
+        rate_limiter.acquire().await;
+
+        let e = match get_data_detailed(client, url, headers).await {
+            Ok(response) => {
+                if let Some(remaining) = response.rate_limit_remaining() {
+                    debug!("{} answered {} with {} requests left", url, response.status, remaining);
+                }
+                if adaptive {
+                    rate_limiter.relax().await;
+                }
+                return Ok(response.data);
+            }
+            Err(e) => e,
+        };
+
+        // Hold every later request back for as long as the exchange asked
+        let retry_after = e.downcast_ref::<RequestFailed>().and_then(|failed| failed.retry_after);
+        if let Some(retry_after) = retry_after {
+            info!("{} asked to wait {:?} before the next request", url, retry_after);
+            rate_limiter.penalize(retry_after).await;
+        }
+
+        if adaptive && is_rate_limited(&e) && rate_limited < MAX_RATE_LIMITED_RETRIES {
+            rate_limiter.tighten().await;
+            rate_limited += 1;
+            info!(
+                "Rate limited by {}. Retrying at {} requests/sec ({}/{})",
+                url, rate_limiter.tokens_per_second().await, rate_limited, MAX_RATE_LIMITED_RETRIES
+            );
+        } else if attempt < max_retries && is_retryable(&e) && !(adaptive && is_rate_limited(&e)) {
+            // A Retry-After is already waited out by the next acquire
+            if retry_after.is_none() {
+                tokio::time::sleep(backoff(attempt)).await;
+            }
+            attempt += 1;
+            info!("Request to {} failed: {}. Retrying ({}/{})", url, e, attempt, max_retries);
+        } else {
+            return Err(e);
+        }
+    }
+}
@@ -0,0 +1,180 @@
+#[cfg(any(feature = "coinbase", feature = "gemini"))]
+use std::time::UNIX_EPOCH;
+#[cfg(feature = "gemini")]
+use std::time::SystemTime;
+
+use anyhow::{anyhow, Result};
+use serde::de::DeserializeOwned;
+use tracing::info;
+#[cfg(feature = "gemini")]
+use tracing::warn;
+use serde_json::{from_value, Value};
+
+use super::symbol;
+use super::types::{check_precision, BinanceResult, BitstampResult, Exchange, NormalizedBook, OrderBook};
+#[cfg(feature = "coinbase")]
+use super::types::CoinbaseResult;
+#[cfg(feature = "gemini")]
+use super::types::{book_age, GeminiResult};
+
+// Where to fetch an exchange's REST book and how to read it.
+// Supporting another exchange means implementing this and listing it in Endpoints::exchanges.
+pub trait ExchangeAdapter: Send + Sync {
+    // Tag put on every level from this exchange
+    fn name(&self) -> Exchange;
+
+    // Book URL for a BASE-QUOTE symbol
+    fn endpoint(&self, symbol: &str) -> String;
+
+    // The response as (asks ascending, bids descending).
+    // With `strict`, a price or size with more digits than a Decimal holds fails the parse instead of being rounded.
+    fn parse(&self, data: Value, strict: bool) -> Result<(Vec<OrderBook>, Vec<OrderBook>)>;
+
+    // Unix seconds of the newest data in the response, for --max-age. None if the exchange doesn't say.
+    fn last_updated(&self, _data: &Value) -> Option<u64> {
+        None
+    }
+
+    // Sequence number and time that identify the snapshot, for exchanges that send them
+    fn snapshot(&self, _data: &Value) -> Option<(u64, String)> {
+        None
+    }
+}
+
+// Deserializes a book response, checking the precision of its levels first when `strict`
+fn from_book<T: DeserializeOwned>(data: Value, strict: bool) -> Result<T> {
+    if strict {
+        check_precision(&data).map_err(|e| anyhow!(e))?;
+    }
+    Ok(from_value(data)?)
+}
+
+fn sides(book: NormalizedBook) -> (Vec<OrderBook>, Vec<OrderBook>) {
+    (book.asks, book.bids)
+}
+
+#[cfg(feature = "gemini")]
+fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default()
+}
+
+// `template` may hold a {symbol} placeholder, see symbol::endpoint
+#[cfg(feature = "coinbase")]
+pub struct Coinbase {
+    pub template: String,
+}
+
+#[cfg(feature = "coinbase")]
+impl ExchangeAdapter for Coinbase {
+    fn name(&self) -> Exchange {
+        Exchange::Coinbase
+    }
+
+    fn endpoint(&self, sym: &str) -> String {
+        symbol::endpoint(&self.template, &symbol::to_coinbase(sym))
+    }
+
+    fn parse(&self, data: Value, strict: bool) -> Result<(Vec<OrderBook>, Vec<OrderBook>)> {
+        let result: CoinbaseResult = from_book(data, strict)?;
+        Ok(sides(result.into()))
+    }
+
+    fn last_updated(&self, data: &Value) -> Option<u64> {
+        let time = humantime::parse_rfc3339_weak(data["time"].as_str()?).ok()?;
+        time.duration_since(UNIX_EPOCH).ok().map(|d| d.as_secs())
+    }
+
+    fn snapshot(&self, data: &Value) -> Option<(u64, String)> {
+        Some((data["sequence"].as_u64()?, data["time"].as_str()?.to_string()))
+    }
+}
+
+#[cfg(feature = "gemini")]
+pub struct Gemini {
+    pub template: String,
+    // Warn when the oldest level on a side is older than this, in seconds
+    pub stale_secs: Option<u64>,
+}
+
+#[cfg(feature = "gemini")]
+impl ExchangeAdapter for Gemini {
+    fn name(&self) -> Exchange {
+        Exchange::Gemini
+    }
+
+    fn endpoint(&self, sym: &str) -> String {
+        symbol::endpoint(&self.template, &symbol::to_gemini(sym))
+    }
+
+    fn parse(&self, data: Value, strict: bool) -> Result<(Vec<OrderBook>, Vec<OrderBook>)> {
+        let result: GeminiResult = from_book(data, strict)?;
+
+        for (side, orders) in [("bids", &result.bids), ("asks", &result.asks)] {
+            if let Some((oldest, newest)) = book_age(orders) {
+                info!("Gemini {} timestamps: oldest {}, newest {}", side, oldest, newest);
+                let age = now_secs().saturating_sub(oldest);
+                if let Some(max_age) = self.stale_secs
+                    && age > max_age
+                {
+                    warn!("Gemini {} look stale: the oldest level is {}s old", side, age);
+                }
+            }
+        }
+
+        Ok(sides(result.into()))
+    }
+
+    // Every level carries its own timestamp, sent as a string of unix seconds
+    fn last_updated(&self, data: &Value) -> Option<u64> {
+        ["bids", "asks"]
+            .iter()
+            .filter_map(|side| data[side].as_array())
+            .flatten()
+            .filter_map(|level| level["timestamp"].as_str()?.parse::<u64>().ok())
+            .max()
+    }
+}
+
+pub struct Binance {
+    pub template: String,
+}
+
+impl ExchangeAdapter for Binance {
+    fn name(&self) -> Exchange {
+        Exchange::Binance
+    }
+
+    fn endpoint(&self, sym: &str) -> String {
+        symbol::endpoint(&self.template, &symbol::to_binance(sym))
+    }
+
+    fn parse(&self, data: Value, strict: bool) -> Result<(Vec<OrderBook>, Vec<OrderBook>)> {
+        let result: BinanceResult = from_book(data, strict)?;
+        info!("Binance last update id: {}", result.last_update_id);
+        Ok(sides(result.into()))
+    }
+}
+
+pub struct Bitstamp {
+    pub template: String,
+}
+
+impl ExchangeAdapter for Bitstamp {
+    fn name(&self) -> Exchange {
+        Exchange::Bitstamp
+    }
+
+    fn endpoint(&self, sym: &str) -> String {
+        symbol::endpoint(&self.template, &symbol::to_bitstamp(sym))
+    }
+
+    fn parse(&self, data: Value, strict: bool) -> Result<(Vec<OrderBook>, Vec<OrderBook>)> {
+        let result: BitstampResult = from_book(data, strict)?;
+        info!("Bitstamp book at {}us", result.microtimestamp);
+        Ok(sides(result.into()))
+    }
+
+    fn last_updated(&self, data: &Value) -> Option<u64> {
+        data["timestamp"].as_str()?.parse().ok()
+    }
+}
@@ -0,0 +1,45 @@
+use std::{
+    fs::{self, File},
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use rust_decimal::Decimal;
+use serde_json::Value;
+
+use super::types::{Exchange, OrderBook};
+
+// Writes the merged book to `path` as `side,price,size,cumulative_size`.
+// The header is always written, even when both sides are empty.
+pub fn write_csv(path: &Path, asks: &[OrderBook], bids: &[OrderBook]) -> std::io::Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    write_book(&mut writer, asks, bids)?;
+    writer.flush()
+}
+
+pub fn write_book<W: Write>(writer: &mut W, asks: &[OrderBook], bids: &[OrderBook]) -> std::io::Result<()> {
+    writeln!(writer, "side,price,size,cumulative_size")?;
+    write_side(writer, "ask", asks)?;
+    write_side(writer, "bid", bids)
+}
+
+// Levels are expected best price first so the cumulative size grows away from the top of the book
+fn write_side<W: Write>(writer: &mut W, side: &str, levels: &[OrderBook]) -> std::io::Result<()> {
+    let mut cumulative = Decimal::ZERO;
+    for level in levels {
+        cumulative += level.size;
+        writeln!(writer, "{},{},{},{}", side, level.price, level.size, cumulative)?;
+    }
+    Ok(())
+}
+
+// Saves an exchange's raw response as `{exchange}-{symbol}-{unix ms}.json` in `dir`, creating it if needed.
+// The file can be replayed as is with --coinbase-file or --gemini-file.
+pub fn record_response(dir: &Path, exchange: Exchange, symbol: &str, data: &Value) -> std::io::Result<PathBuf> {
+    fs::create_dir_all(dir)?;
+    let fetched_at_ms = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis()).unwrap_or_default();
+    let path = dir.join(format!("{}-{}-{}.json", exchange.to_string().to_lowercase(), symbol, fetched_at_ms));
+    fs::write(&path, serde_json::to_vec(data)?)?;
+    Ok(path)
+}
@@ -0,0 +1,112 @@
+use std::net::SocketAddr;
+use std::sync::LazyLock;
+use std::time::Duration;
+
+use anyhow::Result;
+use tracing::{info, warn};
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, HistogramVec, IntCounterVec, IntGaugeVec, Opts, Registry, TextEncoder,
+};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+// Everything exposed on --metrics-port lives in this registry
+static REGISTRY: LazyLock<Registry> = LazyLock::new(Registry::new);
+
+static FETCH_DURATION: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register(HistogramVec::new(
+        HistogramOpts::new("ob_fetch_duration_seconds", "Time taken by a single exchange request"),
+        &["exchange"],
+    ))
+});
+
+static FETCH_TOTAL: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register(IntCounterVec::new(
+        Opts::new("ob_fetch_total", "Exchange requests by outcome"),
+        &["exchange", "outcome"],
+    ))
+});
+
+static MERGE_DURATION: LazyLock<Histogram> = LazyLock::new(|| {
+    register(Histogram::with_opts(HistogramOpts::new(
+        "ob_merge_duration_seconds",
+        "Time taken to merge the exchange books",
+    )))
+});
+
+static MERGED_LEVELS: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    register(IntGaugeVec::new(
+        Opts::new("ob_merged_levels", "Number of levels in the last merged book"),
+        &["side"],
+    ))
+});
+
+fn register<M: prometheus::core::Collector + Clone + 'static>(metric: prometheus::Result<M>) -> M {
+    let metric = metric.expect("metric definitions are valid");
+    REGISTRY.register(Box::new(metric.clone())).expect("metrics are registered once");
+    metric
+}
+
+// Records one exchange request. `exchange` is the host the request went to.
+pub fn observe_fetch(exchange: &str, elapsed: Duration, success: bool) {
+    FETCH_DURATION.with_label_values(&[exchange]).observe(elapsed.as_secs_f64());
+    let outcome = if success { "success" } else { "failure" };
+    FETCH_TOTAL.with_label_values(&[exchange, outcome]).inc();
+}
+
+// Records one merge and the size of the sides it produced
+pub fn observe_merge(elapsed: Duration, asks: Option<usize>, bids: Option<usize>) {
+    MERGE_DURATION.observe(elapsed.as_secs_f64());
+    for (side, levels) in [("ask", asks), ("bid", bids)] {
+        if let Some(levels) = levels {
+            MERGED_LEVELS.with_label_values(&[side]).set(levels as i64);
+        }
+    }
+}
+
+// Current metrics in the Prometheus text format
+pub fn render() -> String {
+    let mut buffer = Vec::new();
+    if let Err(e) = TextEncoder::new().encode(&REGISTRY.gather(), &mut buffer) {
+        warn!("Failed to encode metrics: {}", e);
+    }
+    String::from_utf8(buffer).unwrap_or_default()
+}
+
+// Starts serving the metrics over plain HTTP in the background and returns the bound address.
+// Every request gets the metrics back, whatever its path.
+pub async fn serve(port: u16) -> Result<SocketAddr> {
+    let listener = TcpListener::bind(("0.0.0.0", port)).await?;
+    let addr = listener.local_addr()?;
+    info!("Serving metrics on http://{}/metrics", addr);
+
+    tokio::spawn(async move {
+        loop {
+            let (mut socket, _) = match listener.accept().await {
+                Ok(connection) => connection,
+                Err(e) => {
+                    warn!("Metrics connection failed: {}", e);
+                    continue;
+                }
+            };
+
+            tokio::spawn(async move {
+                // The request itself is not inspected, read it so the client sees a clean response
+                let mut request = [0u8; 1024];
+                let _ = socket.read(&mut request).await;
+
+                let body = render();
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                );
+                if let Err(e) = socket.write_all(response.as_bytes()).await {
+                    warn!("Failed to send metrics: {}", e);
+                }
+            });
+        }
+    });
+
+    Ok(addr)
+}
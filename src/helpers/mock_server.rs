@@ -0,0 +1,80 @@
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use serde_json::Value;
+use tracing::{info, warn};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+// Static books served by `serve-mock`, as the raw JSON each exchange would send
+pub struct MockBooks {
+    pub coinbase: String,
+    pub gemini: String,
+}
+
+impl MockBooks {
+    // Reads the fixtures up front so a bad file fails at startup rather than on the first request
+    pub fn load(coinbase: &Path, gemini: &Path) -> Result<Self> {
+        let read = |path: &Path| -> Result<String> {
+            let body = std::fs::read_to_string(path).with_context(|| format!("Failed to read {}", path.display()))?;
+            serde_json::from_str::<Value>(&body).with_context(|| format!("{} is not valid JSON", path.display()))?;
+            Ok(body)
+        };
+        Ok(MockBooks { coinbase: read(coinbase)?, gemini: read(gemini)? })
+    }
+
+    // Book for a request path, ignoring any query string. None for an unknown route.
+    fn route(&self, path: &str) -> Option<&str> {
+        match path.split('?').next()? {
+            "/coinbase" => Some(&self.coinbase),
+            "/gemini" => Some(&self.gemini),
+            _ => None,
+        }
+    }
+}
+
+// Starts serving the books in the background on localhost and returns the bound address.
+// Coinbase's book is on /coinbase and Gemini's on /gemini, anything else is a 404.
+pub async fn serve(port: u16, books: MockBooks) -> Result<SocketAddr> {
+    let listener = TcpListener::bind(("127.0.0.1", port)).await?;
+    let addr = listener.local_addr()?;
+    info!("Serving mock exchanges on http://{}", addr);
+    let books = Arc::new(books);
+
+    tokio::spawn(async move {
+        loop {
+            let (mut socket, _) = match listener.accept().await {
+                Ok(connection) => connection,
+                Err(e) => {
+                    warn!("Mock exchange connection failed: {}", e);
+                    continue;
+                }
+            };
+
+            let books = Arc::clone(&books);
+            tokio::spawn(async move {
+                // Only the request line matters, e.g. `GET /coinbase HTTP/1.1`
+                let mut request = [0u8; 1024];
+                let read = socket.read(&mut request).await.unwrap_or_default();
+                let request = String::from_utf8_lossy(&request[..read]);
+                let path = request.split_whitespace().nth(1).unwrap_or_default();
+
+                let response = match books.route(path) {
+                    Some(body) => format!(
+                        "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        body.len(),
+                        body
+                    ),
+                    None => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string(),
+                };
+                if let Err(e) = socket.write_all(response.as_bytes()).await {
+                    warn!("Failed to send the mock book: {}", e);
+                }
+            });
+        }
+    });
+
+    Ok(addr)
+}
@@ -2,4 +2,12 @@ pub mod data_fetcher;
 pub mod api_client;
 pub mod types;
 pub mod orderbook_merger;
-pub mod rate_limiter;
\ No newline at end of file
+pub mod rate_limiter;
+pub mod symbol;
+pub mod export;
+pub mod stream;
+pub mod metrics;
+pub mod cache;
+pub mod exchange;
+pub mod mock_server;
+pub mod prefix_book;
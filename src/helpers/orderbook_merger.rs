@@ -1,185 +1,279 @@
-use rust_decimal::Decimal;
-use crate::helpers::types::{CoinbaseOrder, GeminiOrder, OrderBook};
-use log::{info};
+use rust_decimal::{Decimal, RoundingStrategy};
+use crate::helpers::prefix_book::PrefixBook;
+use crate::helpers::types::{Exchange, OrderBook, OrderBookDetailed};
+#[cfg(all(feature = "coinbase", feature = "gemini"))]
+use crate::helpers::types::{CoinbaseOrder, GeminiOrder};
+use tracing::{debug, info};
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BTreeMap, BinaryHeap};
+use std::fmt;
 
-// Merge sorted asks from both coinbase and gemini. Ascending Order
-// Using iterator for efficiency here. Not collecting here.
-pub fn merge_sorted_asks(coinbase_asks: Vec<CoinbaseOrder>,gemini_asks: Vec<GeminiOrder>) -> Vec<OrderBook> {
-    let mut merged: Vec<OrderBook> = Vec::with_capacity(coinbase_asks.len() + gemini_asks.len());
-
-    // Ensure inputs are sorted
-    let mut coinbase_asks = coinbase_asks;
-    let mut gemini_asks = gemini_asks;
-    coinbase_asks.sort_by(|a, b| a.price.partial_cmp(&b.price).unwrap());
-    gemini_asks.sort_by(|a, b| a.price.partial_cmp(&b.price).unwrap());
-
-    // Then proceed with merge...
-    let mut cb_iter = coinbase_asks.into_iter().peekable();
-    let mut gem_iter = gemini_asks.into_iter().peekable();
-
-    loop {
-        match (cb_iter.peek(), gem_iter.peek()) {
-            (Some(cb), Some(gem)) => {
-                if cb.price <=  gem.price {
-                    let order = cb_iter.next().unwrap();
-                    merged.push(OrderBook{
-                        price: order.price,
-                        size: order.size,
-                        name: "COINBASE".to_string()
-                    });
-                } else {
-                    let order = gem_iter.next().unwrap();
-                    merged.push(OrderBook {
-                        price: order.price,
-                        size: order.amount,
-                        name: "GEMINI".to_string()
-                    });
-                }
-                
-            }
-            (Some(_), None) => {
-                // Ony coinbase left
-                for order in cb_iter {
-                    merged.push(OrderBook {
-                        price: order.price,
-                        size: order.size,
-                        name: "COINBASE".to_string()
-                    });
-                }
-                break;
-            }
-            (None, Some(_)) => {
-                // Only Gemini Left
-                for order in gem_iter {
-                    merged.push(OrderBook {
-                        price: order.price,
-                        size: order.amount,
-                        name: "GEMINI".to_string()
-                    });
-                }
-                break;
-            }
-            (None, None) => {
-                break;
+// Reasons a fill price cannot be computed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PriceError {
+    // There are no levels on this side of the book
+    EmptyBook,
+    // The book does not hold enough size to fill the requested quantity
+    InsufficientLiquidity { requested: Decimal, available: Decimal },
+    // The level at `index` is out of order, so walking from the top would not take the best prices
+    Unsorted { index: usize },
+    // A notional or running total does not fit in a Decimal
+    Overflow,
+    // The best price is zero, so there is nothing to measure a fill against
+    ZeroPrice,
+}
+
+impl fmt::Display for PriceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PriceError::EmptyBook => write!(f, "the order book is empty"),
+            PriceError::InsufficientLiquidity { requested, available } => {
+                write!(f, "insufficient liquidity: requested {}, only {} available", requested, available)
             }
+            PriceError::Unsorted { index } => write!(f, "the order book is not sorted at level {}", index),
+            PriceError::Overflow => write!(f, "the fill total overflowed"),
+            PriceError::ZeroPrice => write!(f, "the best price is zero"),
         }
     }
-    merged
+}
+
+impl std::error::Error for PriceError {}
+
+// Merge sorted asks from both coinbase and gemini. Ascending Order
+// Thin wrapper over merge_asks kept for two exchange callers.
+#[cfg(all(feature = "coinbase", feature = "gemini"))]
+pub fn merge_sorted_asks(coinbase_asks: Vec<CoinbaseOrder>, gemini_asks: Vec<GeminiOrder>) -> Vec<OrderBook> {
+    merge_asks(vec![
+        coinbase_asks.into_iter().map(OrderBook::from).collect(),
+        gemini_asks.into_iter().map(OrderBook::from).collect(),
+    ], false, false)
 }
 
 // Merging sorted bids from Coinbase and Gemini. Descending price order.
+#[cfg(all(feature = "coinbase", feature = "gemini"))]
 pub fn merge_sorted_bids(coinbase_bids: Vec<CoinbaseOrder>, gemini_bids: Vec<GeminiOrder>) -> Vec<OrderBook> {
-    let mut merged = Vec::with_capacity(coinbase_bids.len() + gemini_bids.len());
-    
-    // Ensure inputs are sorted (descending)
-    let mut coinbase_bids = coinbase_bids;
-    let mut gemini_bids = gemini_bids;
-    coinbase_bids.sort_by(|a, b| b.price.partial_cmp(&a.price).unwrap()); // Descending
-    gemini_bids.sort_by(|a, b| b.price.partial_cmp(&a.price).unwrap());   // Descending
-
-// Then proceed with merge...
-
-    let mut cb_iter = coinbase_bids.into_iter().peekable();
-    let mut gem_iter = gemini_bids.into_iter().peekable();
-
-    loop {
-        match (cb_iter.peek(), gem_iter.peek()) {
-            (Some(cb), Some(gem)) => {
-                if cb.price >= gem.price {
-                    let order = cb_iter.next().unwrap();
-                    merged.push(OrderBook {
-                        price: order.price,
-                        size: order.size,
-                        name: "COINBASE".to_string()
-                    });
-                } else {
-                    let order = gem_iter.next().unwrap();
-                    merged.push(OrderBook{
-                        price: order.price,
-                        size: order.amount,
-                        name: "GEMINI".to_string()
-                    });
-                }
-                
-            }
-            (Some(_), None) => {
-                // Only coinbase left
-                for order in cb_iter {
-                    merged.push(OrderBook {
-                        price: order.price,
-                        size: order.size,
-                        name: "COINBASE".to_string()
-                    });
-                }
-                break;
-            }
-            (None, Some(_)) => {
-                // Only gemini order left.
-                for order in gem_iter {
-                    merged.push(OrderBook {
-                        price: order.price,
-                        size: order.amount,
-                        name: "GEMINI".to_string()
-                    });
-                }
-                break;
-            }
-            (None, None) => {
-                break;
-            }
+    merge_bids(vec![
+        coinbase_bids.into_iter().map(OrderBook::from).collect(),
+        gemini_bids.into_iter().map(OrderBook::from).collect(),
+    ], false, false)
+}
+
+// Same as merge_sorted_asks, but levels with the same price are combined into one.
+#[cfg(all(feature = "coinbase", feature = "gemini"))]
+pub fn merge_sorted_asks_consolidated(coinbase_asks: Vec<CoinbaseOrder>, gemini_asks: Vec<GeminiOrder>) -> Vec<OrderBook> {
+    consolidate(merge_sorted_asks(coinbase_asks, gemini_asks))
+}
+
+// Same as merge_sorted_bids, but levels with the same price are combined into one.
+#[cfg(all(feature = "coinbase", feature = "gemini"))]
+pub fn merge_sorted_bids_consolidated(coinbase_bids: Vec<CoinbaseOrder>, gemini_bids: Vec<GeminiOrder>) -> Vec<OrderBook> {
+    consolidate(merge_sorted_bids(coinbase_bids, gemini_bids))
+}
+
+// Combines consecutive levels sharing a price by summing their sizes.
+// The combined level keeps the source of the first level at that price.
+pub fn consolidate(merged: Vec<OrderBook>) -> Vec<OrderBook> {
+    let mut consolidated: Vec<OrderBook> = Vec::with_capacity(merged.len());
+
+    for order in merged {
+        match consolidated.last_mut() {
+            Some(last) if last.price == order.price => last.size += order.size,
+            _ => consolidated.push(order),
         }
     }
+
+    consolidated
+}
+
+// Drops levels that exactly repeat the one before them: same price, size and source.
+// Only redundant data from an exchange looks like that, so nothing real is lost.
+pub fn dedup_levels(mut merged: Vec<OrderBook>) -> Vec<OrderBook> {
+    let before = merged.len();
+    merged.dedup();
+    if merged.len() < before {
+        debug!("Dropped {} duplicate levels", before - merged.len());
+    }
     merged
 }
 
-pub fn calculate_entity_price(entity: &[OrderBook], quantity: Decimal, is_ascending: bool, order_type: &str) -> Result<Decimal, String> {
-    let mut total_cost = Decimal::ZERO;
-    let mut remaining_quantity = quantity;
-    let original_quantity = quantity;
-    let mut count = 0;
-    let mut total_size_available = Decimal::ZERO;
-    let mut tiny_orders = 0;
+// Merge any number of normalized ask books. Ascending price order.
+// With `skip_empty` zero size levels are dropped instead of being merged.
+// With `assume_sorted` the books are trusted to be sorted already and merged in a single linear pass.
+pub fn merge_asks(books: Vec<Vec<OrderBook>>, skip_empty: bool, assume_sorted: bool) -> Vec<OrderBook> {
+    merge_k_way(books, true, skip_empty, assume_sorted)
+}
 
-    let mut cb_count:Decimal = Decimal::ZERO;
-    let mut gm_count:Decimal = Decimal::ZERO;
+// Merge any number of normalized bid books. Descending price order.
+// With `skip_empty` zero size levels are dropped instead of being merged.
+// With `assume_sorted` the books are trusted to be sorted already and merged in a single linear pass.
+pub fn merge_bids(books: Vec<Vec<OrderBook>>, skip_empty: bool, assume_sorted: bool) -> Vec<OrderBook> {
+    merge_k_way(books, false, skip_empty, assume_sorted)
+}
 
+// Best price first: ascending for asks, descending for bids.
+// Asks sort the same as OrderBook's own Ord (price only), bids in exactly the reverse order.
+// Equal prices put the larger size first.
+fn price_order(is_ascending: bool) -> impl Fn(&OrderBook, &OrderBook) -> Ordering + Copy {
+    move |a, b| {
+        let by_price = if is_ascending { a.price.cmp(&b.price) } else { b.price.cmp(&a.price) };
+        by_price.then_with(|| b.size.cmp(&a.size))
+    }
+}
 
-    // Insignificant here. But just calculating very Tiny orders to identify any bugs of any sort.
-    for entry in entity.iter() {
-        total_size_available += entry.size;
-        // To check if BTC size is < 0.0001
-        if entry.size < Decimal::new(1, 4) {
-            tiny_orders += 1;
-        }
+// Rounds to the nearest multiple of `tick`, halfway values away from zero.
+// Rounding never reorders prices, so a sorted book stays sorted.
+pub fn round_to_tick(value: Decimal, tick: Decimal) -> Decimal {
+    if tick <= Decimal::ZERO {
+        return value;
     }
+    (value / tick).round_dp_with_strategy(0, RoundingStrategy::MidpointAwayFromZero) * tick
+}
 
-    info!("[{}] Total Quantity Available is : {}", order_type, total_size_available);
-    info!("[{}] Total tiny orders: {}", order_type, tiny_orders);
+// Keeps only the best `depth` levels of one exchange's side, best price first.
+// Uses a partial selection so the full side never has to be sorted.
+pub fn truncate_to_depth(book: &mut Vec<OrderBook>, depth: usize, is_ascending: bool) {
+    let order = price_order(is_ascending);
 
-    // Checking if all orders are sorted correctly!
-    if entity.len() > 1 {
-        // Verify ordering (for asks: ascending, for bids: descending)
-        let mut is_sorted = true;
-        for i in 1..entity.len() {
+    if depth == 0 {
+        book.clear();
+        return;
+    }
+    if book.len() > depth {
+        book.select_nth_unstable_by(depth - 1, order);
+        book.truncate(depth);
+    }
+    book.sort_by(order);
+}
 
-            let is_wrong_order = if is_ascending {
-                entity[i-1].price > entity[i].price // Should be ascending
-            } else {
-                entity[i-1].price < entity[i].price // Should be descending
-            };
+// Books at least this long are sorted in parallel when built with the rayon feature
+pub const PARALLEL_SORT_THRESHOLD: usize = 50_000;
 
-            if is_wrong_order {
-                is_sorted = false;
-                info!("WARNING: Orders not sorted! Order {} (price {}) vs Order {} (price {})", i-1, entity[i-1].price, i, entity[i].price);
-                break;
+// Most curve samples price_curve reserves room for before it starts filling them in
+const MAX_CURVE_CAPACITY: usize = 1024;
+
+// Sorts one exchange's side best price first.
+// Stable either way, so equal levels keep the exchange's own order and the result doesn't depend on the feature.
+fn sort_side(book: &mut [OrderBook], is_ascending: bool) {
+    #[cfg(feature = "rayon")]
+    if book.len() >= PARALLEL_SORT_THRESHOLD {
+        use rayon::slice::ParallelSliceMut;
+        book.par_sort_by(price_order(is_ascending));
+        return;
+    }
+    book.sort_by(price_order(is_ascending));
+}
+
+// k-way merge using a min-heap keyed on price (negated for bids so the best price pops first).
+// Equal prices are taken larger size first, then by exchange, so the output does not depend on
+// the order the books were passed in.
+fn merge_k_way(books: Vec<Vec<OrderBook>>, is_ascending: bool, skip_empty: bool, assume_sorted: bool) -> Vec<OrderBook> {
+    let key = |order: &OrderBook| if is_ascending { order.price } else { -order.price };
+
+    let mut merged = Vec::with_capacity(books.iter().map(Vec::len).sum());
+
+    // Ensure inputs are sorted unless the caller vouches for them
+    let mut iters: Vec<_> = books
+        .into_iter()
+        .map(|mut book| {
+            if !assume_sorted {
+                sort_side(&mut book, is_ascending);
             }
+            book.into_iter()
+        })
+        .collect();
+
+    let mut heap = BinaryHeap::with_capacity(iters.len());
+    for (idx, iter) in iters.iter_mut().enumerate() {
+        if let Some(order) = iter.next() {
+            heap.push(Reverse((key(&order), Reverse(order.size), order.source, idx, order)));
         }
+    }
 
-        if !is_sorted {
-            info!("WARNING: Order book is not properly sorted!");
+    while let Some(Reverse((_, _, _, idx, order))) = heap.pop() {
+        if !(skip_empty && order.size == Decimal::ZERO) {
+            merged.push(order);
+        }
+        if let Some(next) = iters[idx].next() {
+            heap.push(Reverse((key(&next), Reverse(next.size), next.source, idx, next)));
         }
     }
 
+    merged
+}
+
+// Same order as merge_asks and merge_bids, but each level keeps its order count.
+// The books are concatenated and sorted, which is simpler but slower than the k-way merge.
+pub fn merge_detailed(books: Vec<Vec<OrderBookDetailed>>, is_ascending: bool) -> Vec<OrderBookDetailed> {
+    let mut merged: Vec<OrderBookDetailed> = books.into_iter().flatten().collect();
+    // Stable, so equal levels of one exchange stay in its own order
+    merged.sort_by(|a, b| {
+        let by_price = if is_ascending { a.price.cmp(&b.price) } else { b.price.cmp(&a.price) };
+        by_price.then_with(|| b.size.cmp(&a.size)).then_with(|| a.source.cmp(&b.source))
+    });
+    merged
+}
+
+// Result of walking the book for a given quantity
+struct Fill {
+    total_cost: Decimal,
+    filled: Decimal,
+    by_source: FillBreakdown,
+    count: usize,
+    // Price of the first level taken
+    best_price: Option<Decimal>,
+}
+
+// Quantity and notional taken from each exchange, as (quantity, notional)
+pub type FillBreakdown = BTreeMap<Exchange, (Decimal, Decimal)>;
+
+// Checks the book is in the expected order (for asks: ascending, for bids: descending).
+// The error names the first level that is out of order.
+pub fn validate_book(orders: &[OrderBook], is_ascending: bool) -> Result<(), String> {
+    match first_unsorted(orders, is_ascending) {
+        Some(i) => Err(format!(
+            "Orders not sorted! Order {} (price {}) vs Order {} (price {})",
+            i-1, orders[i-1].price, i, orders[i].price
+        )),
+        None => Ok(()),
+    }
+}
+
+// Index of the first level that is out of order compared to the one before it
+fn first_unsorted(orders: &[OrderBook], is_ascending: bool) -> Option<usize> {
+    (1..orders.len()).find(|&i| {
+        if is_ascending {
+            orders[i-1].price > orders[i].price // Should be ascending
+        } else {
+            orders[i-1].price < orders[i].price // Should be descending
+        }
+    })
+}
+
+// Pricing needs a non-empty book with the best price first
+fn check_book(entity: &[OrderBook], is_ascending: bool) -> Result<(), PriceError> {
+    if entity.is_empty() {
+        return Err(PriceError::EmptyBook);
+    }
+    match first_unsorted(entity, is_ascending) {
+        Some(index) => {
+            info!("WARNING: Order book is not properly sorted at level {}!", index);
+            Err(PriceError::Unsorted { index })
+        }
+        None => Ok(()),
+    }
+}
+
+// Consumes levels from the best price until `quantity` is filled or the book runs out.
+// All the notional math is checked so extreme books fail with Overflow instead of panicking.
+fn walk_book(entity: &[OrderBook], quantity: Decimal) -> Result<Fill, PriceError> {
+    let mut fill = Fill {
+        total_cost: Decimal::ZERO,
+        filled: Decimal::ZERO,
+        by_source: FillBreakdown::new(),
+        count: 0,
+        best_price: None,
+    };
+    let mut remaining_quantity = quantity;
 
     for entry in entity.iter() {
 
@@ -188,50 +282,357 @@ pub fn calculate_entity_price(entity: &[OrderBook], quantity: Decimal, is_ascend
             continue;
         }
 
-        if remaining_quantity <= entry.size {
-            if entry.name == "COINBASE".to_string() {
-                cb_count += remaining_quantity;
-            } else {
-                gm_count += remaining_quantity;
-            }
+        // partial fill of the given order quantity, or the whole level
+        let take = remaining_quantity.min(entry.size);
 
-            // partial fill of the given order quantity
-            total_cost += entry.price * remaining_quantity;
-            count += 1;
-            remaining_quantity = Decimal::ZERO; // To tackle the wrong firing of Insufficient Liquidity error.
-            break;
-        } else {
-            if entry.name == "COINBASE".to_string() {
-                cb_count += entry.size;
-            } else {
-                gm_count += entry.size;
-            }
+        let notional = entry.price.checked_mul(take).ok_or(PriceError::Overflow)?;
+        let (source_qty, source_notional) = fill.by_source.entry(entry.source).or_default();
+        *source_qty = source_qty.checked_add(take).ok_or(PriceError::Overflow)?;
+        *source_notional = source_notional.checked_add(notional).ok_or(PriceError::Overflow)?;
 
-            total_cost += entry.price * entry.size;
-            remaining_quantity -= entry.size;
-            count += 1;
+        fill.total_cost = fill.total_cost.checked_add(notional).ok_or(PriceError::Overflow)?;
+        fill.filled = fill.filled.checked_add(take).ok_or(PriceError::Overflow)?;
+        fill.count += 1;
+        fill.best_price.get_or_insert(entry.price);
+        remaining_quantity -= take;
 
-            if remaining_quantity <= Decimal::ZERO {
-                info!("WARNING: remaining_quantity became negative: {}", remaining_quantity);
-                remaining_quantity = Decimal::ZERO;
-                break; // Quit and avoid further processing!
-            }
+        if remaining_quantity <= Decimal::ZERO {
+            break; // Quit and avoid further processing!
         }
     }
 
-    info!("Total orders processed: {}", count);
+    Ok(fill)
+}
+
+// Walks the book from the best level and returns the total cost of filling `quantity`.
+// Fails with EmptyBook or Unsorted if the book can't be walked, and with InsufficientLiquidity
+// if the book runs out before the quantity is filled.
+pub fn calculate_entity_price(entity: &[OrderBook], quantity: Decimal, is_ascending: bool, order_type: &str) -> Result<Decimal, PriceError> {
+    let original_quantity = quantity;
+    let total_size_available = total_size(entity);
+
+    // Insignificant here. But just calculating very Tiny orders to identify any bugs of any sort.
+    // To check if BTC size is < 0.0001
+    let tiny_orders = entity.iter().filter(|entry| entry.size < Decimal::new(1, 4)).count();
+
+    info!("[{}] Total Quantity Available is : {}", order_type, total_size_available);
+    info!("[{}] Total tiny orders: {}", order_type, tiny_orders);
+
+    check_book(entity, is_ascending)?;
+
+    let fill = walk_book(entity, quantity)?;
+    let remaining_quantity = original_quantity - fill.filled;
+
+    info!("Total orders processed: {}", fill.count);
     info!("Remaining quantity after processing: {}", remaining_quantity);
 
    
+    for (exchange, (amount, _)) in &fill.by_source {
+        info!("AMOUNT FROM {}: {}", exchange, amount);
+    }
+
     if remaining_quantity > Decimal::ZERO {
-        info!("Insufficient liquidity: requested {}, only {} available", original_quantity, original_quantity - remaining_quantity);
+        info!("Insufficient liquidity: requested {}, only {} available", original_quantity, fill.filled);
+        return Err(PriceError::InsufficientLiquidity {
+            requested: original_quantity,
+            available: fill.filled,
+        });
+    }
+
+    Ok(fill.total_cost)
+}
+
+// Total size across every level of the book
+pub fn total_size(entity: &[OrderBook]) -> Decimal {
+    entity.iter().map(|entry| entry.size).sum()
+}
+
+// Share of the book's total size that `quantity` takes, as a percentage.
+// Over 100 when the book can't fill it, None when the book holds no size at all.
+pub fn book_share(entity: &[OrderBook], quantity: Decimal) -> Option<Decimal> {
+    quantity.checked_mul(Decimal::ONE_HUNDRED)?.checked_div(total_size(entity))
+}
+
+// Running size and cost of a static book, so repeated fills are a binary search instead of a walk each.
+// Built with build_cumulative.
+#[derive(Debug, Clone)]
+pub struct CumBook(PrefixBook);
+
+// Precomputes the running totals of a book that is already sorted best price first, like a merged side.
+// The order is trusted, so unlike calculate_entity_price an unsorted book is not reported.
+pub fn build_cumulative(entity: &[OrderBook]) -> CumBook {
+    // A sorted side runs from its first price to its last, which tells asks from bids
+    let is_ascending = match (entity.first(), entity.last()) {
+        (Some(first), Some(last)) => first.price <= last.price,
+        _ => true,
+    };
+    CumBook(PrefixBook::from_levels(entity.iter().cloned(), is_ascending))
+}
+
+impl CumBook {
+    // Total cost of filling `quantity` from the best level, the same as calculate_entity_price on a sorted book
+    pub fn cost(&self, quantity: Decimal) -> Result<Decimal, PriceError> {
+        self.0.fill(quantity)
+    }
+}
+
+// Total size offered within `limit_price`: asks at or below it, or bids at or above it.
+// Zero for an empty book or when no level is within the limit.
+pub fn depth_to_price(entity: &[OrderBook], limit_price: Decimal, is_ascending: bool) -> Decimal {
+    entity
+        .iter()
+        .filter(|entry| if is_ascending { entry.price <= limit_price } else { entry.price >= limit_price })
+        .map(|entry| entry.size)
+        .sum()
+}
+
+// Samples the average fill price at `points` evenly spaced quantities up to the total available size.
+// Walks the book once, so the whole curve costs the same as a single fill.
+// Returns an empty curve if there is nothing to fill or no points were requested, and fails with Overflow
+// if the sizes or costs do not fit in a Decimal.
+pub fn price_curve(entity: &[OrderBook], points: usize) -> Result<Vec<(Decimal, Decimal)>, PriceError> {
+    let total_size_available = entity
+        .iter()
+        .try_fold(Decimal::ZERO, |total, entry| total.checked_add(entry.size))
+        .ok_or(PriceError::Overflow)?;
+
+    if points == 0 || total_size_available <= Decimal::ZERO {
+        return Ok(Vec::new());
     }
 
-    println!("AMOUNT FROM COINBASE: {}", cb_count);
-    println!("AMOUNT FROM GEMINI: {}", gm_count);
+    // Grows past this as samples are pushed, so a huge `points` can't reserve memory up front
+    let mut curve = Vec::with_capacity(points.min(MAX_CURVE_CAPACITY));
+    let steps = Decimal::from(points);
+    let mut filled = Decimal::ZERO;
+    let mut total_cost = Decimal::ZERO;
+    let mut next_point = 1;
+
+    for entry in entity.iter() {
+        if entry.size <= Decimal::ZERO {
+            continue;
+        }
 
-    Ok(total_cost)
+        let level_end = filled.checked_add(entry.size).ok_or(PriceError::Overflow)?;
+
+        // Emit every sample that lands inside this level
+        while next_point <= points {
+            // Use the exact total for the last sample so rounding never pushes it past the book
+            let target = if next_point == points {
+                total_size_available
+            } else {
+                total_size_available
+                    .checked_mul(Decimal::from(next_point))
+                    .and_then(|scaled| scaled.checked_div(steps))
+                    .ok_or(PriceError::Overflow)?
+            };
+
+            if target > level_end {
+                break;
+            }
+
+            let cost = entry.price
+                .checked_mul(target - filled)
+                .and_then(|partial| total_cost.checked_add(partial))
+                .ok_or(PriceError::Overflow)?;
+            curve.push((target, cost.checked_div(target).ok_or(PriceError::Overflow)?));
+            next_point += 1;
+        }
+
+        total_cost = entry.price
+            .checked_mul(entry.size)
+            .and_then(|notional| total_cost.checked_add(notional))
+            .ok_or(PriceError::Overflow)?;
+        filled = level_end;
+    }
+
+    Ok(curve)
 }
 
+// How much of a `quantity` fill each exchange provides.
+// Covers whatever could be filled if the book runs out first.
+pub fn calculate_fill_breakdown(entity: &[OrderBook], quantity: Decimal, is_ascending: bool) -> Result<FillBreakdown, PriceError> {
+    check_book(entity, is_ascending)?;
+    Ok(walk_book(entity, quantity)?.by_source)
+}
+
+// A limit order for `quantity` at `limit_price` as (filled, resting, notional).
+// Only the levels at or better than the limit fill immediately, the rest of the order would sit on the book.
+// An empty book is not an error here, the whole order simply rests.
+pub fn simulate_limit(entity: &[OrderBook], limit_price: Decimal, quantity: Decimal, is_ascending: bool) -> Result<(Decimal, Decimal, Decimal), PriceError> {
+    if !entity.is_empty() {
+        check_book(entity, is_ascending)?;
+    }
 
+    let crossing = entity
+        .iter()
+        .take_while(|entry| if is_ascending { entry.price <= limit_price } else { entry.price >= limit_price })
+        .count();
+    let fill = walk_book(&entity[..crossing], quantity)?;
 
+    Ok((fill.filled, quantity - fill.filled, fill.total_cost))
+}
+
+// What a round trip of `quantity` costs per unit, buying it then selling it straight back: vwap_buy - vwap_sell.
+// Fails if either side can't fill the whole quantity, where the VWAP of a partial fill would understate it.
+pub fn effective_spread(asks: &[OrderBook], bids: &[OrderBook], quantity: Decimal) -> Result<Decimal, PriceError> {
+    let full_vwap = |entity: &[OrderBook], is_ascending: bool| {
+        let available = total_size(entity);
+        if !entity.is_empty() && available < quantity {
+            return Err(PriceError::InsufficientLiquidity { requested: quantity, available });
+        }
+        calculate_vwap(entity, quantity, is_ascending)
+    };
+    Ok(full_vwap(asks, true)? - full_vwap(bids, false)?)
+}
+
+// Volume weighted average price for filling `quantity`.
+// If the book cannot cover the whole quantity, the average is taken over what was actually filled.
+pub fn calculate_vwap(entity: &[OrderBook], quantity: Decimal, is_ascending: bool) -> Result<Decimal, PriceError> {
+    vwap_and_best_price(entity, quantity, is_ascending).map(|(vwap, _)| vwap)
+}
+
+// The average fill price along with the price of the best level the fill took
+fn vwap_and_best_price(entity: &[OrderBook], quantity: Decimal, is_ascending: bool) -> Result<(Decimal, Decimal), PriceError> {
+    check_book(entity, is_ascending)?;
+
+    let fill = walk_book(entity, quantity)?;
+
+    let Some(best_price) = fill.best_price.filter(|_| fill.filled != Decimal::ZERO) else {
+        return Err(PriceError::InsufficientLiquidity { requested: quantity, available: Decimal::ZERO });
+    };
+
+    let vwap = fill.total_cost.checked_div(fill.filled).ok_or(PriceError::Overflow)?;
+    Ok((vwap, best_price))
+}
+
+// Applies a taker fee of `bps` basis points to a fill notional.
+// Buying costs more than the book price and selling raises less.
+// Fails with Overflow if the fee or the total does not fit in a Decimal.
+pub fn apply_fee(notional: Decimal, bps: Decimal, is_buy: bool) -> Result<Decimal, PriceError> {
+    let fee = notional
+        .checked_mul(bps)
+        .and_then(|scaled| scaled.checked_div(Decimal::from(10_000)))
+        .ok_or(PriceError::Overflow)?;
+    let total = if is_buy { notional.checked_add(fee) } else { notional.checked_sub(fee) };
+    total.ok_or(PriceError::Overflow)
+}
+
+// Slippage of filling `quantity` relative to the best price, as a fraction: (vwap - best) / best.
+// Signed so a worse fill is positive on both sides, i.e. paying up on asks or selling down on bids.
+// Fails with ZeroPrice when the best level is priced at zero.
+pub fn slippage(entity: &[OrderBook], quantity: Decimal, is_ascending: bool) -> Result<Decimal, PriceError> {
+    let (vwap, best_price) = vwap_and_best_price(entity, quantity, is_ascending)?;
+
+    let slippage = vwap
+        .checked_sub(best_price)
+        .ok_or(PriceError::Overflow)?
+        .checked_div(best_price)
+        .ok_or(PriceError::ZeroPrice)?;
+    Ok(if is_ascending { slippage } else { -slippage })
+}
+
+// Walks the book spending `quote` (e.g. USD) and returns the base quantity it buys.
+// On bids this is the base quantity that has to be sold to raise `quote`.
+// Fails with InsufficientLiquidity, in quote units, if the book runs out before the whole amount is spent.
+pub fn fill_by_quote(entity: &[OrderBook], quote: Decimal, is_ascending: bool) -> Result<Decimal, PriceError> {
+    check_book(entity, is_ascending)?;
+
+    let mut remaining_quote = quote;
+    let mut base = Decimal::ZERO;
+
+    for entry in entity.iter() {
+        if entry.size <= Decimal::ZERO || entry.price <= Decimal::ZERO {
+            continue;
+        }
+
+        // A level too large to represent can always cover what is left of the quote
+        let level_cost = entry.price.checked_mul(entry.size).unwrap_or(Decimal::MAX);
+        if level_cost >= remaining_quote {
+            // Partial fill of this level uses up the rest of the quote
+            let partial = remaining_quote.checked_div(entry.price).ok_or(PriceError::Overflow)?;
+            return base.checked_add(partial).ok_or(PriceError::Overflow);
+        }
+
+        base = base.checked_add(entry.size).ok_or(PriceError::Overflow)?;
+        remaining_quote -= level_cost;
+    }
+
+    Err(PriceError::InsufficientLiquidity { requested: quote, available: quote - remaining_quote })
+}
+
+// Top of book spread and mid price as (spread, mid).
+// Expects asks ascending and bids descending, returns None if either side is empty.
+pub fn spread(asks: &[OrderBook], bids: &[OrderBook]) -> Option<(Decimal, Decimal)> {
+    let best_ask = asks.first()?.price;
+    let best_bid = bids.first()?.price;
+
+    Some((best_ask - best_bid, (best_ask + best_bid) / Decimal::TWO))
+}
+
+// Size weighted mid, (best_bid * ask_size + best_ask * bid_size) / (bid_size + ask_size).
+// Leans toward the side facing more size on the other. None if either side is empty, the top levels hold no size
+// or the weighting does not fit in a Decimal.
+pub fn micro_price(asks: &[OrderBook], bids: &[OrderBook]) -> Option<Decimal> {
+    let (ask, bid) = (asks.first()?, bids.first()?);
+    let weighted = bid.price.checked_mul(ask.size)?.checked_add(ask.price.checked_mul(bid.size)?)?;
+
+    weighted.checked_div(ask.size.checked_add(bid.size)?)
+}
+
+// Exchanges whose best price strays more than `pct` percent from the median of all the best prices given.
+// A stale or broken feed shows up here before it skews the merged book.
+// With two books the median is their midpoint, so both are flagged once they are far enough apart.
+pub fn detect_outlier(books: &[(Exchange, Decimal)], pct: Decimal) -> Vec<Exchange> {
+    if books.len() < 2 {
+        return Vec::new();
+    }
+    let mut prices: Vec<Decimal> = books.iter().map(|(_, price)| *price).collect();
+    prices.sort();
+    let mid = prices.len() / 2;
+    let median = match prices.len() % 2 {
+        0 => (prices[mid - 1] + prices[mid]) / Decimal::TWO,
+        _ => prices[mid],
+    };
+    if median <= Decimal::ZERO {
+        return Vec::new();
+    }
+    books
+        .iter()
+        .filter(|(_, price)| {
+            let deviation = (*price - median).abs().checked_div(median).and_then(|ratio| ratio.checked_mul(Decimal::ONE_HUNDRED));
+            deviation.is_none_or(|deviation| deviation > pct)
+        })
+        .map(|(exchange, _)| *exchange)
+        .collect()
+}
+
+// Total bid size over total ask size within the best `depth` levels of each side.
+// Above 1 there is more size waiting to buy than to sell. None when those asks hold no size, or a sum overflows.
+pub fn imbalance(asks: &[OrderBook], bids: &[OrderBook], depth: usize) -> Option<Decimal> {
+    let size = |levels: &[OrderBook]| levels.iter().take(depth).try_fold(Decimal::ZERO, |sum, level| sum.checked_add(level.size));
+    size(bids)?.checked_div(size(asks)?)
+}
+
+// Best level across the exchange books without merging them: the lowest ask or the highest bid.
+// Each book is scanned once, so they don't need to be sorted.
+// Ties are broken like in a merge: the larger size, then the exchange order, then the earlier book.
+pub fn best_level(books: &[Vec<OrderBook>], is_ascending: bool) -> Option<OrderBook> {
+    let order = price_order(is_ascending);
+    books
+        .iter()
+        .flatten()
+        .fold(None, |best: Option<&OrderBook>, level| match best {
+            Some(best) if order(level, best).then_with(|| level.source.cmp(&best.source)) != Ordering::Less => Some(best),
+            _ => Some(level),
+        })
+        .cloned()
+}
+
+// A bid at or above the best ask should have traded already, so the data is stale or bad.
+// Expects asks ascending and bids descending, an empty side is never crossed.
+pub fn is_crossed(asks: &[OrderBook], bids: &[OrderBook]) -> bool {
+    match (asks.first(), bids.first()) {
+        (Some(best_ask), Some(best_bid)) => best_bid.price >= best_ask.price,
+        _ => false,
+    }
+}
@@ -0,0 +1,129 @@
+use rust_decimal::Decimal;
+
+use super::orderbook_merger::PriceError;
+use super::types::{Exchange, OrderBook};
+
+// One side of a book that keeps running totals of size and notional next to the levels,
+// so pricing a quantity is a binary search instead of a walk from the top.
+// Inserts and removes only redo the totals from the changed level down.
+#[derive(Debug, Clone)]
+pub struct PrefixBook {
+    is_ascending: bool,
+    // Best price first. Equal prices keep the order they were inserted in.
+    levels: Vec<OrderBook>,
+    // (size, notional) of the levels up to and including each one. None once the notional overflows.
+    totals: Vec<Option<(Decimal, Decimal)>>,
+}
+
+impl PrefixBook {
+    // Empty asks when `is_ascending`, empty bids otherwise
+    pub fn new(is_ascending: bool) -> Self {
+        PrefixBook { is_ascending, levels: Vec::new(), totals: Vec::new() }
+    }
+
+    // Builds a side from levels in any order
+    pub fn from_levels(levels: impl IntoIterator<Item = OrderBook>, is_ascending: bool) -> Self {
+        let mut book = PrefixBook::new(is_ascending);
+        book.levels = levels.into_iter().collect();
+        // Stable, so levels with the same price stay in the order given
+        book.levels.sort_by(|a, b| if is_ascending { a.price.cmp(&b.price) } else { b.price.cmp(&a.price) });
+        book.recompute_from(0);
+        book
+    }
+
+    pub fn levels(&self) -> &[OrderBook] {
+        &self.levels
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.levels.is_empty()
+    }
+
+    // Total size on this side
+    pub fn total_size(&self) -> Decimal {
+        self.levels.iter().map(|level| level.size).sum()
+    }
+
+    // Adds a level, replacing the one `level.source` already had at that price
+    pub fn insert(&mut self, level: OrderBook) {
+        let index = match self.position(level.price, level.source) {
+            Some(index) => {
+                self.levels[index] = level;
+                index
+            }
+            None => {
+                // After the levels already at this price
+                let index = self.levels.partition_point(|existing| !self.is_worse(existing.price, level.price));
+                self.levels.insert(index, level);
+                index
+            }
+        };
+        self.recompute_from(index);
+    }
+
+    // Removes `source`'s level at `price`, returning it if there was one
+    pub fn remove(&mut self, price: Decimal, source: Exchange) -> Option<OrderBook> {
+        let index = self.position(price, source)?;
+        let removed = self.levels.remove(index);
+        self.recompute_from(index);
+        Some(removed)
+    }
+
+    // Total cost of filling `quantity` from the best level down, the same as calculate_entity_price
+    // on these levels. Fails with EmptyBook, InsufficientLiquidity or Overflow in the same cases.
+    pub fn fill(&self, quantity: Decimal) -> Result<Decimal, PriceError> {
+        if self.levels.is_empty() {
+            return Err(PriceError::EmptyBook);
+        }
+
+        // First level whose running size covers the quantity, or whose running total overflowed.
+        // Every level before it is taken whole.
+        let index = self.totals.partition_point(|total| total.is_some_and(|(size, _)| size < quantity));
+        if index == self.levels.len() {
+            return match self.totals.last() {
+                Some(Some((available, _))) => Err(PriceError::InsufficientLiquidity { requested: quantity, available: *available }),
+                _ => Err(PriceError::Overflow),
+            };
+        }
+
+        let (size_before, notional_before) = match index {
+            0 => (Decimal::ZERO, Decimal::ZERO),
+            _ => self.totals[index - 1].ok_or(PriceError::Overflow)?,
+        };
+        let remaining = quantity - size_before;
+        // Only possible past an overflowed total, where the fill would have to run on
+        if remaining > self.levels[index].size {
+            return Err(PriceError::Overflow);
+        }
+        let partial = self.levels[index].price.checked_mul(remaining).ok_or(PriceError::Overflow)?;
+        notional_before.checked_add(partial).ok_or(PriceError::Overflow)
+    }
+
+    fn position(&self, price: Decimal, source: Exchange) -> Option<usize> {
+        let start = self.levels.partition_point(|existing| self.is_worse(price, existing.price));
+        self.levels[start..]
+            .iter()
+            .take_while(|existing| existing.price == price)
+            .position(|existing| existing.source == source)
+            .map(|offset| start + offset)
+    }
+
+    // True if `price` sits further from the top of the book than `than`
+    fn is_worse(&self, price: Decimal, than: Decimal) -> bool {
+        if self.is_ascending { price > than } else { price < than }
+    }
+
+    fn recompute_from(&mut self, index: usize) {
+        self.totals.truncate(index);
+        let mut running = match index {
+            0 => Some((Decimal::ZERO, Decimal::ZERO)),
+            _ => self.totals[index - 1],
+        };
+        for level in &self.levels[index..] {
+            running = running.and_then(|(size, notional)| {
+                Some((size.checked_add(level.size)?, notional.checked_add(level.price.checked_mul(level.size)?)?))
+            });
+            self.totals.push(running);
+        }
+    }
+}
@@ -1,13 +1,33 @@
+use std::fmt;
 use std::sync::Arc;
 use std::time::{Duration, Instant};
-use std::str::FromStr;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 use tokio::sync::Mutex;
 use rust_decimal::Decimal;
+use rust_decimal::prelude::ToPrimitive;
+
+// Lowest rate tighten() can reach, as a fraction of the configured rate
+const MIN_RATE_DIVISOR: u32 = 16;
+// Number of successful relax() calls needed to climb from zero back to the configured rate
+const RELAX_STEPS: u32 = 10;
 
 // Error returned when rate limit is exceeded
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct RateLimitExceeded;
 
+// Error returned when an interval cannot be turned into a refill rate
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidInterval(pub Duration);
+
+impl fmt::Display for InvalidInterval {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Invalid rate limit interval {:?}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidInterval {}
+
 // Token bucket rate limiter that allows at most `capacity` tokens,
 // with tokens refilling at `tokens_per_second` rate.
 // 
@@ -15,6 +35,14 @@ pub struct RateLimitExceeded;
 // without sleeping in the executing thread.
 pub struct RateLimiter {
     state: Arc<Mutex<RateLimiterState>>,
+    // Spreads out the waits of callers that were held back at the same time
+    jitter: Option<Jitter>,
+}
+
+struct Jitter {
+    // Largest extra wait as a fraction of the computed one
+    fraction: f64,
+    rng: std::sync::Mutex<StdRng>,
 }
 
 struct RateLimiterState {
@@ -24,10 +52,52 @@ struct RateLimiterState {
     capacity: Decimal,
     // Rate at which tokens are refilled per second
     tokens_per_second: Decimal,
-    // Last time the state was updated (for calculating token refill)
+    // Configured refill rate. Adaptive mode never relaxes above this
+    base_tokens_per_second: Decimal,
+    // Last time the state was updated (for calculating token refill).
+    // In the future after penalize(), and no tokens are earned until then.
     last_update: Instant,
 }
 
+impl RateLimiterState {
+    // Adds the tokens earned since `last_update`, capped at capacity.
+    // Uses the exact nanosecond count so short waits are never rounded away.
+    fn refill(&mut self, now: Instant) {
+        if now < self.last_update {
+            return;
+        }
+        self.tokens = self.tokens_at(now);
+        self.last_update = now;
+    }
+
+    // What refill(now) would leave in the bucket, without writing it back.
+    // Nothing is earned before `last_update`, so during a penalty this is just the current count.
+    fn tokens_at(&self, now: Instant) -> Decimal {
+        let elapsed = now.saturating_duration_since(self.last_update);
+        let elapsed_secs = Decimal::from_i128_with_scale(elapsed.as_nanos() as i128, 9);
+        let tokens_to_add = self.tokens_per_second * elapsed_secs;
+        (self.tokens + tokens_to_add).min(self.capacity)
+    }
+
+    // Time until a whole token is available, rounded up to the next nanosecond.
+    // Includes what is left of a penalty. Never zero, so a caller sleeping on it cannot spin.
+    fn time_until_token(&self, now: Instant) -> Duration {
+        let tokens_needed = (Decimal::ONE - self.tokens).max(Decimal::ZERO);
+        let wait_secs = tokens_needed / self.tokens_per_second;
+        let wait_nanos = (wait_secs * Decimal::from(1_000_000_000u64)).ceil();
+        let penalty = self.last_update.saturating_duration_since(now);
+        penalty + Duration::from_nanos(wait_nanos.to_u64().unwrap_or(u64::MAX).max(1))
+    }
+}
+
+// `wait` plus a uniformly random 0 to `fraction` share of it. A fraction of zero or less leaves it unchanged.
+pub fn jittered_wait<R: Rng + ?Sized>(wait: Duration, fraction: f64, rng: &mut R) -> Duration {
+    if fraction <= 0.0 {
+        return wait;
+    }
+    wait + wait.mul_f64(rng.random_range(0.0..fraction))
+}
+
 impl RateLimiter {
     // Creates a new rate limiter with the specified capacity and refill rate.
     // # Arguments
@@ -48,8 +118,10 @@ impl RateLimiter {
                 tokens: capacity,
                 capacity,
                 tokens_per_second,
+                base_tokens_per_second: tokens_per_second,
                 last_update: Instant::now(),
             })),
+            jitter: None,
         }
     }
 
@@ -57,12 +129,36 @@ impl RateLimiter {
     // 
     // This is a convenience method for the common case where you want
     // "at most once every X seconds".
-    pub fn new_per_interval(interval: Duration) -> Self {
-        let capacity = Decimal::ONE;
-        let interval_secs = interval.as_secs_f64();
-        let tokens_per_second = Decimal::ONE / Decimal::from_str(&format!("{:.6}", interval_secs))
-            .unwrap_or(Decimal::ONE);
-        Self::new(capacity, tokens_per_second)
+    pub fn new_per_interval(interval: Duration) -> Result<Self, InvalidInterval> {
+        Ok(Self::new(Decimal::ONE, Self::rate_per_interval(interval)?))
+    }
+
+    // Creates a rate limiter that allows bursts of up to `capacity` calls,
+    // refilling at one token per `interval`.
+    // # Panics
+    // Panics if capacity is 0
+    pub fn new_with_burst(capacity: u32, interval: Duration) -> Result<Self, InvalidInterval> {
+        Ok(Self::new(Decimal::from(capacity), Self::rate_per_interval(interval)?))
+    }
+
+    // Like new_with_burst, but every wait in acquire() is stretched by a random 0 to `jitter` fraction of itself.
+    // Callers released together then come back at different times instead of hitting the limiter at once.
+    pub fn new_with_jitter(capacity: u32, interval: Duration, jitter: f64) -> Result<Self, InvalidInterval> {
+        let mut limiter = Self::new_with_burst(capacity, interval)?;
+        limiter.jitter = Some(Jitter { fraction: jitter, rng: std::sync::Mutex::new(StdRng::from_os_rng()) });
+        Ok(limiter)
+    }
+
+    // Refill rate for one token per `interval`, computed exactly from the nanosecond count.
+    // Fails for a zero interval or one so long the rate rounds down to zero.
+    fn rate_per_interval(interval: Duration) -> Result<Decimal, InvalidInterval> {
+        // Duration::MAX is ~1.8e28 nanoseconds, which still fits in a Decimal
+        let interval_secs = Decimal::from_i128_with_scale(interval.as_nanos() as i128, 9);
+
+        Decimal::ONE
+            .checked_div(interval_secs)
+            .filter(|rate| *rate > Decimal::ZERO)
+            .ok_or(InvalidInterval(interval))
     }
 
     // Attempts to acquire a token without blocking.
@@ -73,19 +169,8 @@ impl RateLimiter {
     pub async fn try_acquire(&self) -> Result<(), RateLimitExceeded> {
         let mut state = self.state.lock().await;
         
-        // Calculate time elapsed since last update
-        let now = Instant::now();
-        let elapsed = state.last_update.elapsed();
-        
-        // Refill tokens based on elapsed time
-        let elapsed_secs = elapsed.as_secs_f64();
-        if elapsed_secs > 0.0 {
-            let elapsed_decimal = Decimal::from_str(&format!("{:.6}", elapsed_secs))
-                .unwrap_or(Decimal::ZERO);
-            let tokens_to_add = state.tokens_per_second * elapsed_decimal;
-            state.tokens = (state.tokens + tokens_to_add).min(state.capacity);
-            state.last_update = now;
-        }
+        // Refill tokens based on time elapsed since last update
+        state.refill(Instant::now());
         
         // Check if we have at least one token
         if state.tokens < Decimal::ONE {
@@ -103,42 +188,63 @@ impl RateLimiter {
     // Note: This uses `tokio::time::sleep` which yields to the executor
     // and doesn't block the OS thread, making it suitable for async code.
     // If you need strictly non-blocking behavior, use `try_acquire` instead.
-    pub async fn acquire(&self) {
+    // Returns how many attempts it took, which is 1 if a token was already available.
+    pub async fn acquire(&self) -> u32 {
+        let mut attempts = 0;
         loop {
+            attempts += 1;
             match self.try_acquire().await {
-                Ok(()) => return,
+                Ok(()) => return attempts,
                 Err(_) => {
                     // Calculate how long to wait until next token is available
-                    let state = self.state.lock().await;
-                    let tokens_needed = Decimal::ONE - state.tokens;
-                    let wait_seconds_decimal = tokens_needed / state.tokens_per_second;
-                    let wait_seconds = wait_seconds_decimal.to_string()
-                        .parse::<f64>()
-                        .unwrap_or(0.0);
-                    drop(state);
-                    
-                    if wait_seconds > 0.0 {
-                        tokio::time::sleep(Duration::from_secs_f64(wait_seconds)).await;
+                    let mut wait = self.state.lock().await.time_until_token(Instant::now());
+                    if let Some(jitter) = &self.jitter {
+                        let mut rng = jitter.rng.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+                        wait = jittered_wait(wait, jitter.fraction, &mut *rng);
                     }
+                    tokio::time::sleep(wait).await;
                 }
             }
         }
     }
 
-    // Returns the current number of available tokens (approximate).
-    // pub async fn available_tokens(&self) -> Decimal {
-    //     let mut state = self.state.lock().await;
-    //     // Update tokens based on elapsed time
-    //     let elapsed = state.last_update.elapsed();
-    //     let elapsed_secs = elapsed.as_secs_f64();
-    //     if elapsed_secs > 0.0 {
-    //         let elapsed_decimal = Decimal::from_str(&format!("{:.6}", elapsed_secs))
-    //             .unwrap_or(Decimal::ZERO);
-    //         let tokens_to_add = state.tokens_per_second * elapsed_decimal;
-    //         state.tokens = (state.tokens + tokens_to_add).min(state.capacity);
-    //         state.last_update = Instant::now();
-    //     }
-        
-    //     state.tokens
-    // }
+    // Multiplicative decrease: halves the refill rate after the exchange signalled a rate limit.
+    // The rate never drops below 1/MIN_RATE_DIVISOR of the configured rate.
+    pub async fn tighten(&self) {
+        let mut state = self.state.lock().await;
+        let floor = state.base_tokens_per_second / Decimal::from(MIN_RATE_DIVISOR);
+        state.tokens_per_second = (state.tokens_per_second / Decimal::TWO).max(floor);
+    }
+
+    // Additive increase: moves the refill rate back towards the configured rate after a success.
+    pub async fn relax(&self) {
+        let mut state = self.state.lock().await;
+        let step = state.base_tokens_per_second / Decimal::from(RELAX_STEPS);
+        state.tokens_per_second = (state.tokens_per_second + step).min(state.base_tokens_per_second);
+    }
+
+    // Empties the bucket and earns nothing for `delay`, e.g. the Retry-After of a 429.
+    // The server knows its own quota better than the bucket's estimate of it.
+    // A shorter delay than an earlier penalty still pending doesn't cut that one short.
+    pub async fn penalize(&self, delay: Duration) {
+        let mut state = self.state.lock().await;
+        let now = Instant::now();
+        state.refill(now);
+        state.tokens = Decimal::ZERO;
+        // A delay too long for an Instant to hold leaves any earlier penalty as it was
+        if let Some(until) = now.checked_add(delay) {
+            state.last_update = state.last_update.max(until);
+        }
+    }
+
+    // Returns the current refill rate, which differs from the configured one after tighten().
+    pub async fn tokens_per_second(&self) -> Decimal {
+        self.state.lock().await.tokens_per_second
+    }
+
+    // Returns the number of tokens available right now, fractions included.
+    // Read only: the refill is computed from the elapsed time but not stored, so looking never moves the clock.
+    pub async fn peek_tokens(&self) -> Decimal {
+        self.state.lock().await.tokens_at(Instant::now())
+    }
 }
\ No newline at end of file
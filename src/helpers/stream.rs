@@ -0,0 +1,265 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use futures_util::{SinkExt, StreamExt};
+use tracing::{info, warn};
+use rust_decimal::Decimal;
+use serde_json::{json, Value};
+use tokio::sync::mpsc;
+use tokio_tungstenite::{connect_async, tungstenite::Message};
+
+use super::types::{parse_decimal, Exchange, NormalizedBook, OrderBook};
+
+pub const COINBASE_WS_URL: &str = "wss://advanced-trade-ws.coinbase.com";
+pub const GEMINI_WS_URL: &str = "wss://api.gemini.com/v2/marketdata";
+
+// Pause before reconnecting after a dropped connection or a sequence gap
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BookSide {
+    Bid,
+    Ask,
+}
+
+// A single price level from a feed. A zero size removes the level.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LevelChange {
+    pub side: BookSide,
+    pub price: Decimal,
+    pub size: Decimal,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FeedEvent {
+    // Replaces the whole book
+    Snapshot(Vec<LevelChange>),
+    // Changes individual levels
+    Update(Vec<LevelChange>),
+}
+
+// The feed skipped at least one message, so the book can no longer be trusted
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SequenceGap {
+    pub expected: u64,
+    pub received: u64,
+}
+
+impl fmt::Display for SequenceGap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "sequence gap: expected {}, received {}", self.expected, self.received)
+    }
+}
+
+impl std::error::Error for SequenceGap {}
+
+// In-memory level2 book for one exchange, keyed by price
+#[derive(Debug, Clone)]
+pub struct LiveBook {
+    source: Exchange,
+    asks: BTreeMap<Decimal, Decimal>,
+    bids: BTreeMap<Decimal, Decimal>,
+    last_sequence: Option<u64>,
+}
+
+impl LiveBook {
+    pub fn new(source: Exchange) -> Self {
+        LiveBook {
+            source,
+            asks: BTreeMap::new(),
+            bids: BTreeMap::new(),
+            last_sequence: None,
+        }
+    }
+
+    // Drops every level and forgets the sequence, used when (re)subscribing
+    pub fn clear(&mut self) {
+        self.asks.clear();
+        self.bids.clear();
+        self.last_sequence = None;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.asks.is_empty() && self.bids.is_empty()
+    }
+
+    pub fn source(&self) -> Exchange {
+        self.source
+    }
+
+    pub fn apply(&mut self, event: FeedEvent) {
+        let changes = match event {
+            FeedEvent::Snapshot(levels) => {
+                self.asks.clear();
+                self.bids.clear();
+                levels
+            }
+            FeedEvent::Update(changes) => changes,
+        };
+
+        for change in changes {
+            let levels = match change.side {
+                BookSide::Ask => &mut self.asks,
+                BookSide::Bid => &mut self.bids,
+            };
+
+            if change.size.is_zero() {
+                levels.remove(&change.price);
+            } else {
+                levels.insert(change.price, change.size);
+            }
+        }
+    }
+
+    // Sequence numbers must increase by exactly one, anything else means a message was lost
+    pub fn check_sequence(&mut self, sequence: u64) -> Result<(), SequenceGap> {
+        if let Some(last) = self.last_sequence
+            && sequence != last + 1
+        {
+            return Err(SequenceGap { expected: last + 1, received: sequence });
+        }
+
+        self.last_sequence = Some(sequence);
+        Ok(())
+    }
+
+    // Asks ascending and bids descending, ready for the merger
+    pub fn to_book(&self) -> NormalizedBook {
+        let level = |(price, size): (&Decimal, &Decimal)| OrderBook { price: *price, size: *size, source: self.source };
+
+        NormalizedBook {
+            asks: self.asks.iter().map(level).collect(),
+            bids: self.bids.iter().rev().map(level).collect(),
+        }
+    }
+}
+
+fn decimal_field(value: &Value, field: &str, strict: bool) -> Result<Decimal> {
+    let text = value[field].as_str().ok_or_else(|| anyhow!("missing {}", field))?;
+    parse_decimal(text, strict).map_err(|e| anyhow!(e))
+}
+
+/*
+ * Coinbase Advanced Trade level2 messages look like
+ * {"channel":"l2_data","sequence_num":3,"events":[{"type":"snapshot","updates":[{"side":"bid","price_level":"..","new_quantity":".."}]}]}
+ * Every message on the connection carries the sequence number, including subscription acks and heartbeats.
+ * With `strict`, a price or size with more digits than a Decimal holds fails the message instead of being rounded.
+ */
+pub fn parse_coinbase(text: &str, strict: bool) -> Result<(Option<u64>, Vec<FeedEvent>)> {
+    let message: Value = serde_json::from_str(text)?;
+    let sequence = message["sequence_num"].as_u64();
+
+    if message["channel"] != "l2_data" {
+        return Ok((sequence, Vec::new()));
+    }
+
+    let mut events = Vec::new();
+    for event in message["events"].as_array().into_iter().flatten() {
+        let mut changes = Vec::new();
+        for update in event["updates"].as_array().into_iter().flatten() {
+            let side = match update["side"].as_str() {
+                Some("bid") => BookSide::Bid,
+                Some("offer") => BookSide::Ask,
+                other => return Err(anyhow!("unknown Coinbase side {:?}", other)),
+            };
+            changes.push(LevelChange {
+                side,
+                price: decimal_field(update, "price_level", strict)?,
+                size: decimal_field(update, "new_quantity", strict)?,
+            });
+        }
+
+        events.push(match event["type"].as_str() {
+            Some("snapshot") => FeedEvent::Snapshot(changes),
+            _ => FeedEvent::Update(changes),
+        });
+    }
+
+    Ok((sequence, events))
+}
+
+/*
+ * Gemini v2 l2_updates carry changes as ["buy"|"sell", price, quantity].
+ * The first message after subscribing holds the whole book, so it is applied to the freshly cleared book.
+ * The v2 feed has no sequence numbers, a dropped connection is the only gap we can see.
+ * `strict` is the same as for parse_coinbase.
+ */
+pub fn parse_gemini(text: &str, strict: bool) -> Result<Vec<FeedEvent>> {
+    let message: Value = serde_json::from_str(text)?;
+
+    if message["type"] != "l2_updates" {
+        return Ok(Vec::new());
+    }
+
+    let mut changes = Vec::new();
+    for change in message["changes"].as_array().into_iter().flatten() {
+        let side = match change[0].as_str() {
+            Some("buy") => BookSide::Bid,
+            Some("sell") => BookSide::Ask,
+            other => return Err(anyhow!("unknown Gemini side {:?}", other)),
+        };
+        let price = change[1].as_str().ok_or_else(|| anyhow!("missing price"))?;
+        let size = change[2].as_str().ok_or_else(|| anyhow!("missing quantity"))?;
+        let parse = |text: &str| parse_decimal(text, strict).map_err(|e| anyhow!(e));
+        changes.push(LevelChange { side, price: parse(price)?, size: parse(size)? });
+    }
+
+    Ok(vec![FeedEvent::Update(changes)])
+}
+
+fn subscription(exchange: Exchange, product: &str) -> Result<Value> {
+    match exchange {
+        Exchange::Coinbase => Ok(json!({"type": "subscribe", "product_ids": [product], "channel": "level2"})),
+        Exchange::Gemini => Ok(json!({"type": "subscribe", "subscriptions": [{"name": "l2", "symbols": [product.to_uppercase()]}]})),
+        Exchange::Binance | Exchange::Bitstamp => Err(anyhow!("streaming is not supported for {}", exchange)),
+    }
+}
+
+// Sends every change on the exchange feed to `updates`, to be applied to a LiveBook in order.
+// Resubscribes from scratch on gaps or disconnects, starting over with an empty Snapshot that clears the book.
+// Only returns if the exchange has no stream support or nobody is receiving the updates any more.
+pub async fn follow(exchange: Exchange, url: String, product: String, strict: bool, updates: mpsc::Sender<(Exchange, FeedEvent)>) -> Result<()> {
+    let request = subscription(exchange, &product)?;
+
+    while !updates.is_closed() {
+        if let Err(e) = follow_once(exchange, &url, &request, strict, &updates).await {
+            warn!("{} stream interrupted: {}. Resubscribing..", exchange, e);
+        }
+        tokio::time::sleep(RECONNECT_DELAY).await;
+    }
+
+    Ok(())
+}
+
+async fn follow_once(exchange: Exchange, url: &str, request: &Value, strict: bool, updates: &mpsc::Sender<(Exchange, FeedEvent)>) -> Result<()> {
+    let (mut socket, _) = connect_async(url).await?;
+    // Only tracks the sequence, the levels live with the receiver
+    let mut sequence_book = LiveBook::new(exchange);
+    updates.send((exchange, FeedEvent::Snapshot(Vec::new()))).await?;
+    socket.send(Message::Text(request.to_string().into())).await?;
+    info!("Subscribed to the {} level2 feed", exchange);
+
+    while let Some(message) = socket.next().await {
+        let text = match message? {
+            Message::Text(text) => text,
+            Message::Close(frame) => return Err(anyhow!("connection closed: {:?}", frame)),
+            _ => continue,
+        };
+
+        let (sequence, events) = match exchange {
+            Exchange::Coinbase => parse_coinbase(&text, strict)?,
+            _ => (None, parse_gemini(&text, strict)?),
+        };
+
+        if let Some(sequence) = sequence {
+            sequence_book.check_sequence(sequence)?;
+        }
+        for event in events {
+            updates.send((exchange, event)).await?;
+        }
+    }
+
+    Err(anyhow!("connection ended"))
+}
@@ -0,0 +1,76 @@
+// Trading pair handling. Symbols are written as BASE-QUOTE (e.g. BTC-USD) on the CLI
+// and mapped to each exchange's own naming convention.
+
+// Placeholder in the *_API env vars that is replaced by the exchange specific symbol
+const SYMBOL_PLACEHOLDER: &str = "{symbol}";
+
+// Validates a BASE-QUOTE symbol and returns it upper-cased.
+pub fn parse_symbol(s: &str) -> Result<String, String> {
+    let parts: Vec<&str> = s.split('-').collect();
+
+    let is_valid_asset = |asset: &str| {
+        (2..=10).contains(&asset.len()) && asset.chars().all(|c| c.is_ascii_alphanumeric())
+    };
+
+    if parts.len() != 2 || !parts.iter().all(|asset| is_valid_asset(asset)) {
+        return Err(format!("Not a valid symbol {}. Expected BASE-QUOTE, e.g. BTC-USD", s));
+    }
+
+    Ok(s.to_ascii_uppercase())
+}
+
+// Base asset of the pair, e.g. BTC for BTC-USD
+pub fn base_asset(sym: &str) -> &str {
+    sym.split('-').next().unwrap_or(sym)
+}
+
+// Quote asset of the pair, e.g. USD for BTC-USD
+pub fn quote_asset(sym: &str) -> &str {
+    sym.split('-').nth(1).unwrap_or(sym)
+}
+
+// Sign printed in front of amounts in `code`, or the code itself when it has no well known sign
+pub fn currency_symbol(code: &str) -> String {
+    match code.to_ascii_uppercase().as_str() {
+        "USD" => "$",
+        "EUR" => "€",
+        "GBP" => "£",
+        "JPY" => "¥",
+        "KRW" => "₩",
+        "INR" => "₹",
+        "BTC" => "₿",
+        other => return other.to_string(),
+    }
+    .to_string()
+}
+
+// Coinbase uses the upper-case dashed form: BTC-USD
+pub fn to_coinbase(sym: &str) -> String {
+    sym.to_ascii_uppercase()
+}
+
+// Gemini uses the lower-case joined form: btcusd
+pub fn to_gemini(sym: &str) -> String {
+    sym.replace('-', "").to_ascii_lowercase()
+}
+
+// Binance uses the upper-case joined form: BTCUSDT
+pub fn to_binance(sym: &str) -> String {
+    sym.replace('-', "").to_ascii_uppercase()
+}
+
+// Bitstamp uses the lower-case joined form: btcusd
+pub fn to_bitstamp(sym: &str) -> String {
+    sym.replace('-', "").to_ascii_lowercase()
+}
+
+// Fills the {symbol} placeholder of an endpoint template.
+// Templates without a placeholder are returned unchanged.
+pub fn endpoint(template: &str, exchange_symbol: &str) -> String {
+    template.replace(SYMBOL_PLACEHOLDER, exchange_symbol)
+}
+
+// True if the endpoint template can be pointed at different symbols
+pub fn has_placeholder(template: &str) -> bool {
+    template.contains(SYMBOL_PLACEHOLDER)
+}
@@ -1,10 +1,49 @@
 use serde::Deserialize;
-use serde::de::{self, Deserializer, SeqAccess, Visitor, Error};
+use serde::de::{Deserializer, SeqAccess, Visitor, Error};
 use std::fmt;
 use rust_decimal::Decimal;
+use serde_json::Value;
 use std::str::FromStr;
+use tracing::warn;
 
-#[derive(Debug, Deserialize)]
+// An exchange's price or size. Decimal::from_str rounds anything past the 28-29 significant digits a Decimal holds,
+// which is only warned about unless `strict`, where it's an error instead.
+pub fn parse_decimal(s: &str, strict: bool) -> Result<Decimal, String> {
+    let rounded = Decimal::from_str(s).map_err(|e| e.to_string())?;
+    // Trailing zeros after the point carry no precision to lose
+    let significant = if s.contains('.') { s.trim_end_matches('0').trim_end_matches('.') } else { s };
+    if Decimal::from_str_exact(significant).is_err() {
+        if strict {
+            return Err(format!("{} has more digits than a Decimal holds and would be rounded to {}", s, rounded));
+        }
+        warn!("Rounded {} to {} to fit a Decimal", s, rounded);
+    }
+    Ok(rounded)
+}
+
+// Level deserializers round with a warning. A strict parse runs check_precision on the response first.
+fn parse_exchange_decimal<E: Error>(s: &str) -> Result<Decimal, E> {
+    parse_decimal(s, false).map_err(Error::custom)
+}
+
+// Fails on the first price or size under `bids` or `asks` of a REST response that parse_decimal would round.
+// Strings that aren't numbers, like Coinbase order ids, are left for the deserializers.
+pub fn check_precision(data: &Value) -> Result<(), String> {
+    fn check(value: &Value) -> Result<(), String> {
+        match value {
+            Value::String(s) if Decimal::from_str(s).is_ok() => parse_decimal(s, true).map(drop),
+            Value::Array(values) => values.iter().try_for_each(check),
+            Value::Object(fields) => fields.values().try_for_each(check),
+            _ => Ok(()),
+        }
+    }
+    ["bids", "asks"].iter().try_for_each(|side| check(&data[*side]))
+}
+
+// `sequence` and `time` identify the snapshot in the output when this book is priced, see ExchangeAdapter::snapshot.
+// Nothing reads the auction fields.
+#[cfg(feature = "coinbase")]
+#[derive(Debug, Default, Deserialize)]
 pub struct CoinbaseResult {
     pub bids: Vec<CoinbaseOrder>,
     pub asks: Vec<CoinbaseOrder>,
@@ -14,26 +53,27 @@ pub struct CoinbaseResult {
     pub time: String
 }
 
-impl Default for CoinbaseResult {
-    fn default() -> Self {
-        Self {
-            bids: Vec::new(),
-            asks: Vec::new(),
-            sequence: 0,
-            auction_mode: false,
-            auction: None,
-            time: String::new(),
-        }
-    }
-}
-
+#[cfg(feature = "coinbase")]
 #[derive(Debug)]
 pub struct CoinbaseOrder {
     pub price: Decimal,
     pub size: Decimal,
+    // Orders aggregated into the level. Always 1 in a level3 book, where each entry is a single order.
     pub num_orders: u64,
+    // Only set in a level3 book
+    pub order_id: Option<String>,
+}
+
+// Level2 books end each level with the number of orders, level3 books with the order id
+#[cfg(feature = "coinbase")]
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum CoinbaseLevelTail {
+    NumOrders(u64),
+    OrderId(String),
 }
 
+#[cfg(feature = "coinbase")]
 impl<'de> Deserialize<'de> for CoinbaseOrder {
     fn deserialize<D>(deserializer: D) -> Result<CoinbaseOrder, D::Error>
     where
@@ -51,7 +91,7 @@ impl<'de> Deserialize<'de> for CoinbaseOrder {
 
             fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
                 // To present an error file deserialization fails!
-                write!(f, "an array like [\"price\",\"size\",num_orders]")
+                write!(f, "an array like [\"price\",\"size\",num_orders] or [\"price\",\"size\",\"order_id\"]")
             }
 
             // Handles the deserialization of a sequence (an array) into a CoinbaseOrder
@@ -66,15 +106,19 @@ impl<'de> Deserialize<'de> for CoinbaseOrder {
                 let size_str: String = seq
                     .next_element()?
                     .ok_or_else(|| Error::invalid_length(1, &self))?;
-                let num_orders: u64 = seq
+                let tail: CoinbaseLevelTail = seq
                     .next_element()?
                     .ok_or_else(|| Error::invalid_length(2, &self))?;
+                let (num_orders, order_id) = match tail {
+                    CoinbaseLevelTail::NumOrders(num_orders) => (num_orders, None),
+                    CoinbaseLevelTail::OrderId(order_id) => (1, Some(order_id)),
+                };
 
                 // Converts the extracted elements to the target type
-                let price = Decimal::from_str(&price_str).map_err(Error::custom)?;
-                let size = Decimal::from_str(&size_str).map_err(Error::custom)?;
+                let price = parse_exchange_decimal(&price_str)?;
+                let size = parse_exchange_decimal(&size_str)?;
     
-                Ok(CoinbaseOrder { price, size, num_orders })
+                Ok(CoinbaseOrder { price, size, num_orders, order_id })
             }
         }
 
@@ -82,21 +126,17 @@ impl<'de> Deserialize<'de> for CoinbaseOrder {
     }
 }
 
-#[derive(Debug, Deserialize)]
+// A malformed level is skipped with a warning so it doesn't take the rest of the book down with it
+#[cfg(feature = "gemini")]
+#[derive(Debug, Default, Deserialize)]
 pub struct GeminiResult {
+    #[serde(deserialize_with = "skip_malformed_levels")]
     pub bids: Vec<GeminiOrder>,
+    #[serde(deserialize_with = "skip_malformed_levels")]
     pub asks: Vec<GeminiOrder>
 }
 
-impl Default for GeminiResult {
-    fn default() -> Self {
-        Self {
-            bids: Vec::new(),
-            asks: Vec::new(),
-        }
-    }
-}
-
+#[cfg(feature = "gemini")]
 #[derive(Debug, Deserialize)]
 pub struct GeminiOrder {
     #[serde(deserialize_with = "from_str_to_decimal")]
@@ -109,24 +149,126 @@ pub struct GeminiOrder {
     pub timestamp: u64 
 }
 
-// Taking a deserializer D that should implement the Deserializer trait
-fn from_str_to_decimal<'de, D>(d: D) -> Result<Decimal, D::Error>
+// Reads the side as raw JSON first, then keeps each level that parses
+#[cfg(feature = "gemini")]
+fn skip_malformed_levels<'de, D>(d: D) -> Result<Vec<GeminiOrder>, D::Error>
 where
     D: Deserializer<'de>,
 {
-    let s = String::deserialize(d)?;
-    // Return a Result which is Decimal or the deserialization error
-    Decimal::from_str(&s).map_err(Error::custom)
+    let levels = Vec::<serde_json::Value>::deserialize(d)?;
+    Ok(levels
+        .into_iter()
+        .filter_map(|level| match GeminiOrder::deserialize(&level) {
+            Ok(order) => Some(order),
+            Err(e) => {
+                warn!("Skipping malformed Gemini level {}: {}", level, e);
+                None
+            }
+        })
+        .collect())
 }
 
-// Taking a deserializer D that should implement the Deserializer trait.
-fn from_str_to_f64<'de, D>(d: D) -> Result<f64, D::Error>
+// Oldest and newest level timestamps (unix seconds) as (min, max). None for an empty side.
+#[cfg(feature = "gemini")]
+pub fn book_age(orders: &[GeminiOrder]) -> Option<(u64, u64)> {
+    let oldest = orders.iter().map(|order| order.timestamp).min()?;
+    let newest = orders.iter().map(|order| order.timestamp).max()?;
+    Some((oldest, newest))
+}
+
+// Binance /api/v3/depth response. Only the top `limit` levels (100 by default, 5000 max)
+// are returned, so the merged book is only as deep as the `limit` in BINANCE_API.
+#[derive(Debug, Default, Deserialize)]
+pub struct BinanceResult {
+    #[serde(rename = "lastUpdateId")]
+    pub last_update_id: u64,
+    pub bids: Vec<BinanceOrder>,
+    pub asks: Vec<BinanceOrder>,
+}
+
+#[derive(Debug)]
+pub struct BinanceOrder {
+    pub price: Decimal,
+    pub qty: Decimal,
+}
+
+impl<'de> Deserialize<'de> for BinanceOrder {
+    fn deserialize<D>(deserializer: D) -> Result<BinanceOrder, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        // Same approach as CoinbaseOrder, but Binance levels are only ["price", "qty"]
+        struct OrderVisitor;
+
+        impl<'de> Visitor<'de> for OrderVisitor {
+            type Value = BinanceOrder;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "an array like [\"price\",\"qty\"]")
+            }
+
+            fn visit_seq<A>(self, mut seq: A) -> Result<BinanceOrder, A::Error>
+            where
+                A: SeqAccess<'de>,
+            {
+                let price_str: String = seq
+                    .next_element()?
+                    .ok_or_else(|| Error::invalid_length(0, &self))?;
+                let qty_str: String = seq
+                    .next_element()?
+                    .ok_or_else(|| Error::invalid_length(1, &self))?;
+
+                let price = parse_exchange_decimal(&price_str)?;
+                let qty = parse_exchange_decimal(&qty_str)?;
+
+                Ok(BinanceOrder { price, qty })
+            }
+        }
+
+        deserializer.deserialize_seq(OrderVisitor)
+    }
+}
+
+// Taking a deserializer D that should implement the Deserializer trait
+#[cfg(feature = "gemini")]
+fn from_str_to_decimal<'de, D>(d: D) -> Result<Decimal, D::Error>
 where
     D: Deserializer<'de>,
 {
     let s = String::deserialize(d)?;
-    // Return a Result which is f64 or the deserialization error
-    s.parse::<f64>().map_err(Error::custom)
+    // Return a Result which is Decimal or the deserialization error
+    parse_exchange_decimal(&s)
+}
+
+// Bitstamp /api/v2/order_book response. The timestamps are strings of unix seconds and microseconds.
+#[derive(Debug, Default, Deserialize)]
+pub struct BitstampResult {
+    #[serde(deserialize_with = "from_str_to_u64")]
+    pub timestamp: u64,
+    #[serde(deserialize_with = "from_str_to_u64")]
+    pub microtimestamp: u64,
+    pub bids: Vec<BitstampOrder>,
+    pub asks: Vec<BitstampOrder>,
+}
+
+#[derive(Debug)]
+pub struct BitstampOrder {
+    pub price: Decimal,
+    pub amount: Decimal,
+}
+
+impl<'de> Deserialize<'de> for BitstampOrder {
+    fn deserialize<D>(deserializer: D) -> Result<BitstampOrder, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        // Levels are ["price", "amount"], rejected if there are more or fewer entries
+        let (price, amount): (String, String) = Deserialize::deserialize(deserializer)?;
+        Ok(BitstampOrder {
+            price: parse_exchange_decimal(&price)?,
+            amount: parse_exchange_decimal(&amount)?,
+        })
+    }
 }
 
 // Taking a deserializer D that should implement the Deserializer trait.
@@ -139,12 +281,33 @@ where
     s.parse::<u64>().map_err(Error::custom)
 }
 
+// Exchange a merged price level came from.
+// The declaration order is the last tie-break when merging equal levels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Exchange {
+    Coinbase,
+    Gemini,
+    Binance,
+    Bitstamp,
+}
+
+impl fmt::Display for Exchange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Exchange::Coinbase => write!(f, "COINBASE"),
+            Exchange::Gemini => write!(f, "GEMINI"),
+            Exchange::Binance => write!(f, "BINANCE"),
+            Exchange::Bitstamp => write!(f, "BITSTAMP"),
+        }
+    }
+}
+
 // Orderbook for Merged data
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct OrderBook {
     pub price: Decimal,
     pub size: Decimal,
-    pub name: String,
+    pub source: Exchange,
 }
 
 // Implementing PartialOrd for OrderBook
@@ -154,9 +317,148 @@ impl PartialOrd for OrderBook {
     }
 }
 
-// Implementing Ord for OrderBook
+// `price @ size (EXCHANGE)`, e.g. 110025.98 @ 0.016117 (GEMINI)
+impl fmt::Display for OrderBook {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} @ {} ({})", self.price, self.size, self.source)
+    }
+}
+
+// Implementing Ord for OrderBook. Only the price is compared, the source never affects ordering.
 impl Ord for OrderBook {
     fn cmp(&self, other: &Self) -> std::cmp::Ordering {
         self.price.cmp(&other.price)
     }
 }
+
+#[cfg(feature = "coinbase")]
+impl From<CoinbaseOrder> for OrderBook {
+    fn from(order: CoinbaseOrder) -> Self {
+        OrderBook {
+            price: order.price,
+            size: order.size,
+            source: Exchange::Coinbase,
+        }
+    }
+}
+
+#[cfg(feature = "gemini")]
+impl From<GeminiOrder> for OrderBook {
+    fn from(order: GeminiOrder) -> Self {
+        OrderBook {
+            price: order.price,
+            size: order.amount,
+            source: Exchange::Gemini,
+        }
+    }
+}
+
+impl From<BinanceOrder> for OrderBook {
+    fn from(order: BinanceOrder) -> Self {
+        OrderBook {
+            price: order.price,
+            size: order.qty,
+            source: Exchange::Binance,
+        }
+    }
+}
+
+impl From<BitstampOrder> for OrderBook {
+    fn from(order: BitstampOrder) -> Self {
+        OrderBook {
+            price: order.price,
+            size: order.amount,
+            source: Exchange::Bitstamp,
+        }
+    }
+}
+
+// An OrderBook level that also keeps how many orders rest at it, for looking at liquidity quality.
+// Only Coinbase sends a count, so levels from the other exchanges have None.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OrderBookDetailed {
+    pub price: Decimal,
+    pub size: Decimal,
+    pub num_orders: Option<u64>,
+    pub source: Exchange,
+}
+
+#[cfg(feature = "coinbase")]
+impl From<CoinbaseOrder> for OrderBookDetailed {
+    fn from(order: CoinbaseOrder) -> Self {
+        OrderBookDetailed {
+            price: order.price,
+            size: order.size,
+            num_orders: Some(order.num_orders),
+            source: Exchange::Coinbase,
+        }
+    }
+}
+
+#[cfg(feature = "gemini")]
+impl From<GeminiOrder> for OrderBookDetailed {
+    fn from(order: GeminiOrder) -> Self {
+        OrderBookDetailed {
+            price: order.price,
+            size: order.amount,
+            num_orders: None,
+            source: Exchange::Gemini,
+        }
+    }
+}
+
+impl From<BinanceOrder> for OrderBookDetailed {
+    fn from(order: BinanceOrder) -> Self {
+        OrderBookDetailed {
+            price: order.price,
+            size: order.qty,
+            num_orders: None,
+            source: Exchange::Binance,
+        }
+    }
+}
+
+// One exchange's book with both sides normalized into OrderBook levels
+#[derive(Debug, Default)]
+pub struct NormalizedBook {
+    pub asks: Vec<OrderBook>,
+    pub bids: Vec<OrderBook>,
+}
+
+#[cfg(feature = "coinbase")]
+impl From<CoinbaseResult> for NormalizedBook {
+    fn from(result: CoinbaseResult) -> Self {
+        NormalizedBook {
+            asks: result.asks.into_iter().map(OrderBook::from).collect(),
+            bids: result.bids.into_iter().map(OrderBook::from).collect(),
+        }
+    }
+}
+
+#[cfg(feature = "gemini")]
+impl From<GeminiResult> for NormalizedBook {
+    fn from(result: GeminiResult) -> Self {
+        NormalizedBook {
+            asks: result.asks.into_iter().map(OrderBook::from).collect(),
+            bids: result.bids.into_iter().map(OrderBook::from).collect(),
+        }
+    }
+}
+
+impl From<BinanceResult> for NormalizedBook {
+    fn from(result: BinanceResult) -> Self {
+        NormalizedBook {
+            asks: result.asks.into_iter().map(OrderBook::from).collect(),
+            bids: result.bids.into_iter().map(OrderBook::from).collect(),
+        }
+    }
+}
+
+impl From<BitstampResult> for NormalizedBook {
+    fn from(result: BitstampResult) -> Self {
+        NormalizedBook {
+            asks: result.asks.into_iter().map(OrderBook::from).collect(),
+            bids: result.bids.into_iter().map(OrderBook::from).collect(),
+        }
+    }
+}
@@ -0,0 +1,71 @@
+//! Order book aggregation across exchanges: fetch each exchange's book, merge them and price a quantity.
+//! The `ob-aggregator-rs` command line tool is built on this crate.
+//!
+//! ```
+//! use ob_aggregator_rs::{merge, price_quantity, Exchange, NormalizedBook, OrderBook};
+//! use rust_decimal::Decimal;
+//!
+//! let level = |price: i64, size: i64, source| OrderBook { price: Decimal::from(price), size: Decimal::from(size), source };
+//! let coinbase = NormalizedBook {
+//!     asks: vec![level(101, 1, Exchange::Coinbase), level(103, 1, Exchange::Coinbase)],
+//!     bids: vec![level(99, 1, Exchange::Coinbase)],
+//! };
+//! let gemini = NormalizedBook {
+//!     asks: vec![level(102, 1, Exchange::Gemini)],
+//!     bids: vec![level(100, 1, Exchange::Gemini), level(98, 1, Exchange::Gemini)],
+//! };
+//!
+//! let book = merge(vec![coinbase, gemini]);
+//! let quote = price_quantity(&book, Decimal::from(2));
+//!
+//! // Two units cost the best two asks across both books, and sell into the best two bids
+//! assert_eq!(quote.buy, Ok(Decimal::from(203)));
+//! assert_eq!(quote.sell, Ok(Decimal::from(199)));
+//! ```
+
+pub mod helpers;
+
+use anyhow::Result;
+use reqwest::header::HeaderMap;
+use reqwest::Client;
+use rust_decimal::Decimal;
+
+use helpers::data_fetcher::get_data;
+use helpers::orderbook_merger::{calculate_entity_price, merge_asks, merge_bids};
+
+pub use helpers::api_client::create_client;
+pub use helpers::exchange::ExchangeAdapter;
+pub use helpers::orderbook_merger::PriceError;
+pub use helpers::types::{Exchange, NormalizedBook, OrderBook};
+
+// What filling a quantity against a book would cost on the buy side and return on the sell side
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PricedQuantity {
+    pub quantity: Decimal,
+    pub buy: Result<Decimal, PriceError>,
+    pub sell: Result<Decimal, PriceError>,
+}
+
+// Fetches `exchange`'s book for a BASE-QUOTE symbol, with asks ascending and bids descending.
+// With `strict`, a price or size that a Decimal can't hold exactly fails the fetch instead of being rounded.
+pub async fn fetch_book(client: &Client, exchange: &dyn ExchangeAdapter, symbol: &str, strict: bool) -> Result<NormalizedBook> {
+    let data = get_data(client, &exchange.endpoint(symbol), &HeaderMap::new()).await?;
+    let (asks, bids) = exchange.parse(data, strict)?;
+    Ok(NormalizedBook { asks, bids })
+}
+
+// Merges the books of several exchanges into one, best price first on each side.
+// Equal prices put the larger size first, and equal sizes keep the order the books were given in.
+pub fn merge(books: Vec<NormalizedBook>) -> NormalizedBook {
+    let (asks, bids) = books.into_iter().map(|book| (book.asks, book.bids)).unzip();
+    NormalizedBook { asks: merge_asks(asks, false, false), bids: merge_bids(bids, false, false) }
+}
+
+// Total cost of buying `quantity` from the asks and total proceeds of selling it into the bids
+pub fn price_quantity(book: &NormalizedBook, quantity: Decimal) -> PricedQuantity {
+    PricedQuantity {
+        quantity,
+        buy: calculate_entity_price(&book.asks, quantity, true, "ASKS"),
+        sell: calculate_entity_price(&book.bids, quantity, false, "BIDS"),
+    }
+}
@@ -1,38 +1,77 @@
-use clap::{Parser};
+use clap::{CommandFactory, Parser, Subcommand, ValueEnum};
 use dotenvy::dotenv;
 use std::{
     env,
-    sync::Arc,
-    time::{Duration, Instant},
+    fmt::{self, Write as _},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
-use serde_json::from_value;
-use log::{info, debug};
-use env_logger;
-use anyhow::Result;
-use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tracing::{info, debug, info_span, warn, Instrument, Span};
+use tracing_subscriber::{filter::LevelFilter, EnvFilter};
+use anyhow::{Context, Result};
+use rust_decimal::{Decimal, RoundingStrategy};
+use rust_decimal::prelude::ToPrimitive;
 use num_format::{Locale, ToFormattedString};
+use reqwest::Client;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use futures_util::future::join_all;
+use tokio::sync::Semaphore;
+use tokio_util::sync::CancellationToken;
 
-mod helpers;
+use ob_aggregator_rs::helpers;
 
 use helpers::{
-    api_client, 
-    data_fetcher::get_data, 
+    api_client::{self, ApiKeys},
+    cache::{BookCache, CacheHit},
+    data_fetcher::{get_data, get_data_rate_limited, get_data_with_retries, is_parse_error},
+    exchange::{self, ExchangeAdapter},
+    export,
+    metrics,
+    mock_server,
     orderbook_merger::{
-        merge_sorted_asks,
-        merge_sorted_bids,
-        calculate_entity_price
+        merge_asks,
+        merge_bids,
+        consolidate,
+        dedup_levels,
+        truncate_to_depth,
+        round_to_tick,
+        calculate_entity_price,
+        book_share,
+        build_cumulative,
+        calculate_vwap,
+        effective_spread,
+        fill_by_quote,
+        apply_fee,
+        calculate_fill_breakdown,
+        depth_to_price,
+        simulate_limit,
+        FillBreakdown,
+        slippage,
+        price_curve,
+        spread,
+        micro_price,
+        imbalance,
+        best_level,
+        detect_outlier,
+        is_crossed,
+        validate_book,
+        PriceError
     },
     types::{
-        CoinbaseResult,
-        GeminiResult
+        NormalizedBook,
+        OrderBook
     },
+    prefix_book::PrefixBook,
     rate_limiter::RateLimiter,
+    stream::{self, BookSide, FeedEvent, LevelChange, LiveBook},
+    symbol::{self, parse_symbol},
+    types::Exchange,
 };
 
-use crate::helpers::types::OrderBook;
 
-
-#[derive(Parser, Debug)]
+#[derive(Parser, Debug, Clone)]
 #[command(
     name = "ob-aggregator-rs",
     version = "0.0.1",
@@ -40,247 +79,4864 @@ use crate::helpers::types::OrderBook;
     about = "This app helps you compute the quantity of BTC you can buy or sell",
     long_about = "This is a simple program to analyze the orderbook price and print the best bid and ask price"
 )]
-
 struct Args {
     /// Quantity
-    #[arg(short, long, value_parser = parse_qty, default_value_t = String::from("10.0"))]
-    qty: String,
+    #[arg(short, long, value_parser = parse_qty_or_zero, default_value = "10.0")]
+    qty: Decimal,
+
+    /// Accept --qty 0, which prices an empty order
+    #[arg(long)]
+    allow_zero: bool,
+
+    /// Currency code used to label prices, defaults to the quote asset of --symbol
+    #[arg(long, value_name = "CODE", value_parser = parse_currency)]
+    currency: Option<String>,
+
+    /// Amount of quote currency (e.g. USD) to spend, reports the base quantity it buys or has to be sold
+    #[arg(long, value_parser = parse_qty, conflicts_with = "qty")]
+    quote_amount: Option<Decimal>,
+
+    /// Trading pair as BASE-QUOTE
+    #[arg(short, long, value_parser = parse_symbol, default_value_t = String::from("BTC-USD"))]
+    symbol: String,
+
+    /// Also price a ladder of quantities from the same book, e.g. 0.1,1,5,10
+    #[arg(long, value_name = "QTYS", value_parser = parse_qty, value_delimiter = ',')]
+    quantities: Option<Vec<Decimal>>,
+
+    /// Price several pairs at once, e.g. BTC-USD,ETH-USD,SOL-USD
+    #[arg(
+        long,
+        value_parser = parse_symbol,
+        value_delimiter = ',',
+        conflicts_with_all = ["symbol", "stream", "interval", "csv", "coinbase_file", "gemini_file"]
+    )]
+    symbols: Option<Vec<String>>,
+
+    /// With --symbols, keep at most this many exchange requests in flight at once
+    #[arg(long, value_name = "N", requires = "symbols", value_parser = clap::value_parser!(u32).range(1..))]
+    max_concurrency: Option<u32>,
+
+    /// Print the average ask/bid price sampled at this many points up to the full book size, at most 10000
+    #[arg(long, value_name = "POINTS", value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..=MAX_CURVE_POINTS))]
+    curve: Option<usize>,
+
+    /// Print the effective spread for this size: the average buy price minus the average sell price
+    #[arg(long, value_name = "QTY", value_parser = parse_qty)]
+    round_trip: Option<Decimal>,
+
+    /// Print the total bid size over the total ask size within the best N levels of each side (10 if N is left out)
+    #[arg(long, value_name = "N", num_args = 0..=1, default_missing_value = "10")]
+    imbalance: Option<usize>,
+
+    /// Slow the rate limiter down when an exchange answers 429 and retry instead of failing
+    #[arg(long)]
+    adaptive_rate_limit: bool,
+
+    /// Send requests without waiting on the rate limiter. Implied when every book is read from a file
+    #[arg(long, conflicts_with = "adaptive_rate_limit")]
+    no_rate_limit: bool,
+
+    /// Also print the volume weighted average price per BTC
+    #[arg(long)]
+    vwap: bool,
+
+    /// Which side of the book to price
+    #[arg(long, value_enum, default_value_t = Side::Both)]
+    side: Side,
+
+    /// Output format
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+    format: OutputFormat,
+
+    /// Print the top N merged levels of each side with the exchange they came from
+    #[arg(long, value_name = "N")]
+    show_source: Option<usize>,
+
+    /// Print the top N asks and bids side by side with their cumulative size (20 if N is left out)
+    #[arg(long, value_name = "N", num_args = 0..=1, default_missing_value = "20")]
+    pretty: Option<usize>,
+
+    /// Print only the best ask and best bid, found without merging the books
+    #[arg(long)]
+    top: bool,
+
+    /// Combine levels with the same price across exchanges into a single level
+    #[arg(long)]
+    consolidate: bool,
+
+    /// Retry a failed request this many times on 429, 5xx or network errors, at most 10
+    #[arg(long, default_value_t = 0, value_parser = clap::value_parser!(u32).range(0..=10))]
+    retries: u32,
+
+    /// HTTP timeout for each exchange request, in seconds
+    #[arg(long, default_value_t = api_client::DEFAULT_TIMEOUT.as_secs(), value_parser = clap::value_parser!(u64).range(1..))]
+    timeout_secs: u64,
+
+    /// Stop waiting for the exchanges this many seconds after the fetch starts and price whatever has arrived
+    #[arg(long, value_name = "SECS", value_parser = clap::value_parser!(u64).range(1..))]
+    deadline: Option<u64>,
+
+    /// Extra header sent with every exchange request, as "Name: value". Can be repeated
+    #[arg(long = "header", value_name = "NAME: VALUE", value_parser = api_client::parse_header)]
+    headers: Vec<(HeaderName, HeaderValue)>,
+
+    /// Allow this many requests back to back before the 2 second rate limit kicks in
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    burst: Option<u32>,
+
+    /// Add a random 0 to FRACTION share of each rate limit wait, so parallel requests don't all retry together
+    #[arg(long, value_name = "FRACTION", value_parser = parse_jitter)]
+    jitter: Option<f64>,
+
+    /// Fail if any exchange returns a book that is not sorted best price first
+    #[arg(long)]
+    validate: bool,
+
+    /// Fail instead of warning when the merged book is crossed (best bid at or above best ask)
+    #[arg(long)]
+    strict: bool,
+
+    /// Fail to parse an exchange's book when a price or size has more digits than can be held exactly, instead of rounding it with a warning
+    #[arg(long)]
+    strict_precision: bool,
+
+    /// Drop zero size levels while merging so they don't show up in the book or its stats
+    #[arg(long)]
+    skip_empty: bool,
+
+    /// Drop levels that exactly repeat the one before them (same price, size and exchange) while merging.
+    /// Leave off for a Coinbase level3 book, where two equal orders at a price are both real
+    #[arg(long)]
+    dedup: bool,
+
+    /// Also print how far the average fill price is from the best price, as a percentage
+    #[arg(long)]
+    show_slippage: bool,
+
+    /// Ignore levels smaller than this size, e.g. dust, when merging and pricing. 0 keeps every level
+    #[arg(long, value_name = "SIZE", default_value_t = Decimal::ZERO)]
+    min_size: Decimal,
+
+    /// Round every price to this tick, e.g. 0.01, before merging so near equal levels are combined
+    #[arg(long, value_name = "TICK", value_parser = parse_tick)]
+    tick: Option<Decimal>,
+
+    /// Trust every exchange to send its book sorted and skip sorting it before merging
+    #[arg(long)]
+    assume_sorted: bool,
+
+    /// Taker fee in basis points, also prints the buy and sell totals net of the fee
+    #[arg(long, value_name = "BPS", value_parser = parse_fee_bps)]
+    fee_bps: Option<Decimal>,
+
+    /// Also print how much of each fill comes from each exchange
+    #[arg(long)]
+    breakdown: bool,
+
+    /// Also print how much is offered at or below this price and bid at or above it
+    #[arg(long, value_name = "PRICE")]
+    up_to_price: Option<Decimal>,
+
+    /// Simulate a limit order for --qty at this price: what fills now and what would rest on the book
+    #[arg(long, value_name = "PRICE", conflicts_with = "quote_amount")]
+    limit_price: Option<Decimal>,
+
+    /// Only merge and price the best N levels of each exchange's book
+    #[arg(long, value_name = "N")]
+    depth: Option<usize>,
+
+    /// Follow the Coinbase and Gemini level2 WebSocket feeds and print the prices whenever they change
+    #[arg(long)]
+    stream: bool,
+
+    /// Serve Prometheus metrics for fetches and merges on this port
+    #[arg(long, value_name = "PORT")]
+    metrics_port: Option<u16>,
+
+    /// Keep the last successful book of each exchange in this directory and reuse it when a fetch fails
+    #[arg(long, value_name = "DIR")]
+    cache_dir: Option<PathBuf>,
+
+    /// Warn when a cached book used in place of a failed fetch is older than this many seconds
+    #[arg(long, default_value_t = 30)]
+    cache_stale_secs: u64,
+
+    /// Repeat the fetch, merge and price cycle every this many seconds until Ctrl-C
+    #[arg(long, value_name = "SECS", value_parser = clap::value_parser!(u64).range(1..))]
+    interval: Option<u64>,
+
+    /// Warn when the oldest Gemini level on a side was last updated more than this many seconds ago
+    #[arg(long, value_name = "SECS")]
+    gemini_stale_secs: Option<u64>,
+
+    /// Drop an exchange's book when its newest data is more than this many seconds old.
+    /// Uses the Gemini level timestamps and the Coinbase snapshot time
+    #[arg(long, value_name = "SECS")]
+    max_age: Option<u64>,
+
+    /// Fail unless at least this many exchanges returned a usable book
+    #[arg(long, value_name = "N", default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+    min_exchanges: u32,
+
+    /// Drop an exchange's book when its best bid or ask is more than this many percent from the median of all the exchanges'.
+    /// Without it books more than 5% off are only warned about.
+    /// With fewer than three exchanges pricing a side there is no telling which one is off, so nothing is dropped
+    #[arg(long, value_name = "PCT", value_parser = parse_percent)]
+    reject_outliers: Option<Decimal>,
+
+    /// Coinbase endpoint to use instead of COINBASE_API. May contain a {symbol} placeholder
+    #[arg(long, value_name = "URL", conflicts_with = "coinbase_file")]
+    coinbase_url: Option<String>,
+
+    /// Gemini endpoint to use instead of GEMINI_API. May contain a {symbol} placeholder
+    #[arg(long, value_name = "URL", conflicts_with = "gemini_file")]
+    gemini_url: Option<String>,
+
+    /// Read the Coinbase book from this JSON file instead of COINBASE_API
+    #[arg(long, value_name = "PATH")]
+    coinbase_file: Option<PathBuf>,
+
+    /// Read the Gemini book from this JSON file instead of GEMINI_API
+    #[arg(long, value_name = "PATH")]
+    gemini_file: Option<PathBuf>,
+
+    /// Save each exchange's raw response in this directory, to replay later with --coinbase-file or --gemini-file
+    #[arg(long, value_name = "DIR")]
+    record: Option<PathBuf>,
+
+    /// Write the merged book to this CSV file as side,price,size,cumulative_size
+    #[arg(long, value_name = "PATH")]
+    csv: Option<PathBuf>,
+
+    /// Decimal places shown in prices and totals
+    #[arg(long, value_name = "N", default_value_t = 2, value_parser = clap::value_parser!(u32).range(0..=12))]
+    price_dp: u32,
+
+    /// Digit grouping and decimal separator of the printed amounts, e.g. en, de or fr.
+    /// An unknown code falls back to en with a warning
+    #[arg(long, value_name = "CODE", default_value = "en")]
+    locale: String,
+
+    /// Log info with -v, debug with -vv and trace with -vvv, over the level in RUST_LOG. Without it only warnings, unless RUST_LOG says otherwise
+    #[arg(short, long, action = clap::ArgAction::Count, global = true)]
+    verbose: u8,
+
+    /// How prices and totals are rounded to --price-dp. conservative rounds buy costs up and sell proceeds down
+    #[arg(long, value_enum, default_value_t = Rounding::Conservative)]
+    rounding: Rounding,
+
+    /// Print how the best prices and the quote moved since a quote saved with --format json, in the text report
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["symbols", "interval", "stream"])]
+    compare: Option<PathBuf>,
+
+    /// Also write the quote, in --format, to this file
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["interval", "stream"])]
+    output_file: Option<PathBuf>,
+
+    /// Validate the configuration and exit without contacting any exchange
+    #[arg(long)]
+    check: bool,
+
+    #[command(subcommand)]
+    command: Option<Command>,
 }
 
-fn parse_qty(s: &str) -> Result<String, String> {
-    let v: f64 = s.parse::<f64>().map_err(|e| format!("Not a valid quantity {}. Error : {}", s, e))?;
+// Without a subcommand the book is fetched, merged and priced
+#[derive(Subcommand, Debug, Clone, PartialEq, Eq)]
+enum Command {
+    /// Send one GET to each configured exchange and report whether it answered and how fast, without merging or pricing
+    Selftest,
+    /// Serve static Coinbase and Gemini books on localhost, to point COINBASE_API and GEMINI_API at for a demo
+    ServeMock {
+        /// Port to listen on
+        #[arg(long, default_value_t = 8080)]
+        port: u16,
+        /// Coinbase book served on /coinbase
+        #[arg(long, value_name = "PATH", default_value = "coinbase.json")]
+        coinbase_book: PathBuf,
+        /// Gemini book served on /gemini
+        #[arg(long, value_name = "PATH", default_value = "gemini.json")]
+        gemini_book: PathBuf,
+    },
+}
 
-    if !v.is_finite() {
-        return Err("Value must be finite".into());
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum OutputFormat {
+    Text,
+    Json,
+    // Json with a timestamp, for tailing the --interval output one object per line
+    Jsonl,
+}
+
+// Machine readable quote printed with --format json.
+// Decimals serialize as strings so no precision is lost.
+#[derive(Debug, Serialize, Deserialize)]
+struct JsonQuote {
+    symbol: String,
+    quantity: Decimal,
+    buy_price: Option<Decimal>,
+    sell_price: Option<Decimal>,
+    currency: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    buy_vwap: Option<Decimal>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sell_vwap: Option<Decimal>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    buy_net: Option<Decimal>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sell_net: Option<Decimal>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    quote_amount: Option<Decimal>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    buy_quantity: Option<Decimal>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sell_quantity: Option<Decimal>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    buy_book_share: Option<Decimal>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sell_book_share: Option<Decimal>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    ask_size_to_price: Option<Decimal>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    bid_size_to_price: Option<Decimal>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    coinbase_sequence: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    coinbase_time: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    best_ask: Option<Decimal>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    best_bid: Option<Decimal>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    imbalance: Option<Decimal>,
+}
+
+// One line of --format jsonl: the JSON quote stamped with when it was rendered
+#[derive(Debug, Serialize)]
+struct JsonLine {
+    timestamp: String,
+    #[serde(flatten)]
+    quote: JsonQuote,
+}
+
+// How far each figure moved since a saved quote, as current minus previous.
+// None when either quote lacks the figure.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct QuoteChange {
+    best_ask: Option<Decimal>,
+    best_bid: Option<Decimal>,
+    buy_price: Option<Decimal>,
+    sell_price: Option<Decimal>,
+}
+
+impl QuoteChange {
+    fn between(previous: &JsonQuote, current: &JsonQuote) -> Self {
+        let delta = |previous: Option<Decimal>, current: Option<Decimal>| Some(current? - previous?);
+        QuoteChange {
+            best_ask: delta(previous.best_ask, current.best_ask),
+            best_bid: delta(previous.best_bid, current.best_bid),
+            buy_price: delta(previous.buy_price, current.buy_price),
+            sell_price: delta(previous.sell_price, current.sell_price),
+        }
     }
+}
 
-    if v <= 0.0 {
-        return Err("Value cannot be negative".into());
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+enum Side {
+    Buy,
+    Sell,
+    #[default]
+    Both,
+}
+
+impl Side {
+    // Buying walks the asks
+    fn includes_buy(self) -> bool {
+        matches!(self, Side::Buy | Side::Both)
     }
 
-    // Not converting to Decimal inorder not to loose precision.
-    Ok(s.to_string())
+    // Selling walks the bids
+    fn includes_sell(self) -> bool {
+        matches!(self, Side::Sell | Side::Both)
+    }
 }
 
-#[tokio::main]
-async fn main() -> Result<()>{
-    env_logger::init();
-    dotenv().ok();
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+enum Rounding {
+    // Half away from zero
+    Nearest,
+    Up,
+    Down,
+    // Against the trader: buys up, sells down, and anything else to the nearest
+    #[default]
+    Conservative,
+}
 
-    let args = Args::parse();
-    info!("Orderbook aggregator started");
+impl Rounding {
+    // `is_buy` is None for figures that belong to neither side, like the spread
+    fn strategy(self, is_buy: Option<bool>) -> RoundingStrategy {
+        match (self, is_buy) {
+            (Rounding::Up, _) | (Rounding::Conservative, Some(true)) => RoundingStrategy::ToPositiveInfinity,
+            (Rounding::Down, _) | (Rounding::Conservative, Some(false)) => RoundingStrategy::ToNegativeInfinity,
+            (Rounding::Nearest, _) | (Rounding::Conservative, None) => RoundingStrategy::MidpointAwayFromZero,
+        }
+    }
 
-    let coinbase_api: &str = &env::var("COINBASE_API").unwrap();
-    let gemini_api: &str = &env::var("GEMINI_API").unwrap();
+    // Rounds `value` to `dp` places, see strategy
+    fn round(self, value: Decimal, dp: u32, is_buy: Option<bool>) -> Decimal {
+        value.round_dp_with_strategy(dp, self.strategy(is_buy))
+    }
+}
 
-    // Create a client to fetch the data from the APIs
-    let client = api_client::create_client();
+// Options controlling how the exchange books are merged
+#[derive(Clone, Copy, Debug, Default)]
+struct MergeOptions {
+    side: Side,
+    consolidate: bool,
+    skip_empty: bool,
+    dedup: bool,
+    min_size: Decimal,
+    depth: Option<usize>,
+    assume_sorted: bool,
+    tick: Option<Decimal>,
+}
+
+impl From<&Args> for MergeOptions {
+    fn from(args: &Args) -> Self {
+        MergeOptions {
+            side: args.side,
+            consolidate: args.consolidate,
+            skip_empty: args.skip_empty,
+            dedup: args.dedup,
+            min_size: args.min_size,
+            depth: args.depth,
+            assume_sorted: args.assume_sorted,
+            tick: args.tick,
+        }
+    }
+}
+
+// Merges only the sides needed for the requested direction across all exchange books. Returns (asks, bids).
+fn merge_books(options: MergeOptions, books: Vec<NormalizedBook>) -> (Option<Vec<OrderBook>>, Option<Vec<OrderBook>>) {
+    let (ask_books, bid_books) = split_sides(books);
+    (merge_side(options, ask_books, true), merge_side(options, bid_books, false))
+}
 
-    // Create a rate limiter
-    let rate_limiter = Arc::new(RateLimiter::new_per_interval(Duration::from_secs(2)));
+// Same result as merge_books, but asks and bids are merged on two blocking threads at once
+async fn merge_books_parallel(options: MergeOptions, books: Vec<NormalizedBook>) -> Result<(Option<Vec<OrderBook>>, Option<Vec<OrderBook>>)> {
+    let (ask_books, bid_books) = split_sides(books);
+    // Blocking tasks don't inherit the caller's span, so their logs are attributed explicitly
+    let (ask_span, bid_span) = (Span::current(), Span::current());
+    let (asks, bids) = tokio::join!(
+        tokio::task::spawn_blocking(move || ask_span.in_scope(|| merge_side(options, ask_books, true))),
+        tokio::task::spawn_blocking(move || bid_span.in_scope(|| merge_side(options, bid_books, false))),
+    );
+    Ok((asks?, bids?))
+}
 
-    let coinbase_rl = Arc::clone(&rate_limiter);
-    let gemini_rl = Arc::clone(&rate_limiter);
+fn split_sides(books: Vec<NormalizedBook>) -> (Vec<Vec<OrderBook>>, Vec<Vec<OrderBook>>) {
+    books.into_iter().map(|book| (book.asks, book.bids)).unzip()
+}
 
-    info!("Fetching the Data from Coinbase and Gemini");
+// Merges one side of every exchange, or returns None when --side doesn't need it
+fn merge_side(options: MergeOptions, mut books: Vec<Vec<OrderBook>>, is_ascending: bool) -> Option<Vec<OrderBook>> {
+    let wanted = if is_ascending { options.side.includes_buy() } else { options.side.includes_sell() };
+    if !wanted {
+        return None;
+    }
 
-    // Fetch the entire dataset from the APIs
-    let (result_coinbase, result_gemini) = tokio::join!(
-        async {
-            coinbase_rl.acquire().await;
-            get_data(&client, &coinbase_api).await
-        },
-        async {
-            gemini_rl.acquire().await;
-            get_data(&client, &gemini_api).await
+    // Duplicates are dropped per exchange, before rounding to a tick can make distinct levels equal.
+    // A merge keeps each exchange's levels at a price together, so this is the same as deduping the merged side.
+    if options.dedup {
+        books = books.into_iter().map(dedup_levels).collect();
+    }
+
+    if let Some(tick) = options.tick {
+        books.iter_mut().flatten().for_each(|level| level.price = round_to_tick(level.price, tick));
+    }
+
+    // Dust is dropped before --depth so it doesn't use up the visible levels
+    if options.min_size > Decimal::ZERO {
+        books.iter_mut().for_each(|book| book.retain(|level| level.size >= options.min_size));
+    }
+
+    if let Some(depth) = options.depth {
+        books.iter_mut().for_each(|book| truncate_to_depth(book, depth, is_ascending));
+    }
+
+    let merged = if is_ascending { merge_asks(books, options.skip_empty, options.assume_sorted) } else { merge_bids(books, options.skip_empty, options.assume_sorted) };
+    // Levels rounded onto the same tick are only useful combined
+    Some(if options.consolidate || options.tick.is_some() { consolidate(merged) } else { merged })
+}
+
+// (buy, sell) cost of a quantity, a side is None when --side doesn't need it
+type SidePrices = (Option<Result<Decimal, PriceError>>, Option<Result<Decimal, PriceError>>);
+
+// The merged sides of the streamed books, kept up to date from the feed's own level changes so a tick
+// reprices without merging or walking everything again. Prices the same as merge_books and calculate_entity_price.
+struct LiveMerge {
+    min_size: Decimal,
+    asks: Option<PrefixBook>,
+    bids: Option<PrefixBook>,
+}
+
+impl LiveMerge {
+    // None with --tick or --depth, which reshape whole books and need the full merge
+    fn new(options: MergeOptions) -> Option<Self> {
+        if options.tick.is_some() || options.depth.is_some() {
+            return None;
         }
-    );
+        Some(LiveMerge {
+            min_size: options.min_size,
+            asks: options.side.includes_buy().then(|| PrefixBook::new(true)),
+            bids: options.side.includes_sell().then(|| PrefixBook::new(false)),
+        })
+    }
 
-    // Parse the data from the APIs
-    let coinbase_data: Option<CoinbaseResult> = match result_coinbase {
-        Ok(value) => {
-            match from_value(value) {
-                Ok(data) => Some(data),
-                Err(e) => {
-                    info!("Error fetching Coinbase data! Error: {:?}", e);
-                    None
+    // Applies one event from `source`'s feed. Only a Snapshot, which replaces that exchange's levels, rebuilds a side.
+    fn apply(&mut self, source: Exchange, event: &FeedEvent) {
+        // Dust is left out the same way merge_side drops it
+        let kept = |change: &LevelChange| !change.size.is_zero() && change.size >= self.min_size;
+        let level = |change: &LevelChange| OrderBook { price: change.price, size: change.size, source };
+
+        match event {
+            FeedEvent::Snapshot(levels) => {
+                for (side, book_side) in [(&mut self.asks, BookSide::Ask), (&mut self.bids, BookSide::Bid)] {
+                    let Some(side) = side else { continue };
+                    let others = side.levels().iter().filter(|existing| existing.source != source).cloned();
+                    let fresh = levels.iter().filter(|change| change.side == book_side && kept(change)).map(level);
+                    *side = PrefixBook::from_levels(others.chain(fresh).collect::<Vec<_>>(), book_side == BookSide::Ask);
                 }
             }
-        },
-        Err(e) => {
-            debug!("Error : {:?}", e);
-            None
-        }
-    };
-    
-    let gemini_data: Option<GeminiResult> = match result_gemini {
-        Ok(value) => {
-            match from_value(value) {
-                Ok(data) => Some(data),
-                Err(e) => {
-                    info!("Error fetching Gemini data! Error: {:?}", e);
-                    None
+            FeedEvent::Update(changes) => {
+                for change in changes {
+                    let side = match change.side {
+                        BookSide::Ask => self.asks.as_mut(),
+                        BookSide::Bid => self.bids.as_mut(),
+                    };
+                    let Some(side) = side else { continue };
+                    if kept(change) {
+                        side.insert(level(change));
+                    } else {
+                        side.remove(change.price, source);
+                    }
                 }
             }
-        },
-        Err(e) => {
-            debug!("Error : {:?}", e);
-            None
         }
-    };
+    }
+
+    fn quote(&self, qty: Decimal) -> SidePrices {
+        (self.asks.as_ref().map(|asks| asks.fill(qty)), self.bids.as_ref().map(|bids| bids.fill(qty)))
+    }
+}
 
-    // If both are None, return an error. Quitting..
-    if coinbase_data.is_none() && gemini_data.is_none() {
-        return Err(anyhow::anyhow!("Failed to fetch data from Coinbase and Gemini. Quitting..!"));
+// Whether the build has the REST fetch for `exchange`, see the coinbase and gemini features
+fn compiled_in(exchange: Exchange) -> bool {
+    match exchange {
+        Exchange::Coinbase => cfg!(feature = "coinbase"),
+        Exchange::Gemini => cfg!(feature = "gemini"),
+        Exchange::Binance | Exchange::Bitstamp => true,
     }
+}
 
-    // If either is None, use the other one. If both are Some, use both.
-    // The logic is designed to move ahead if either of them fails. 
-    let coinbase_data = coinbase_data.unwrap_or_default();
-    let gemini_data = gemini_data.unwrap_or_default();
+// Reads a required endpoint template from the environment
+fn api_template(name: &str) -> Result<String> {
+    env::var(name).with_context(|| format!("{} not set; create a .env or export it", name))
+}
 
-    info!("Loaded the data successfully from Coinbase and Gemini");
-    info!("Coinbase bids: {}, asks: {}", coinbase_data.bids.len(), coinbase_data.asks.len());
-    info!("Gemini bids: {}, asks: {}", gemini_data.bids.len(), gemini_data.asks.len());
-    info!("--------------------------------");
+// How far an exchange's best price can stray from the others before a warning, without --reject-outliers
+const OUTLIER_WARN_PCT: Decimal = Decimal::from_parts(5, 0, 0, false, 0);
 
-    info!("Merging bids");
+const ZERO_QTY: &str = "Value cannot be zero, pass --allow-zero to price an empty order";
 
-    // Merge orderbooks 
-    let (merged_asks, merged_bids) = tokio::task::spawn_blocking(move || {
-        let asks = merge_sorted_asks(coinbase_data.asks, gemini_data.asks);
-        let bids = merge_sorted_bids(coinbase_data.bids, gemini_data.bids);
-        (asks, bids)
-    })
-    .await?;
+// Feed events queued between the streams and the pricing loop before a stream waits
+const STREAM_BUFFER: usize = 1024;
 
-    info!("Asks merged successfully! Total: {}", merged_asks.len());
-    info!("Bids merged successfully! Total: {}", merged_bids.len());
+// Largest --curve accepted, each point is a line of output
+const MAX_CURVE_POINTS: u64 = 10_000;
 
-    // let cb_first_20 = &merged_asks[..20.min(merged_asks.len())];
-    // println!("{:?}", &cb_first_20);
+// Parsed straight into a Decimal so every digit given is kept. An f64 would round them.
+fn parse_qty(s: &str) -> Result<Decimal, String> {
+    let trimmed = s.trim().trim_start_matches(['+', '-']).to_ascii_lowercase();
+    if matches!(trimmed.as_str(), "inf" | "infinity" | "nan") {
+        return Err("Value must be finite".into());
+    }
 
-    // let cb_ff = merged_asks.iter().cloned().take(20).collect::<Vec<OrderBook>>();
-    // println!("{:?}", cb_ff);
-    // let mut index: usize = 0;
-    // println!("PRINTING TOP 20 ASKS");
-    // for (idx, ob) in merged_asks.iter().enumerate() {
-    //     println!("idx : {:?} | Price : {:?} | Exchange : {}", idx, &ob.price, &ob.name);
+    let v = Decimal::from_str_exact(s)
+        .or_else(|_| Decimal::from_scientific(s))
+        .map_err(|e| format!("Not a valid quantity {}. Error : {}", s, e))?;
 
-    //     index+=1;
+    if v.is_sign_negative() && !v.is_zero() {
+        return Err("Value cannot be negative".into());
+    }
 
-    //     if index > 20 {
-    //         break;
-    //     }
+    if v.is_zero() {
+        return Err(ZERO_QTY.into());
+    }
 
-    // }
+    Ok(v)
+}
 
-    // index = 0;
-    // println!("PRINTING TOP 20 BIDS");
-    // for (idx, ob) in merged_bids.iter().enumerate() {
-    //     println!("idx : {:?} | Price : {:?} | Exchange: {}", idx, &ob.price, &ob.name);
+// --qty can be zero with --allow-zero, which clap can't see here. main rejects a zero without it.
+fn parse_qty_or_zero(s: &str) -> Result<Decimal, String> {
+    match parse_qty(s) {
+        Err(e) if e == ZERO_QTY => Ok(Decimal::ZERO),
+        result => result,
+    }
+}
 
-    //     index+=1;
+fn parse_currency(s: &str) -> Result<String, String> {
+    if (2..=10).contains(&s.len()) && s.chars().all(|c| c.is_ascii_alphanumeric()) {
+        Ok(s.to_ascii_uppercase())
+    } else {
+        Err(format!("Not a valid currency code {}. Expected e.g. USD", s))
+    }
+}
 
-    //     if index > 20 {
-    //         break;
-    //     }
+// Resolved exchange endpoints for the requested symbol
+#[derive(Debug, Clone)]
+struct Endpoints {
+    coinbase: String,
+    gemini: String,
+    binance: Option<String>,
+    bitstamp: Option<String>,
+    api_keys: ApiKeys,
+}
 
-    // }
+// Endpoint templates with a `{symbol}` placeholder, filled in per pair
+#[derive(Debug, Clone)]
+struct ApiTemplates {
+    coinbase: String,
+    gemini: String,
+    binance: Option<String>,
+    bitstamp: Option<String>,
+    api_keys: ApiKeys,
+}
 
-    // for i in [0..20] {
-        
-    //     println!("Merged Bids :: Index {} : {:?}", i, &merged_bids[i]);
-    // }
+impl ApiTemplates {
+    // Reads COINBASE_API, GEMINI_API and the optional BINANCE_API and BITSTAMP_API.
+    // --coinbase-url/--gemini-url take precedence over their variable, and an exchange read
+    // from a file with --coinbase-file/--gemini-file doesn't need either.
+    fn from_env(args: &Args) -> Result<Self> {
+        let template = |name: &str, url: &Option<String>, file: &Option<PathBuf>| match (url, file) {
+            (_, Some(_)) => Ok(String::new()),
+            (Some(url), None) => Ok(url.clone()),
+            (None, None) => api_template(name),
+        };
 
-    // for i in [0..20] {
-    //     println!("merged Asks :: Index  : {:?}", &coinbase_data.asks[i]);
-    // }
+        // An exchange left out of the build never needs its variable either
+        let template = |name: &str, exchange: Exchange, url: &Option<String>, file: &Option<PathBuf>| match compiled_in(exchange) {
+            true => template(name, url, file),
+            false => Ok(String::new()),
+        };
 
-    // for i in [0..20] {
-    //     println!("Gemini :: Index  : bid : {:?}", &coinbase_data.bids[i]);
-    // }
+        Ok(ApiTemplates {
+            coinbase: template("COINBASE_API", Exchange::Coinbase, &args.coinbase_url, &args.coinbase_file)?,
+            gemini: template("GEMINI_API", Exchange::Gemini, &args.gemini_url, &args.gemini_file)?,
+            // Binance is optional and only fetched when BINANCE_API is set
+            binance: env::var("BINANCE_API").ok(),
+            // Bitstamp too, with BITSTAMP_API
+            bitstamp: env::var("BITSTAMP_API").ok(),
+            api_keys: ApiKeys::from_env()?,
+        })
+    }
+}
 
-    // for i in [0..20] {
-    //     println!("Gemini :: Index  : asks : {:?}", &coinbase_data.asks[i]);
-    // }
+impl Endpoints {
+    // Fills the templates in with each exchange's form of `args.symbol`
+    fn for_symbol(templates: &ApiTemplates, args: &Args) -> Self {
+        let sym = args.symbol.as_str();
 
+        let ignores_symbol = |template: &str, file: &Option<PathBuf>| file.is_none() && !symbol::has_placeholder(template);
+        if sym != "BTC-USD" && (ignores_symbol(&templates.coinbase, &args.coinbase_file) || ignores_symbol(&templates.gemini, &args.gemini_file)) {
+            warn!("COINBASE_API/GEMINI_API have no {{symbol}} placeholder, so --symbol {} is ignored", sym);
+        }
 
+        Endpoints {
+            coinbase: symbol::endpoint(&templates.coinbase, &symbol::to_coinbase(sym)),
+            gemini: symbol::endpoint(&templates.gemini, &symbol::to_gemini(sym)),
+            binance: templates.binance.as_ref().map(|template| symbol::endpoint(template, &symbol::to_binance(sym))),
+            bitstamp: templates.bitstamp.as_ref().map(|template| symbol::endpoint(template, &symbol::to_bitstamp(sym))),
+            api_keys: templates.api_keys.clone(),
+        }
+    }
 
-    // Calculate prices 
-    let qty = Decimal::from_str_exact(&args.qty).unwrap();
-    let (buy_price, sell_price) = tokio::task::spawn_blocking(move || {
-        let buy = calculate_entity_price(&merged_asks, qty, true, "ASKS"); // asks = ascending
-        let sell = calculate_entity_price(&merged_bids, qty, false, "BIDS"); // bids = descending
-        (buy, sell)
-    })
-    .await?;
-
-    println!("--------------------------------");
-    info!("Buy Price : {:?}", buy_price);
-    info!("Sell Price : {:?}", sell_price);
-    let buy_val = buy_price.unwrap().to_string().parse::<f64>().unwrap();
-    let sell_val = sell_price.unwrap().to_string().parse::<f64>().unwrap();
-    
-    // Format with commas by converting to cents (integer), formatting, then adding decimal
-    let buy_cents = (buy_val * 100.0).round() as i64;
-    let sell_cents = (sell_val * 100.0).round() as i64;
-    
-    println!("To buy {} BTC: ${}.{:02}", args.qty, 
-        (buy_cents / 100).to_formatted_string(&Locale::en), 
-        buy_cents.abs() % 100);
-    println!("To sell {} BTC: ${}.{:02}", args.qty, 
-        (sell_cents / 100).to_formatted_string(&Locale::en), 
-        sell_cents.abs() % 100);
+    // Exchanges to fetch, in merge tie-break order
+    #[cfg_attr(not(feature = "gemini"), allow(unused_variables))]
+    fn exchanges(&self, args: &Args) -> Vec<Box<dyn ExchangeAdapter>> {
+        let mut exchanges: Vec<Box<dyn ExchangeAdapter>> = Vec::new();
+        #[cfg(feature = "coinbase")]
+        exchanges.push(Box::new(exchange::Coinbase { template: self.coinbase.clone() }));
+        #[cfg(feature = "gemini")]
+        exchanges.push(Box::new(exchange::Gemini { template: self.gemini.clone(), stale_secs: args.gemini_stale_secs }));
+        if let Some(template) = &self.binance {
+            exchanges.push(Box::new(exchange::Binance { template: template.clone() }));
+        }
+        if let Some(template) = &self.bitstamp {
+            exchanges.push(Box::new(exchange::Bitstamp { template: template.clone() }));
+        }
+        exchanges
+    }
+}
 
-    Ok(())
+// How much of the run succeeded, reported through the exit code.
+// A run with no usable exchange is an Err instead, which exits with 1.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+enum RunStatus {
+    // Every exchange was fetched live
+    #[default]
+    Complete,
+    // A quote was produced, but some exchange failed or was replaced by its cached book
+    Partial,
 }
 
+impl RunStatus {
+    fn exit_code(self) -> i32 {
+        match self {
+            RunStatus::Complete => 0,
+            RunStatus::Partial => 2,
+        }
+    }
+}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+// Why an exchange was left out of a run
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ExclusionReason {
+    // The request failed, timed out or missed the --deadline
+    NetworkError,
+    // The response was not valid JSON or not shaped like the exchange's book
+    ParseError,
+    // The book was older than --max-age
+    Stale,
+    // The best bid or ask was too far from the other exchanges', under --reject-outliers
+    Outlier,
+}
 
-    #[tokio::test]
-    async fn test_rate_limiter() {
-        let rate_limiter = Arc::new(RateLimiter::new_per_interval(Duration::from_secs(2)));
-        let start = Instant::now();
+impl fmt::Display for ExclusionReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ExclusionReason::NetworkError => write!(f, "network error"),
+            ExclusionReason::ParseError => write!(f, "parse error"),
+            ExclusionReason::Stale => write!(f, "stale book"),
+            ExclusionReason::Outlier => write!(f, "outlier"),
+        }
+    }
+}
 
-        // First call should succeed immediately
-        rate_limiter.acquire().await;
-        let first_call_time = start.elapsed();
-        assert!(first_call_time < Duration::from_millis(100), "First call should succeed quickly, took {:?}", first_call_time);
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Exclusion {
+    exchange: Exchange,
+    reason: ExclusionReason,
+    detail: String,
+}
 
-        // Second call should be rate limited and wait ~2 seconds
-        let before_second = Instant::now();
-        rate_limiter.acquire().await;
-        let second_call_wait = before_second.elapsed();
-        assert!(second_call_wait >= Duration::from_secs(2) - Duration::from_millis(50), "Second call should wait at least ~2 seconds, waited {:?}", second_call_wait);
-        assert!(second_call_wait < Duration::from_secs(3), "Second call should not wait too long, waited {:?}", second_call_wait);
+// Buy and sell totals for one --quantities entry
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct LadderRow {
+    quantity: Decimal,
+    buy: Option<Result<Decimal, PriceError>>,
+    sell: Option<Result<Decimal, PriceError>>,
+}
 
-        // Third call should also be rate limited
-        let before_third = Instant::now();
-        rate_limiter.acquire().await;
-        let third_call_wait = before_third.elapsed();
-        assert!(third_call_wait >= Duration::from_secs(2) - Duration::from_millis(50), "Third call should wait at least ~2 seconds, waited {:?}", third_call_wait);
+// Prices every quantity against the same merged sides, so the ladder needs no extra fetches.
+// Each side's running totals are built once and every rung is a binary search over them.
+fn price_ladder(asks: Option<&[OrderBook]>, bids: Option<&[OrderBook]>, quantities: &[Decimal]) -> Vec<LadderRow> {
+    if quantities.is_empty() {
+        return Vec::new();
+    }
+    let asks = asks.map(build_cumulative);
+    let bids = bids.map(build_cumulative);
+    quantities
+        .iter()
+        .map(|&quantity| LadderRow {
+            quantity,
+            buy: asks.as_ref().map(|asks| asks.cost(quantity)),
+            sell: bids.as_ref().map(|bids| bids.cost(quantity)),
+        })
+        .collect()
+}
 
-        // Total time for 3 calls should be at least 4 seconds (2s between each)
-        let total_time = start.elapsed();
-        assert!(total_time >= Duration::from_secs(4) - Duration::from_millis(100), "Total time for 3 calls should be at least ~4 seconds, took {:?}", total_time);
+// Everything `run` computes, left for `main` to print.
+// The merged sides are None when --side skipped them.
+#[derive(Debug, Default)]
+struct RunOutput {
+    quantity: Decimal,
+    asks: Option<Vec<OrderBook>>,
+    bids: Option<Vec<OrderBook>>,
+    top_of_book: Option<(Decimal, Decimal)>,
+    micro_price: Option<Decimal>,
+    buy_price: Option<Result<Decimal, PriceError>>,
+    sell_price: Option<Result<Decimal, PriceError>>,
+    buy_vwap: Option<Result<Decimal, PriceError>>,
+    sell_vwap: Option<Result<Decimal, PriceError>>,
+    buy_slippage: Option<Result<Decimal, PriceError>>,
+    sell_slippage: Option<Result<Decimal, PriceError>>,
+    buy_breakdown: Option<Result<FillBreakdown, PriceError>>,
+    sell_breakdown: Option<Result<FillBreakdown, PriceError>>,
+    // Percentage of the visible size on each side that the quantity takes
+    buy_book_share: Option<Decimal>,
+    sell_book_share: Option<Decimal>,
+    // --round-trip effective spread, None unless both sides were merged
+    round_trip: Option<Result<Decimal, PriceError>>,
+    // --imbalance bid to ask size ratio. None when the asks it covers hold no size
+    imbalance: Option<Decimal>,
+    // Size within --up-to-price on each side
+    ask_size_to_price: Option<Decimal>,
+    bid_size_to_price: Option<Decimal>,
+    // --limit-price orders as (filled, resting, notional)
+    buy_limit: Option<Result<(Decimal, Decimal, Decimal), PriceError>>,
+    sell_limit: Option<Result<(Decimal, Decimal, Decimal), PriceError>>,
+    // Set instead of the prices when pricing with --quote-amount
+    quote_amount: Option<Decimal>,
+    buy_quantity: Option<Result<Decimal, PriceError>>,
+    sell_quantity: Option<Result<Decimal, PriceError>>,
+    // One row per --quantities entry
+    ladder: Vec<LadderRow>,
+    // Exchanges whose failed fetch was replaced by a cached book
+    cache_hits: Vec<CacheHit>,
+    // Exchanges dropped from the run, and why
+    excluded: Vec<Exclusion>,
+    // Identifies the Coinbase snapshot that was priced, None if Coinbase failed
+    coinbase_sequence: Option<u64>,
+    coinbase_time: Option<String>,
+    status: RunStatus,
+}
+
+#[tokio::main]
+async fn main() -> Result<()>{
+    dotenv().ok();
+    let args = Args::parse();
+
+    // RUST_LOG works as it did with env_logger, e.g. RUST_LOG=info, and -v does the same without it
+    tracing_subscriber::fmt()
+        .with_env_filter(log_filter(args.verbose, env::var("RUST_LOG").ok().as_deref()))
+        .with_writer(std::io::stderr)
+        .init();
+    if Locale::from_name(&args.locale).is_err() {
+        warn!("Unknown locale {}, formatting numbers for en instead", args.locale);
     }
 
-    #[tokio::test]
-    async fn test_rate_limiter_2() {
-        let rate_limiter = Arc::new(RateLimiter::new_per_interval(Duration::from_secs(2)));
+    if args.qty.is_zero() && !args.allow_zero {
+        Args::command().error(clap::error::ErrorKind::ValueValidation, format!("invalid value '0' for '--qty <QTY>': {}", ZERO_QTY)).exit();
+    }
+    info!("Orderbook aggregator started");
 
-        // First call should succeed
-        assert!(rate_limiter.try_acquire().await.is_ok(), "First call should succeed");
+    if args.check {
+        check_config(&args, ApiTemplates::from_env(&args))?;
+        println!("config OK");
+        return Ok(());
+    }
 
-        // Second call should fail immediately (non-blocking)
-        assert!(rate_limiter.try_acquire().await.is_err(), "Second call should fail immediately due to rate limit");
+    if let Some(Command::ServeMock { port, coinbase_book, gemini_book }) = &args.command {
+        let addr = mock_server::serve(*port, mock_server::MockBooks::load(coinbase_book, gemini_book)?).await?;
+        println!("Mock exchanges on http://{addr}/coinbase and http://{addr}/gemini, Ctrl-C to stop");
+        tokio::signal::ctrl_c().await?;
+        return Ok(());
+    }
 
-        // After waiting 2 seconds, should succeed again
-        tokio::time::sleep(Duration::from_secs(2)).await;
-        assert!(rate_limiter.try_acquire().await.is_ok(), "Call after 2 seconds should succeed");
+    if let Some(port) = args.metrics_port {
+        metrics::serve(port).await?;
+    }
+
+    if args.stream {
+        return run_stream(&args).await;
+    }
+
+    let templates = ApiTemplates::from_env(&args)?;
+
+    // Create a client to fetch the data from the APIs
+    let client = api_client::create_client(Duration::from_secs(args.timeout_secs), args.headers.iter().cloned().collect());
+
+    // Created once so repeated runs share the same request budget
+    let rate_limiter = network_rate_limiter(&args, &templates)?;
+
+    if args.command == Some(Command::Selftest) {
+        let probes = selftest(&args, &Endpoints::for_symbol(&templates, &args), &client).await;
+        for probe in &probes {
+            println!("{}", probe);
+        }
+        let failed = probes.iter().filter(|probe| probe.result.is_err()).count();
+        if failed > 0 {
+            return Err(anyhow::anyhow!("{} of {} exchanges did not answer", failed, probes.len()));
+        }
+        return Ok(());
+    }
+
+    if args.symbols.is_some() {
+        let results = run_symbols(&args, &templates, &client, rate_limiter.as_ref()).await;
+        let priced = results.iter().filter(|(_, result)| result.is_ok()).count();
+        let mut status = if priced < results.len() { RunStatus::Partial } else { RunStatus::Complete };
+        let mut rendered = String::new();
+        for (symbol_args, result) in results {
+            let output = match result {
+                Ok(output) => output,
+                Err(e) => {
+                    warn!("Failed to price {}: {}", symbol_args.symbol, e);
+                    continue;
+                }
+            };
+            if output.status == RunStatus::Partial {
+                status = RunStatus::Partial;
+            }
+            if args.format == OutputFormat::Text {
+                writeln!(rendered, "===== {} =====", symbol_args.symbol)?;
+            }
+            rendered.push_str(&render(&symbol_args, &output)?);
+        }
+        if priced == 0 {
+            return Err(anyhow::anyhow!("Failed to price any of the symbols"));
+        }
+        print!("{}", rendered);
+        write_output_file(&args, &rendered)?;
+        exit_with(status);
+        return Ok(());
+    }
+
+    let endpoints = Endpoints::for_symbol(&templates, &args);
+
+    if let Some(secs) = args.interval {
+        return poll(&args, &endpoints, &client, rate_limiter.as_ref(), Duration::from_secs(secs), &ctrl_c_token(), |output| {
+            write_cycle(&mut std::io::stdout().lock(), &args, output)?;
+            write_csv(&args, output)
+        })
+        .await;
+    }
+
+    // Read before the run so a bad file fails fast
+    let previous = args.compare.as_deref().map(|path| load_quote(path).map(|quote| (path, quote))).transpose()?;
+
+    let output = run(&args, &endpoints, &client, rate_limiter.as_ref()).await?;
+    write_csv(&args, &output)?;
+
+    let mut rendered = render(&args, &output)?;
+    if let Some((path, previous)) = previous.filter(|_| args.format == OutputFormat::Text) {
+        if previous.symbol != args.symbol {
+            warn!("{} holds a {} quote, not {}", path.display(), previous.symbol, args.symbol);
+        }
+        write_changes(&mut rendered, &args, path, &QuoteChange::between(&previous, &json_quote(&args, &output)))?;
+    }
+    print!("{}", rendered);
+    write_output_file(&args, &rendered)?;
+    exit_with(output.status);
+    Ok(())
+}
+
+// Exits with 2 after a partial run. A complete run returns so main can finish normally with 0.
+fn exit_with(status: RunStatus) {
+    if status != RunStatus::Complete {
+        warn!("Not every exchange was used, exiting with {}", status.exit_code());
+        std::process::exit(status.exit_code());
+    }
+}
+
+// One selftest request
+#[derive(Debug)]
+struct Probe {
+    exchange: Exchange,
+    latency: Duration,
+    // The error message when the exchange didn't answer with a JSON book
+    result: Result<(), String>,
+}
+
+impl fmt::Display for Probe {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.result {
+            Ok(()) => write!(f, "{} | ok | {} ms", self.exchange, self.latency.as_millis()),
+            Err(e) => write!(f, "{} | failed: {} | {} ms", self.exchange, e, self.latency.as_millis()),
+        }
     }
-}
\ No newline at end of file
+}
+
+// Pings every exchange at once, skipping the ones read from a file.
+// The requests skip the rate limiter since each exchange only gets one.
+async fn selftest(args: &Args, endpoints: &Endpoints, client: &Client) -> Vec<Probe> {
+    let exchanges: Vec<_> = endpoints.exchanges(args).into_iter().filter(|exchange| book_file(args, exchange.name()).is_none()).collect();
+    join_all(exchanges.iter().map(|exchange| async move {
+        let url = exchange.endpoint(&args.symbol);
+        let started = std::time::Instant::now();
+        let result = get_data(client, &url, &endpoints.api_keys.headers(exchange.name())).await;
+        Probe { exchange: exchange.name(), latency: started.elapsed(), result: result.map(|_| ()).map_err(|e| e.to_string()) }
+    }))
+    .await
+}
+
+// Prices every --symbols pair concurrently, in the order given.
+// All requests share the one rate limiter, so the exchanges see the same request rate as a single pair.
+async fn run_symbols(args: &Args, templates: &ApiTemplates, client: &Client, rate_limiter: Option<&RateLimiter>) -> Vec<(Args, Result<RunOutput>)> {
+    let pairs: Vec<(Args, Endpoints)> = args.symbols.iter().flatten()
+        .map(|sym| {
+            let symbol_args = Args { symbol: sym.clone(), ..args.clone() };
+            let endpoints = Endpoints::for_symbol(templates, &symbol_args);
+            (symbol_args, endpoints)
+        })
+        .collect();
+
+    // The permits cap the requests across every symbol, on top of the rate limiter's pacing
+    let permits = args.max_concurrency.map(|n| Semaphore::new(n as usize));
+    let results = join_all(pairs.iter().map(|(symbol_args, endpoints)| async {
+        run_exchanges(symbol_args, &endpoints.exchanges(symbol_args), &endpoints.api_keys, client, rate_limiter, permits.as_ref(), None).await
+    }))
+    .await;
+    pairs.into_iter().map(|(symbol_args, _)| symbol_args).zip(results).collect()
+}
+
+// --check: everything a run needs up front, short of making a request.
+// The symbols and the numbers clap can check are already validated by the time this runs.
+fn check_config(args: &Args, templates: Result<ApiTemplates>) -> Result<()> {
+    create_rate_limiter(args)?;
+
+    for file in [&args.coinbase_file, &args.gemini_file].into_iter().flatten() {
+        if !file.is_file() {
+            return Err(anyhow::anyhow!("{} does not exist", file.display()));
+        }
+    }
+
+    // The stream only talks to the fixed WebSocket endpoints
+    if args.stream {
+        return Ok(());
+    }
+
+    let templates = templates?;
+    let symbols = args.symbols.clone().unwrap_or_else(|| vec![args.symbol.clone()]);
+    for sym in symbols {
+        let endpoints = Endpoints::for_symbol(&templates, &Args { symbol: sym, ..args.clone() });
+        let urls = [
+            (Exchange::Coinbase, Some(&endpoints.coinbase)),
+            (Exchange::Gemini, Some(&endpoints.gemini)),
+            (Exchange::Binance, endpoints.binance.as_ref()),
+            (Exchange::Bitstamp, endpoints.bitstamp.as_ref()),
+        ];
+        for (exchange, url) in urls {
+            // Books read from a file have no URL, and exchanges left out of the build are never fetched
+            let Some(url) = url.filter(|_| compiled_in(exchange) && book_file(args, exchange).is_none()) else { continue };
+            let parsed = reqwest::Url::parse(url).with_context(|| format!("{} URL {} is not valid", exchange, url))?;
+            if !matches!(parsed.scheme(), "http" | "https") {
+                return Err(anyhow::anyhow!("{} URL {} must be http or https", exchange, url));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// One request every 2 seconds, optionally allowing a burst up front
+fn create_rate_limiter(args: &Args) -> Result<RateLimiter> {
+    Ok(match (args.burst, args.jitter) {
+        (burst, Some(jitter)) => RateLimiter::new_with_jitter(burst.unwrap_or(1), Duration::from_secs(2), jitter)?,
+        (Some(capacity), None) => RateLimiter::new_with_burst(capacity, Duration::from_secs(2))?,
+        (None, None) => RateLimiter::new_per_interval(Duration::from_secs(2))?,
+    })
+}
+
+// The limiter for a run's requests. None with --no-rate-limit, or when every book comes from a file
+// and there is nothing to send.
+fn network_rate_limiter(args: &Args, templates: &ApiTemplates) -> Result<Option<RateLimiter>> {
+    let fetches = Endpoints::for_symbol(templates, args).exchanges(args).iter().any(|exchange| book_file(args, exchange.name()).is_none());
+    if args.no_rate_limit || !fetches {
+        return Ok(None);
+    }
+    create_rate_limiter(args).map(Some)
+}
+
+fn parse_percent(s: &str) -> Result<Decimal, String> {
+    let pct = Decimal::from_str_exact(s).map_err(|e| format!("Not a valid percentage {}. Error : {}", s, e))?;
+    if pct <= Decimal::ZERO {
+        return Err("Percentage must be greater than zero".into());
+    }
+    Ok(pct)
+}
+
+// --locale, or en when it's not a locale num_format knows. main warns about that once up front,
+// so a typo costs the formatting and not the quote.
+fn number_locale(args: &Args) -> Locale {
+    Locale::from_name(&args.locale).unwrap_or(Locale::en)
+}
+
+// Warnings only, unless RUST_LOG says otherwise. Each -v raises the overall level, over RUST_LOG's,
+// while its per target directives such as reqwest=trace still apply.
+fn log_filter(verbose: u8, rust_log: Option<&str>) -> EnvFilter {
+    let level = match verbose {
+        0 => LevelFilter::WARN,
+        1 => LevelFilter::INFO,
+        2 => LevelFilter::DEBUG,
+        _ => LevelFilter::TRACE,
+    };
+    let filter = EnvFilter::builder().with_default_directive(level.into()).parse_lossy(rust_log.unwrap_or_default());
+    if verbose == 0 {
+        return filter;
+    }
+    filter.add_directive(level.into())
+}
+
+fn parse_tick(s: &str) -> Result<Decimal, String> {
+    let tick = Decimal::from_str_exact(s).map_err(|e| format!("Not a valid tick size {}. Error : {}", s, e))?;
+    if tick <= Decimal::ZERO {
+        return Err("Tick size must be greater than zero".into());
+    }
+    Ok(tick)
+}
+
+// A fee can be zero but never negative, and at most the whole notional
+fn parse_fee_bps(s: &str) -> Result<Decimal, String> {
+    let bps = Decimal::from_str_exact(s).map_err(|e| format!("Not a valid fee {}. Error : {}", s, e))?;
+    if bps.is_sign_negative() && !bps.is_zero() {
+        return Err("Fee cannot be negative".into());
+    }
+    if bps > Decimal::from(10_000) {
+        return Err("Fee cannot be over 10000 bps".into());
+    }
+    Ok(bps)
+}
+
+fn parse_jitter(s: &str) -> Result<f64, String> {
+    let fraction: f64 = s.parse().map_err(|e| format!("Not a valid fraction {}. Error : {}", s, e))?;
+    if !(0.0..=1.0).contains(&fraction) {
+        return Err("Jitter must be between 0 and 1".into());
+    }
+    Ok(fraction)
+}
+
+// Runs the whole cycle every `interval` and hands each result to `on_output` until `shutdown` completes.
+// A failed cycle is logged and the loop carries on with the next one.
+async fn poll(
+    args: &Args,
+    endpoints: &Endpoints,
+    client: &Client,
+    rate_limiter: Option<&RateLimiter>,
+    interval: Duration,
+    shutdown: &CancellationToken,
+    mut on_output: impl FnMut(&RunOutput) -> Result<()>,
+) -> Result<()> {
+    let mut ticker = tokio::time::interval(interval);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    loop {
+        tokio::select! {
+            _ = shutdown.cancelled() => break,
+            _ = ticker.tick() => {}
+        }
+        let result = run_exchanges(args, &endpoints.exchanges(args), &endpoints.api_keys, client, rate_limiter, None, Some(shutdown)).await;
+        // A cycle cut short by the shutdown is neither reported nor logged as failed
+        if shutdown.is_cancelled() {
+            break;
+        }
+        match result {
+            Ok(output) => on_output(&output)?,
+            Err(e) => warn!("Polling cycle failed: {}", e),
+        }
+    }
+    info!("Stopping the polling loop");
+    Ok(())
+}
+
+// Cancelled on the first Ctrl-C
+fn ctrl_c_token() -> CancellationToken {
+    let token = CancellationToken::new();
+    let cancel = token.clone();
+    tokio::spawn(async move {
+        let _ = tokio::signal::ctrl_c().await;
+        cancel.cancel();
+    });
+    token
+}
+
+// Prints one polling cycle and flushes it, so a consumer tailing the output gets each cycle as it finishes
+fn write_cycle(out: &mut impl std::io::Write, args: &Args, output: &RunOutput) -> Result<()> {
+    match args.format {
+        OutputFormat::Json | OutputFormat::Jsonl => out.write_all(render(args, output)?.as_bytes())?,
+        OutputFormat::Text => {
+            let quote = Quote::new(args, output.buy_price.clone(), output.sell_price.clone());
+            writeln!(out, "{} | {}", humantime::format_rfc3339_seconds(SystemTime::now()), quote)?;
+        }
+    }
+    out.flush()?;
+    Ok(())
+}
+
+fn write_csv(args: &Args, output: &RunOutput) -> Result<()> {
+    if let Some(path) = &args.csv {
+        export::write_csv(path, output.asks.as_deref().unwrap_or_default(), output.bids.as_deref().unwrap_or_default())
+            .map_err(|e| anyhow::anyhow!("Failed to write {}: {}", path.display(), e))?;
+        info!("Merged book written to {}", path.display());
+    }
+    Ok(())
+}
+
+// Fetches every configured exchange, merges the books and prices the requested quantity
+async fn run(args: &Args, endpoints: &Endpoints, client: &Client, rate_limiter: Option<&RateLimiter>) -> Result<RunOutput> {
+    run_exchanges(args, &endpoints.exchanges(args), &endpoints.api_keys, client, rate_limiter, None, None).await
+}
+
+// Exchange field on the log spans, e.g. fetch{exchange=coinbase}
+fn span_label(exchange: Exchange) -> String {
+    exchange.to_string().to_lowercase()
+}
+
+// Local book file that replaces the request to `exchange`, if one was given
+fn book_file(args: &Args, exchange: Exchange) -> Option<&Path> {
+    match exchange {
+        Exchange::Coinbase => args.coinbase_file.as_deref(),
+        Exchange::Gemini => args.gemini_file.as_deref(),
+        Exchange::Binance | Exchange::Bitstamp => None,
+    }
+}
+
+// The pipeline behind run, for any set of exchanges.
+// Requests wait for one of `permits`, when given, before going out.
+// Cancelling `shutdown` drops the fetches still in flight and fails the run straight away.
+async fn run_exchanges(
+    args: &Args,
+    exchanges: &[Box<dyn ExchangeAdapter>],
+    api_keys: &ApiKeys,
+    client: &Client,
+    rate_limiter: Option<&RateLimiter>,
+    permits: Option<&Semaphore>,
+    shutdown: Option<&CancellationToken>,
+) -> Result<RunOutput> {
+    info!("Fetching the Data from the exchanges");
+
+    // Fetch the entire dataset from the APIs
+    // Each fetch runs in a span named after its exchange so the interleaved logs can be told apart.
+    // With --deadline an exchange still fetching when it passes counts as failed, so the faster ones are priced alone.
+    let deadline = args.deadline.map(|secs| tokio::time::Instant::now() + Duration::from_secs(secs));
+    let results = join_all(exchanges.iter().map(|exchange| {
+        let span = info_span!("fetch", exchange = %span_label(exchange.name()));
+        async move {
+            let url = exchange.endpoint(&args.symbol);
+            debug!("{} endpoint: {}", exchange.name(), url);
+            let headers = api_keys.headers(exchange.name());
+            let file = book_file(args, exchange.name());
+            let fetch = async {
+                let _permit = match permits {
+                    Some(permits) if file.is_none() => Some(permits.acquire().await?),
+                    _ => None,
+                };
+                fetch_or_read(args, client, &url, &headers, file, rate_limiter).await
+            };
+            let fetch = async {
+                match shutdown {
+                    Some(shutdown) => tokio::select! {
+                        _ = shutdown.cancelled() => Err(anyhow::anyhow!("Fetching from {} was cancelled", exchange.name())),
+                        result = fetch => result,
+                    },
+                    None => fetch.await,
+                }
+            };
+            match deadline {
+                Some(deadline) => tokio::time::timeout_at(deadline, fetch).await.unwrap_or_else(|_| {
+                    warn!("No answer from {} within the {}s --deadline", exchange.name(), args.deadline.unwrap_or_default());
+                    Err(anyhow::anyhow!("{} missed the deadline", exchange.name()))
+                }),
+                None => fetch.await,
+            }
+        }
+        .instrument(span)
+    }))
+    .await;
+    if shutdown.is_some_and(CancellationToken::is_cancelled) {
+        return Err(anyhow::anyhow!("Run cancelled"));
+    }
+
+    // Recording is best effort and never changes what gets priced
+    if let Some(dir) = &args.record {
+        for (exchange, result) in exchanges.iter().zip(&results) {
+            let Ok(data) = result else { continue };
+            if book_file(args, exchange.name()).is_some() {
+                continue;
+            }
+            match export::record_response(dir, exchange.name(), &args.symbol, data) {
+                Ok(path) => info!("Recorded the {} response to {}", exchange.name(), path.display()),
+                Err(e) => warn!("Failed to record the {} response in {}: {}", exchange.name(), dir.display(), e),
+            }
+        }
+    }
+
+    // Fall back to the last good book of any exchange that failed, when caching is on.
+    // A fresh response only replaces the cached one after it passed --max-age and parsed.
+    let cache = args.cache_dir.clone().map(|dir| BookCache::new(dir, Duration::from_secs(args.cache_stale_secs)));
+    let mut cache_hits = Vec::new();
+    let mut cached = |exchange: Exchange, result: Result<Value>| match &cache {
+        Some(cache) => {
+            let (result, hit) = cache.with_fallback(exchange, &args.symbol, result);
+            cache_hits.extend(hit);
+            result
+        }
+        None => result,
+    };
+
+    // With --max-age a frozen feed is dropped like a failed fetch, so the other exchanges are quoted alone.
+    // Exchanges that don't timestamp their books are let through.
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default();
+    let staleness = |exchange: Exchange, newest: Option<u64>| match (args.max_age, newest) {
+        (Some(max_age), Some(newest)) if now.saturating_sub(newest) > max_age => {
+            let detail = format!("its newest data is {}s old, over --max-age {}", now.saturating_sub(newest), max_age);
+            warn!("Dropping the {} book: {}", exchange, detail);
+            Some(detail)
+        }
+        _ => None,
+    };
+
+    // Parse the data from the APIs.
+    // Fetch and parse failures are logged and leave that exchange out so the others can still be used.
+    let mut coinbase_snapshot = None;
+    let mut books: Vec<(Exchange, NormalizedBook)> = Vec::new();
+    let mut excluded = Vec::new();
+    for (exchange, result) in exchanges.iter().zip(results) {
+        let name = exchange.name();
+        let _span = info_span!("parse", exchange = %span_label(name)).entered();
+        let fetched = result.is_ok();
+        let data = match cached(name, result) {
+            Ok(data) => data,
+            Err(e) => {
+                debug!("Error : {:?}", e);
+                let reason = if is_parse_error(&e) { ExclusionReason::ParseError } else { ExclusionReason::NetworkError };
+                excluded.push(Exclusion { exchange: name, reason, detail: e.to_string() });
+                continue;
+            }
+        };
+        if let Some(detail) = staleness(name, exchange.last_updated(&data)) {
+            excluded.push(Exclusion { exchange: name, reason: ExclusionReason::Stale, detail });
+            continue;
+        }
+        let snapshot = exchange.snapshot(&data);
+        let to_cache = (fetched && cache.is_some()).then(|| data.clone());
+        match exchange.parse(data, args.strict_precision) {
+            Ok((asks, bids)) => {
+                info!("{} bids: {}, asks: {}", name, bids.len(), asks.len());
+                books.push((name, NormalizedBook { asks, bids }));
+                if name == Exchange::Coinbase {
+                    coinbase_snapshot = snapshot;
+                }
+                if let (Some(cache), Some(data)) = (&cache, to_cache)
+                    && let Err(e) = cache.store(name, &args.symbol, &data)
+                {
+                    warn!("Failed to cache the {} book: {}", name, e);
+                }
+            }
+            Err(e) => {
+                info!("Error fetching {} data! Error: {:?}", name, e);
+                excluded.push(Exclusion { exchange: name, reason: ExclusionReason::ParseError, detail: e.to_string() });
+            }
+        }
+    }
+
+    // Best asks and best bids are compared separately, and a book off on either side counts
+    let pct = args.reject_outliers.unwrap_or(OUTLIER_WARN_PCT);
+    let best_prices = |is_ascending: bool| -> Vec<(Exchange, Decimal)> {
+        books
+            .iter()
+            .filter_map(|(exchange, book)| {
+                let side = if is_ascending { &book.asks } else { &book.bids };
+                best_level(std::slice::from_ref(side), is_ascending).map(|level| (*exchange, level.price))
+            })
+            .collect()
+    };
+    // The median of two prices is their midpoint, which can't tell which one is off.
+    // So a book is only droppable when it was flagged on a side that at least three books priced.
+    let mut outliers = Vec::new();
+    for is_ascending in [true, false] {
+        let prices = best_prices(is_ascending);
+        let droppable = prices.len() >= 3;
+        outliers.extend(detect_outlier(&prices, pct).into_iter().map(|exchange| (exchange, droppable)));
+    }
+    // Droppable first, so an exchange flagged on both sides keeps that
+    outliers.sort_by(|a, b| a.0.cmp(&b.0).then(b.1.cmp(&a.1)));
+    outliers.dedup_by_key(|(exchange, _)| *exchange);
+    // Dropping every book would only turn a disagreement into a failed run
+    let dropping = outliers.iter().filter(|(_, droppable)| *droppable).count();
+    let reject = args.reject_outliers.is_some() && dropping < books.len();
+    for (exchange, droppable) in outliers {
+        let detail = format!("its best price is more than {}% from the median of the exchanges", pct);
+        if !(reject && droppable) {
+            warn!("The {} book looks off: {}", exchange, detail);
+            continue;
+        }
+        warn!("Dropping the {} book: {}", exchange, detail);
+        books.retain(|(name, _)| *name != exchange);
+        excluded.push(Exclusion { exchange, reason: ExclusionReason::Outlier, detail });
+    }
+
+    // If all are missing, return an error. Quitting..
+    if books.is_empty() {
+        return Err(anyhow::anyhow!("Failed to fetch data from all exchanges. Quitting..!"));
+    }
+    // Quoting off fewer sources than expected could pass one exchange's bad book off as the market
+    if books.len() < args.min_exchanges as usize {
+        return Err(anyhow::anyhow!(
+            "Only {} of {} exchanges returned a usable book, --min-exchanges is {}",
+            books.len(),
+            exchanges.len(),
+            args.min_exchanges
+        ));
+    }
+    // Only a Coinbase book that is actually priced has a snapshot to report
+    let coinbase_used = books.iter().any(|(name, _)| *name == Exchange::Coinbase);
+    let (coinbase_sequence, coinbase_time) = coinbase_snapshot.filter(|_| coinbase_used).unzip();
+    let status = if books.len() < exchanges.len() || !cache_hits.is_empty() { RunStatus::Partial } else { RunStatus::Complete };
+
+    info!("Loaded the data successfully from the exchanges");
+    info!("--------------------------------");
+
+    if args.validate {
+        for (exchange, book) in &books {
+            validate_book(&book.asks, true)
+                .map_err(|e| anyhow::anyhow!("{} asks failed validation: {}", exchange, e))?;
+            validate_book(&book.bids, false)
+                .map_err(|e| anyhow::anyhow!("{} bids failed validation: {}", exchange, e))?;
+        }
+        info!("All exchange books are sorted");
+    }
+
+    // --top only needs the best level of each side, so the books are never merged
+    if args.top {
+        let (ask_books, bid_books) = split_sides(books.into_iter().map(|(_, book)| book).collect());
+        let asks: Vec<OrderBook> = best_level(&ask_books, true).into_iter().collect();
+        let bids: Vec<OrderBook> = best_level(&bid_books, false).into_iter().collect();
+        return Ok(RunOutput {
+            quantity: args.qty,
+            top_of_book: spread(&asks, &bids),
+            micro_price: micro_price(&asks, &bids),
+            asks: Some(asks),
+            bids: Some(bids),
+            cache_hits,
+            excluded,
+            coinbase_sequence,
+            coinbase_time,
+            status,
+            ..Default::default()
+        });
+    }
+
+    info!("Merging {:?} side(s)", args.side);
+
+    // Merge orderbooks 
+    let merge_options = MergeOptions::from(args);
+    let started = std::time::Instant::now();
+    let (merged_asks, merged_bids) = merge_books_parallel(merge_options, books.into_iter().map(|(_, book)| book).collect())
+        .instrument(info_span!("merge"))
+        .await?;
+    metrics::observe_merge(started.elapsed(), merged_asks.as_ref().map(Vec::len), merged_bids.as_ref().map(Vec::len));
+
+    if let Some(asks) = &merged_asks {
+        info!("Asks merged successfully! Total: {}", asks.len());
+    }
+    if let Some(bids) = &merged_bids {
+        info!("Bids merged successfully! Total: {}", bids.len());
+    }
+
+    let (asks, bids) = (merged_asks.as_deref().unwrap_or_default(), merged_bids.as_deref().unwrap_or_default());
+    if is_crossed(asks, bids) {
+        let message = format!("The merged book is crossed: best bid {} >= best ask {}", bids[0].price, asks[0].price);
+        if args.strict {
+            return Err(anyhow::anyhow!(message));
+        }
+        warn!("{}. The fill prices may be off", message);
+    }
+
+    let top_of_book = spread(merged_asks.as_deref().unwrap_or_default(), merged_bids.as_deref().unwrap_or_default());
+    let micro_price = micro_price(merged_asks.as_deref().unwrap_or_default(), merged_bids.as_deref().unwrap_or_default());
+    let imbalance = args.imbalance.and_then(|depth| imbalance(merged_asks.as_deref()?, merged_bids.as_deref()?, depth));
+
+    // Calculate prices 
+    let qty = args.qty;
+    let quote_amount = args.quote_amount;
+    let by_qty = quote_amount.is_none();
+    let show_vwap = args.vwap && by_qty;
+    let show_slippage = args.show_slippage && by_qty;
+    let show_breakdown = args.breakdown && by_qty;
+    let up_to_price = args.up_to_price;
+    let limit_price = args.limit_price;
+    let quantities = args.quantities.clone().unwrap_or_default();
+    let round_trip_qty = args.round_trip;
+    let span = info_span!("pricing");
+    tokio::task::spawn_blocking(move || {
+        let _span = span.enter();
+        let buy_price = merged_asks.as_deref().filter(|_| by_qty).map(|asks| calculate_entity_price(asks, qty, true, "ASKS")); // asks = ascending
+        let sell_price = merged_bids.as_deref().filter(|_| by_qty).map(|bids| calculate_entity_price(bids, qty, false, "BIDS")); // bids = descending
+        let buy_book_share = merged_asks.as_deref().filter(|_| by_qty).and_then(|asks| book_share(asks, qty));
+        let sell_book_share = merged_bids.as_deref().filter(|_| by_qty).and_then(|bids| book_share(bids, qty));
+        let buy_vwap = merged_asks.as_deref().filter(|_| show_vwap).map(|asks| calculate_vwap(asks, qty, true));
+        let sell_vwap = merged_bids.as_deref().filter(|_| show_vwap).map(|bids| calculate_vwap(bids, qty, false));
+        let buy_slippage = merged_asks.as_deref().filter(|_| show_slippage).map(|asks| slippage(asks, qty, true));
+        let sell_slippage = merged_bids.as_deref().filter(|_| show_slippage).map(|bids| slippage(bids, qty, false));
+        let buy_breakdown = merged_asks.as_deref().filter(|_| show_breakdown).map(|asks| calculate_fill_breakdown(asks, qty, true));
+        let sell_breakdown = merged_bids.as_deref().filter(|_| show_breakdown).map(|bids| calculate_fill_breakdown(bids, qty, false));
+        let ask_size_to_price = up_to_price.zip(merged_asks.as_deref()).map(|(limit, asks)| depth_to_price(asks, limit, true));
+        let bid_size_to_price = up_to_price.zip(merged_bids.as_deref()).map(|(limit, bids)| depth_to_price(bids, limit, false));
+        let buy_limit = limit_price.zip(merged_asks.as_deref()).map(|(limit, asks)| simulate_limit(asks, limit, qty, true));
+        let sell_limit = limit_price.zip(merged_bids.as_deref()).map(|(limit, bids)| simulate_limit(bids, limit, qty, false));
+        let buy_quantity = quote_amount.zip(merged_asks.as_deref()).map(|(amount, asks)| fill_by_quote(asks, amount, true));
+        let sell_quantity = quote_amount.zip(merged_bids.as_deref()).map(|(amount, bids)| fill_by_quote(bids, amount, false));
+        let ladder = price_ladder(merged_asks.as_deref(), merged_bids.as_deref(), &quantities);
+        let round_trip = round_trip_qty
+            .zip(merged_asks.as_deref().zip(merged_bids.as_deref()))
+            .map(|(qty, (asks, bids))| effective_spread(asks, bids, qty));
+
+        info!("Buy Price : {:?}", buy_price);
+        info!("Sell Price : {:?}", sell_price);
+
+        Ok(RunOutput {
+            quantity: qty,
+            asks: merged_asks,
+            bids: merged_bids,
+            top_of_book,
+            micro_price,
+            buy_price,
+            sell_price,
+            buy_vwap,
+            sell_vwap,
+            buy_slippage,
+            sell_slippage,
+            buy_breakdown,
+            sell_breakdown,
+            buy_book_share,
+            sell_book_share,
+            round_trip,
+            imbalance,
+            ask_size_to_price,
+            bid_size_to_price,
+            buy_limit,
+            sell_limit,
+            quote_amount,
+            buy_quantity,
+            sell_quantity,
+            ladder,
+            cache_hits,
+            excluded,
+            coinbase_sequence,
+            coinbase_time,
+            status,
+        })
+    })
+    .await?
+}
+
+// Streams Coinbase and Gemini level2 books and reprices the quantity on every change.
+// Runs until the process is stopped.
+async fn run_stream(args: &Args) -> Result<()> {
+    let coinbase_url = env::var("COINBASE_WS").unwrap_or_else(|_| stream::COINBASE_WS_URL.to_string());
+    let gemini_url = env::var("GEMINI_WS").unwrap_or_else(|_| stream::GEMINI_WS_URL.to_string());
+
+    let (updates, mut events) = tokio::sync::mpsc::channel(STREAM_BUFFER);
+    tokio::spawn(
+        stream::follow(Exchange::Coinbase, coinbase_url, symbol::to_coinbase(&args.symbol), args.strict_precision, updates.clone())
+            .instrument(info_span!("stream", exchange = %span_label(Exchange::Coinbase))),
+    );
+    tokio::spawn(
+        stream::follow(Exchange::Gemini, gemini_url, symbol::to_gemini(&args.symbol), args.strict_precision, updates)
+            .instrument(info_span!("stream", exchange = %span_label(Exchange::Gemini))),
+    );
+
+    let qty = args.qty;
+    let merge_options = MergeOptions::from(args);
+    let mut live_merge = LiveMerge::new(merge_options);
+    let mut books = [LiveBook::new(Exchange::Coinbase), LiveBook::new(Exchange::Gemini)];
+    let mut last_quote = None;
+
+    loop {
+        let Some(first) = events.recv().await else {
+            anyhow::bail!("every exchange stream stopped");
+        };
+
+        // Everything already queued is priced as one tick
+        let mut next = Some(first);
+        while let Some((source, event)) = next {
+            if let Some(live_merge) = live_merge.as_mut() {
+                live_merge.apply(source, &event);
+            }
+            if let Some(book) = books.iter_mut().find(|book| book.source() == source) {
+                book.apply(event);
+            }
+            next = events.try_recv().ok();
+        }
+
+        if books.iter().all(LiveBook::is_empty) {
+            continue;
+        }
+
+        let quote = match live_merge.as_ref() {
+            Some(live_merge) => live_merge.quote(qty),
+            None => {
+                let books: Vec<NormalizedBook> = books.iter().filter(|book| !book.is_empty()).map(LiveBook::to_book).collect();
+                let (asks, bids) = merge_books(merge_options, books);
+                let buy = asks.as_deref().map(|asks| calculate_entity_price(asks, qty, true, "ASKS"));
+                let sell = bids.as_deref().map(|bids| calculate_entity_price(bids, qty, false, "BIDS"));
+                (buy, sell)
+            }
+        };
+
+        // Only print when the quote actually moved
+        if last_quote.as_ref() == Some(&quote) {
+            continue;
+        }
+        println!("{}", Quote::new(args, quote.0.clone(), quote.1.clone()));
+        last_quote = Some(quote);
+    }
+}
+
+// Reads a captured response from `file` when given, otherwise fetches `url` through the rate limiter
+async fn fetch_or_read(
+    args: &Args,
+    client: &Client,
+    url: &str,
+    headers: &HeaderMap,
+    file: Option<&Path>,
+    rate_limiter: Option<&RateLimiter>,
+) -> Result<Value> {
+    match (file, rate_limiter) {
+        (Some(path), _) => {
+            let text = std::fs::read_to_string(path).with_context(|| format!("Failed to read {}", path.display()))?;
+            Ok(serde_json::from_str(&text)?)
+        }
+        (None, Some(rate_limiter)) => get_data_rate_limited(client, url, headers, rate_limiter, args.adaptive_rate_limit, args.retries).await,
+        (None, None) => get_data_with_retries(client, url, headers, args.retries).await,
+    }
+}
+
+// Buy and sell prices for one quantity, displayed as the one line summary
+// `1 BTC | buy: $100.75 | sell: $99.25`. A side is None when it was not priced.
+struct Quote {
+    quantity: Decimal,
+    base: String,
+    currency: String,
+    price_dp: u32,
+    rounding: Rounding,
+    locale: Locale,
+    buy: Option<Result<Decimal, PriceError>>,
+    sell: Option<Result<Decimal, PriceError>>,
+}
+
+impl Quote {
+    fn new(args: &Args, buy: Option<Result<Decimal, PriceError>>, sell: Option<Result<Decimal, PriceError>>) -> Self {
+        Quote {
+            quantity: args.qty,
+            base: symbol::base_asset(&args.symbol).to_string(),
+            currency: currency_symbol(args),
+            price_dp: args.price_dp,
+            rounding: args.rounding,
+            locale: number_locale(args),
+            buy,
+            sell,
+        }
+    }
+}
+
+impl fmt::Display for Quote {
+    // An empty side is called out so it can never read as a $0.00 quote
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let show = |price: &Option<Result<Decimal, PriceError>>, is_buy: bool| match price {
+            Some(Ok(cost)) => format_money(*cost, &self.currency, self.price_dp, self.rounding.strategy(Some(is_buy)), &self.locale),
+            Some(Err(PriceError::EmptyBook)) => "empty book".to_string(),
+            Some(Err(PriceError::InsufficientLiquidity { .. })) => "insufficient liquidity".to_string(),
+            Some(Err(e)) => e.to_string(),
+            None => "-".to_string(),
+        };
+        write!(f, "{} {} | buy: {} | sell: {}", self.quantity, self.base, show(&self.buy, true), show(&self.sell, false))
+    }
+}
+
+// The report in --format: a single JSON line, or the human readable text
+fn render(args: &Args, output: &RunOutput) -> Result<String> {
+    let mut rendered = String::new();
+    match args.format {
+        OutputFormat::Json => writeln!(rendered, "{}", serde_json::to_string(&json_quote(args, output))?)?,
+        OutputFormat::Jsonl => {
+            let line = JsonLine { timestamp: humantime::format_rfc3339_millis(SystemTime::now()).to_string(), quote: json_quote(args, output) };
+            writeln!(rendered, "{}", serde_json::to_string(&line)?)?
+        }
+        OutputFormat::Text => write_text(&mut rendered, args, output)?,
+    }
+    Ok(rendered)
+}
+
+// Writes the report to --output-file as well as stdout
+fn write_output_file(args: &Args, rendered: &str) -> Result<()> {
+    let Some(path) = &args.output_file else { return Ok(()) };
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty() && !dir.is_dir()) {
+        return Err(anyhow::anyhow!("Cannot write {}: the directory {} does not exist", path.display(), dir.display()));
+    }
+    std::fs::write(path, rendered).with_context(|| format!("Failed to write {}", path.display()))?;
+    info!("Quote written to {}", path.display());
+    Ok(())
+}
+
+fn json_quote(args: &Args, output: &RunOutput) -> JsonQuote {
+    JsonQuote {
+        symbol: args.symbol.clone(),
+        quantity: output.quantity,
+        buy_price: output.buy_price.clone().and_then(Result::ok),
+        sell_price: output.sell_price.clone().and_then(Result::ok),
+        currency: currency_code(args),
+        buy_vwap: output.buy_vwap.clone().and_then(Result::ok),
+        sell_vwap: output.sell_vwap.clone().and_then(Result::ok),
+        quote_amount: output.quote_amount,
+        buy_quantity: output.buy_quantity.clone().and_then(Result::ok),
+        sell_quantity: output.sell_quantity.clone().and_then(Result::ok),
+        buy_net: args.fee_bps.zip(output.buy_price.clone().and_then(Result::ok)).and_then(|(bps, cost)| apply_fee(cost, bps, true).ok()),
+        sell_net: args.fee_bps.zip(output.sell_price.clone().and_then(Result::ok)).and_then(|(bps, cost)| apply_fee(cost, bps, false).ok()),
+        buy_book_share: output.buy_book_share.map(|share| share.round_dp(2)),
+        sell_book_share: output.sell_book_share.map(|share| share.round_dp(2)),
+        ask_size_to_price: output.ask_size_to_price,
+        bid_size_to_price: output.bid_size_to_price,
+        coinbase_sequence: output.coinbase_sequence,
+        coinbase_time: output.coinbase_time.clone(),
+        best_ask: output.asks.as_deref().and_then(<[OrderBook]>::first).map(|level| level.price),
+        best_bid: output.bids.as_deref().and_then(<[OrderBook]>::first).map(|level| level.price),
+        imbalance: output.imbalance,
+    }
+}
+
+// A quote saved earlier with --format json, for --compare
+fn load_quote(path: &Path) -> Result<JsonQuote> {
+    let saved = std::fs::read_to_string(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    serde_json::from_str(&saved).with_context(|| format!("{} is not a quote saved with --format json", path.display()))
+}
+
+// Writes how the prices moved since `previous`, with a + on rises so the direction is always shown
+fn write_changes(out: &mut impl fmt::Write, args: &Args, previous: &Path, change: &QuoteChange) -> fmt::Result {
+    let currency = currency_symbol(args);
+    let locale = number_locale(args);
+    // A change belongs to neither side, so it's rounded like the spread
+    let strategy = args.rounding.strategy(None);
+    let signed = |value: Option<Decimal>| match value {
+        Some(value) if value > Decimal::ZERO => format!("+{}", format_money(value, &currency, args.price_dp, strategy, &locale)),
+        Some(value) => format_money(value, &currency, args.price_dp, strategy, &locale),
+        None => "-".to_string(),
+    };
+    writeln!(out, "Since {}:", previous.display())?;
+    writeln!(out, "Best ask: {} | Best bid: {}", signed(change.best_ask), signed(change.best_bid))?;
+    writeln!(out, "Buy price: {} | Sell price: {}", signed(change.buy_price), signed(change.sell_price))
+}
+
+// Writes the human readable report
+fn write_text(out: &mut impl fmt::Write, args: &Args, output: &RunOutput) -> fmt::Result {
+    let currency = currency_symbol(args);
+    let locale = number_locale(args);
+    let money = |value: Decimal| format_money(value, &currency, args.price_dp, args.rounding.strategy(None), &locale);
+    // Buy costs and sell proceeds, rounded for their side
+    let side_money = |value: Decimal, is_buy: bool| format_money(value, &currency, args.price_dp, args.rounding.strategy(Some(is_buy)), &locale);
+    let asks = output.asks.as_deref().unwrap_or_default();
+    let bids = output.bids.as_deref().unwrap_or_default();
+
+    if args.top {
+        let best = |side: &[OrderBook]| side.first().map_or("-".to_string(), |level| money(level.price));
+        writeln!(out, "Best ask: {}", best(asks))?;
+        writeln!(out, "Best bid: {}", best(bids))?;
+        return Ok(());
+    }
+
+    if let Some(depth) = args.show_source {
+        write_sources(out, "ASK", asks, depth)?;
+        write_sources(out, "BID", bids, depth)?;
+    }
+
+    if let Some(rows) = args.pretty {
+        for line in book_table(asks, bids, rows) {
+            writeln!(out, "{}", line)?;
+        }
+    }
+
+    if let Some(points) = args.curve {
+        for (label, side, is_buy) in [("ASK", &output.asks, true), ("BID", &output.bids, false)] {
+            match side.as_deref().map(|levels| price_curve(levels, points)).unwrap_or(Ok(Vec::new())) {
+                Ok(curve) => {
+                    for (qty, avg_price) in curve {
+                        writeln!(out, "{} curve | qty: {} | avg price: {}", label, qty, args.rounding.round(avg_price, args.price_dp, Some(is_buy)))?;
+                    }
+                }
+                Err(e) => writeln!(out, "{} curve unavailable: {}", label, e)?,
+            }
+        }
+    }
+
+    writeln!(out, "--------------------------------")?;
+
+    for hit in &output.cache_hits {
+        let stale = if hit.stale { " (stale)" } else { "" };
+        writeln!(out, "Using the cached {} book from {}s ago{}", hit.exchange, hit.age.as_secs(), stale)?;
+    }
+
+    for exclusion in &output.excluded {
+        writeln!(out, "Excluded {}: {} ({})", exclusion.exchange, exclusion.reason, exclusion.detail)?;
+    }
+
+    if let (Some(sequence), Some(time)) = (output.coinbase_sequence, &output.coinbase_time) {
+        writeln!(out, "Coinbase snapshot: sequence {} at {}", sequence, time)?;
+    }
+
+    if let Some((spread, mid)) = output.top_of_book {
+        let micro = output.micro_price.map(|micro| format!(" | Micro: {}", money(micro))).unwrap_or_default();
+        writeln!(out, "Spread: {} | Mid: {}{}", money(spread), money(mid), micro)?;
+    }
+
+    let base = symbol::base_asset(&args.symbol);
+    if let (Some(qty), Some(round_trip)) = (args.round_trip, &output.round_trip) {
+        match round_trip {
+            Ok(spread) => writeln!(out, "Effective spread for {} {}: {}", qty, base, money(*spread))?,
+            Err(e) => writeln!(out, "Effective spread for {} {} unavailable: {}", qty, base, e)?,
+        }
+    }
+    if let Some(depth) = args.imbalance {
+        match output.imbalance {
+            Some(ratio) => writeln!(out, "Bid/ask size imbalance over the best {} levels: {:.2}", depth, ratio)?,
+            None => writeln!(out, "Bid/ask size imbalance over the best {} levels unavailable: no ask size", depth)?,
+        }
+    }
+    let fee_note = |cost: Decimal, is_buy: bool| {
+        args.fee_bps
+            .map(|bps| match apply_fee(cost, bps, is_buy) {
+                Ok(net) => format!(" | net of {} bps fee: {}", bps, side_money(net, is_buy)),
+                Err(e) => format!(" | net of {} bps fee unavailable: {}", bps, e),
+            })
+            .unwrap_or_default()
+    };
+    let share_note = |share: Option<Decimal>, side: &str| {
+        share
+            .map(|share| format!(" ({}% of the visible {} liquidity)", share.round_dp(2).normalize(), side))
+            .unwrap_or_default()
+    };
+    let depth_note = args.depth
+        .map(|depth| format!(" (only the best {} levels per exchange are visible with --depth)", depth))
+        .unwrap_or_default();
+
+    match &output.buy_price {
+        Some(Ok(cost)) => writeln!(
+            out,
+            "To buy {} {}: {}{}{}",
+            args.qty,
+            base,
+            side_money(*cost, true),
+            share_note(output.buy_book_share, "ask"),
+            fee_note(*cost, true)
+        )?,
+        Some(Err(PriceError::InsufficientLiquidity { available, .. })) => {
+            writeln!(out, "Cannot buy {} {}: only {} {} available{}", args.qty, base, available, base, depth_note)?
+        }
+        Some(Err(PriceError::EmptyBook)) => writeln!(out, "Cannot buy {} {}: no exchange returned any asks", args.qty, base)?,
+        Some(Err(PriceError::Unsorted { index })) => {
+            writeln!(out, "Cannot buy {} {}: the merged asks are out of order at level {}", args.qty, base, index)?
+        }
+        Some(Err(PriceError::Overflow)) => writeln!(out, "Cannot buy {} {}: the total cost is too large to represent", args.qty, base)?,
+        Some(Err(e)) => writeln!(out, "Cannot buy {} {}: {}", args.qty, base, e)?,
+        None => {}
+    }
+    match &output.sell_price {
+        Some(Ok(cost)) => writeln!(
+            out,
+            "To sell {} {}: {}{}{}",
+            args.qty,
+            base,
+            side_money(*cost, false),
+            share_note(output.sell_book_share, "bid"),
+            fee_note(*cost, false)
+        )?,
+        Some(Err(PriceError::InsufficientLiquidity { available, .. })) => {
+            writeln!(out, "Cannot sell {} {}: only {} {} available{}", args.qty, base, available, base, depth_note)?
+        }
+        Some(Err(PriceError::EmptyBook)) => writeln!(out, "Cannot sell {} {}: no exchange returned any bids", args.qty, base)?,
+        Some(Err(PriceError::Unsorted { index })) => {
+            writeln!(out, "Cannot sell {} {}: the merged bids are out of order at level {}", args.qty, base, index)?
+        }
+        Some(Err(PriceError::Overflow)) => writeln!(out, "Cannot sell {} {}: the total proceeds are too large to represent", args.qty, base)?,
+        Some(Err(e)) => writeln!(out, "Cannot sell {} {}: {}", args.qty, base, e)?,
+        None => {}
+    }
+
+    if !output.ladder.is_empty() {
+        for line in ladder_table(&output.ladder, side_money) {
+            writeln!(out, "{}", line)?;
+        }
+    }
+
+    if let Some(amount) = output.quote_amount {
+        match &output.buy_quantity {
+            Some(Ok(quantity)) => writeln!(out, "{} buys {} {}", money(amount), quantity.round_dp(8), base)?,
+            Some(Err(PriceError::InsufficientLiquidity { available, .. })) => writeln!(out, "Cannot spend {}: the book only holds {}", money(amount), money(*available))?,
+            Some(Err(e)) => writeln!(out, "Cannot spend {}: {}", money(amount), e)?,
+            None => {}
+        }
+        match &output.sell_quantity {
+            Some(Ok(quantity)) => writeln!(out, "Raising {} sells {} {}", money(amount), quantity.round_dp(8), base)?,
+            Some(Err(PriceError::InsufficientLiquidity { available, .. })) => writeln!(out, "Cannot raise {}: the book only holds {}", money(amount), money(*available))?,
+            Some(Err(e)) => writeln!(out, "Cannot raise {}: {}", money(amount), e)?,
+            None => {}
+        }
+    }
+
+    match &output.buy_vwap {
+        Some(Ok(avg)) => writeln!(out, "Average buy price: {} per {}", side_money(*avg, true), base)?,
+        Some(Err(e)) => writeln!(out, "Average buy price unavailable: {}", e)?,
+        None => {}
+    }
+    match &output.sell_vwap {
+        Some(Ok(avg)) => writeln!(out, "Average sell price: {} per {}", side_money(*avg, false), base)?,
+        Some(Err(e)) => writeln!(out, "Average sell price unavailable: {}", e)?,
+        None => {}
+    }
+
+    for (label, breakdown) in [("Buy", &output.buy_breakdown), ("Sell", &output.sell_breakdown)] {
+        match breakdown {
+            Some(Ok(sources)) => {
+                for (exchange, (amount, notional)) in sources {
+                    writeln!(out, "{} from {}: {} {} for {}", label, exchange, amount, base, money(*notional))?;
+                }
+            }
+            Some(Err(e)) => writeln!(out, "{} breakdown unavailable: {}", label, e)?,
+            None => {}
+        }
+    }
+
+    if let Some(limit) = args.up_to_price {
+        if let Some(size) = output.ask_size_to_price {
+            writeln!(out, "Offered at or below {}: {} {}", money(limit), size, base)?;
+        }
+        if let Some(size) = output.bid_size_to_price {
+            writeln!(out, "Bid at or above {}: {} {}", money(limit), size, base)?;
+        }
+    }
+
+    if let Some(limit) = args.limit_price {
+        for (label, simulated) in [("buy", &output.buy_limit), ("sell", &output.sell_limit)] {
+            match simulated {
+                Some(Ok((filled, resting, notional))) => writeln!(
+                    out,
+                    "Limit {} {} {} at {}: {} fills now for {}, {} rests",
+                    label, args.qty, base, money(limit), filled.normalize(), money(*notional), resting.normalize()
+                )?,
+                Some(Err(e)) => writeln!(out, "Limit {} unavailable: {}", label, e)?,
+                None => {}
+            }
+        }
+    }
+
+    match &output.buy_slippage {
+        Some(Ok(fraction)) => writeln!(out, "Buy slippage: {}%", (fraction * Decimal::ONE_HUNDRED).round_dp(4))?,
+        Some(Err(e)) => writeln!(out, "Buy slippage unavailable: {}", e)?,
+        None => {}
+    }
+    match &output.sell_slippage {
+        Some(Ok(fraction)) => writeln!(out, "Sell slippage: {}%", (fraction * Decimal::ONE_HUNDRED).round_dp(4))?,
+        Some(Err(e)) => writeln!(out, "Sell slippage unavailable: {}", e)?,
+        None => {}
+    }
+    Ok(())
+}
+
+// Writes the best `depth` levels of one side along with their exchange
+fn write_sources(out: &mut impl fmt::Write, label: &str, levels: &[OrderBook], depth: usize) -> fmt::Result {
+    for (idx, level) in levels.iter().take(depth).enumerate() {
+        writeln!(out, "{} {} | {}", label, idx, level)?;
+    }
+    Ok(())
+}
+
+// Lays out the top `rows` asks and bids side by side as price and cumulative size.
+// Every column is right aligned to its widest entry so the decimal points of similar numbers line up.
+fn book_table(asks: &[OrderBook], bids: &[OrderBook], rows: usize) -> Vec<String> {
+    let cumulative = |levels: &[OrderBook]| -> Vec<(String, String)> {
+        levels
+            .iter()
+            .take(rows)
+            .scan(Decimal::ZERO, |total, level| {
+                *total += level.size;
+                Some((level.price.to_string(), total.to_string()))
+            })
+            .collect()
+    };
+    let asks = cumulative(asks);
+    let bids = cumulative(bids);
+
+    let headers = ["ASK PRICE", "ASK CUM SIZE", "BID PRICE", "BID CUM SIZE"];
+    let width = |header: &str, cells: Vec<usize>| cells.into_iter().chain([header.len()]).max().unwrap_or_default();
+    let widths = [
+        width(headers[0], asks.iter().map(|(price, _)| price.len()).collect()),
+        width(headers[1], asks.iter().map(|(_, size)| size.len()).collect()),
+        width(headers[2], bids.iter().map(|(price, _)| price.len()).collect()),
+        width(headers[3], bids.iter().map(|(_, size)| size.len()).collect()),
+    ];
+
+    let row = |cells: [&str; 4]| {
+        format!(
+            "{:>w0$}  {:>w1$} | {:>w2$}  {:>w3$}",
+            cells[0], cells[1], cells[2], cells[3],
+            w0 = widths[0], w1 = widths[1], w2 = widths[2], w3 = widths[3]
+        )
+    };
+
+    let mut lines = vec![row(headers)];
+    for idx in 0..asks.len().max(bids.len()) {
+        let (ask_price, ask_size) = asks.get(idx).map(|(p, s)| (p.as_str(), s.as_str())).unwrap_or_default();
+        let (bid_price, bid_size) = bids.get(idx).map(|(p, s)| (p.as_str(), s.as_str())).unwrap_or_default();
+        lines.push(row([ask_price, ask_size, bid_price, bid_size]));
+    }
+    lines
+}
+
+// The --quantities ladder as a right aligned table of totals and average prices.
+// `money` formats a figure for the buy side when its flag is set, and the sell side otherwise.
+fn ladder_table(rows: &[LadderRow], money: impl Fn(Decimal, bool) -> String) -> Vec<String> {
+    let cell = |price: &Option<Result<Decimal, PriceError>>, per: Option<Decimal>, is_buy: bool| match price {
+        Some(Ok(total)) => money(per.map_or(*total, |quantity| *total / quantity), is_buy),
+        // Kept short so one deep rung doesn't widen the whole column
+        Some(Err(PriceError::InsufficientLiquidity { .. })) => "not enough".to_string(),
+        Some(Err(e)) => e.to_string(),
+        None => "-".to_string(),
+    };
+    let cells: Vec<[String; 5]> = rows
+        .iter()
+        .map(|row| [
+            row.quantity.to_string(),
+            cell(&row.buy, None, true),
+            cell(&row.sell, None, false),
+            cell(&row.buy, Some(row.quantity), true),
+            cell(&row.sell, Some(row.quantity), false),
+        ])
+        .collect();
+
+    let headers = ["QTY", "BUY", "SELL", "AVG BUY", "AVG SELL"].map(str::to_string);
+    let widths: Vec<usize> = (0..headers.len())
+        .map(|col| cells.iter().chain([&headers]).map(|row| row[col].len()).max().unwrap_or_default())
+        .collect();
+    let line = |row: &[String; 5]| row.iter().zip(&widths).map(|(cell, width)| format!("{:>width$}", cell, width = width)).collect::<Vec<_>>().join(" | ");
+
+    std::iter::once(&headers).chain(&cells).map(line).collect()
+}
+
+// Quote currency of the output, --currency or else the quote asset of --symbol
+fn currency_code(args: &Args) -> String {
+    args.currency.clone().unwrap_or_else(|| symbol::quote_asset(&args.symbol).to_string())
+}
+
+fn currency_symbol(args: &Args) -> String {
+    symbol::currency_symbol(&currency_code(args))
+}
+
+// Format with the `locale`'s grouping by rounding to `dp` decimal places (cents by default) with `strategy`, then splitting the integer and fractional parts.
+// Stays in Decimal the whole way so large notionals don't lose their last digits.
+// `currency` is a sign like $ or a code like USDT, which gets a space before the amount.
+fn format_money(value: Decimal, currency: &str, dp: u32, strategy: RoundingStrategy, locale: &Locale) -> String {
+    let rounded = value.round_dp_with_strategy(dp, strategy);
+    let whole = rounded.trunc();
+    let fraction = ((rounded - whole).abs() * Decimal::from(10u64.pow(dp))).to_u64().unwrap_or_default();
+    let sign = if rounded.is_sign_negative() && !rounded.is_zero() { "-" } else { "" };
+    let space = if currency.chars().all(|c| c.is_ascii_alphanumeric()) { " " } else { "" };
+    let fraction = if dp == 0 { String::new() } else { format!("{}{:0width$}", locale.decimal(), fraction, width = dp as usize) };
+
+    match whole.abs().to_u128() {
+        Some(units) => format!("{}{}{}{}{}", sign, currency, space, units.to_formatted_string(locale), fraction),
+        None => format!("{}{}{}{}", sign, currency, space, rounded.abs()),
+    }
+}
+
+
+// Tests that need the Coinbase or Gemini adapter are gated on those features, so the rest also run in single exchange builds
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::time::Instant;
+    use crate::helpers::types::{BinanceResult, BitstampResult, Exchange};
+    #[cfg(feature = "coinbase")]
+    use crate::helpers::types::CoinbaseResult;
+    #[cfg(feature = "gemini")]
+    use crate::helpers::types::{book_age, GeminiOrder, GeminiResult};
+    use crate::helpers::orderbook_merger::{total_size, PARALLEL_SORT_THRESHOLD};
+    #[cfg(all(feature = "coinbase", feature = "gemini"))]
+    use crate::helpers::{orderbook_merger::merge_detailed, types::OrderBookDetailed};
+    use crate::helpers::rate_limiter::{self, InvalidInterval};
+    use crate::helpers::data_fetcher::{self, get_data_detailed, get_data_with_retries, is_rate_limited};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+    use wiremock::matchers::{method, path};
+
+    #[tokio::test]
+    async fn test_rate_limiter() {
+        let rate_limiter = Arc::new(RateLimiter::new_per_interval(Duration::from_secs(2)).unwrap());
+        let start = Instant::now();
+
+        // First call should succeed immediately
+        rate_limiter.acquire().await;
+        let first_call_time = start.elapsed();
+        assert!(first_call_time < Duration::from_millis(100), "First call should succeed quickly, took {:?}", first_call_time);
+
+        // Second call should be rate limited and wait ~2 seconds
+        let before_second = Instant::now();
+        rate_limiter.acquire().await;
+        let second_call_wait = before_second.elapsed();
+        assert!(second_call_wait >= Duration::from_secs(2) - Duration::from_millis(50), "Second call should wait at least ~2 seconds, waited {:?}", second_call_wait);
+        assert!(second_call_wait < Duration::from_secs(3), "Second call should not wait too long, waited {:?}", second_call_wait);
+
+        // Third call should also be rate limited
+        let before_third = Instant::now();
+        rate_limiter.acquire().await;
+        let third_call_wait = before_third.elapsed();
+        assert!(third_call_wait >= Duration::from_secs(2) - Duration::from_millis(50), "Third call should wait at least ~2 seconds, waited {:?}", third_call_wait);
+
+        // Total time for 3 calls should be at least 4 seconds (2s between each)
+        let total_time = start.elapsed();
+        assert!(total_time >= Duration::from_secs(4) - Duration::from_millis(100), "Total time for 3 calls should be at least ~4 seconds, took {:?}", total_time);
+    }
+
+    #[tokio::test]
+    async fn test_rate_limiter_2() {
+        let rate_limiter = Arc::new(RateLimiter::new_per_interval(Duration::from_secs(2)).unwrap());
+
+        // First call should succeed
+        assert!(rate_limiter.try_acquire().await.is_ok(), "First call should succeed");
+
+        // Second call should fail immediately (non-blocking)
+        assert!(rate_limiter.try_acquire().await.is_err(), "Second call should fail immediately due to rate limit");
+
+        // After waiting 2 seconds, should succeed again
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        assert!(rate_limiter.try_acquire().await.is_ok(), "Call after 2 seconds should succeed");
+    }
+
+    fn book(levels: &[(&str, &str)]) -> Vec<OrderBook> {
+        levels
+            .iter()
+            .map(|(price, size)| OrderBook {
+                price: Decimal::from_str_exact(price).unwrap(),
+                size: Decimal::from_str_exact(size).unwrap(),
+                source: Exchange::Coinbase,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_price_curve_is_monotonic_for_asks() {
+        let asks = book(&[("100", "0.5"), ("101", "1.5"), ("105", "0.25"), ("110", "2")]);
+
+        let curve = price_curve(&asks, 17).unwrap();
+        assert_eq!(curve.len(), 17);
+
+        // The last point covers the whole book
+        let (last_qty, _) = curve[curve.len() - 1];
+        assert_eq!(last_qty, Decimal::from_str_exact("4.25").unwrap());
+
+        for window in curve.windows(2) {
+            assert!(window[0].0 < window[1].0, "Quantities should increase: {:?}", window);
+            assert!(window[0].1 <= window[1].1, "Average ask price should not decrease: {:?}", window);
+        }
+
+        // First sample is fully inside the best level
+        assert_eq!(curve[0].1, Decimal::from(100));
+    }
+
+    #[test]
+    fn test_price_curve_empty_book() {
+        assert!(price_curve(&[], 10).unwrap().is_empty());
+        assert!(price_curve(&book(&[("100", "1")]), 0).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_price_curve_overflow_and_huge_point_counts() {
+        let max = Decimal::MAX.to_string();
+        assert_eq!(price_curve(&book(&[("1", &max), ("2", "1")]), 4), Err(PriceError::Overflow));
+        assert_eq!(price_curve(&book(&[(&max, "1"), (&max, "1")]), 2), Err(PriceError::Overflow));
+
+        // Rejected by the parser instead of reserving room for them
+        for points in ["0", "10001", &usize::MAX.to_string()] {
+            assert!(Args::try_parse_from(["ob-aggregator-rs", "--curve", points]).is_err(), "--curve {}", points);
+        }
+        assert_eq!(Args::parse_from(["ob-aggregator-rs", "--curve", "10000"]).curve, Some(10_000));
+    }
+
+    #[tokio::test]
+    async fn test_rate_limiter_adaptive() {
+        let rate_limiter = RateLimiter::new_per_interval(Duration::from_secs(2)).unwrap();
+        let base_rate = rate_limiter.tokens_per_second().await;
+
+        // Repeated 429s keep lowering the effective rate
+        let mut previous = base_rate;
+        for _ in 0..3 {
+            rate_limiter.tighten().await;
+            let current = rate_limiter.tokens_per_second().await;
+            assert!(current < previous, "Rate should decrease after a 429: {} -> {}", previous, current);
+            previous = current;
+        }
+
+        // Successes restore the configured rate, but never exceed it
+        for _ in 0..20 {
+            rate_limiter.relax().await;
+        }
+        assert_eq!(rate_limiter.tokens_per_second().await, base_rate);
+    }
+
+    #[test]
+    fn test_calculate_entity_price_insufficient_liquidity() {
+        let asks = book(&[("100", "0.4"), ("101", "0.6")]);
+        let requested = Decimal::from(5);
+
+        let result = calculate_entity_price(&asks, requested, true, "ASKS");
+        assert_eq!(
+            result,
+            Err(PriceError::InsufficientLiquidity { requested, available: Decimal::ONE })
+        );
+
+        // Filling within the book still returns the total cost
+        let result = calculate_entity_price(&asks, Decimal::ONE, true, "ASKS");
+        assert_eq!(result, Ok(Decimal::from_str_exact("100.6").unwrap()));
+    }
+
+    #[test]
+    fn test_calculate_vwap_two_levels() {
+        let asks = book(&[("100", "1"), ("110", "2")]);
+
+        // 1 @ 100 + 1 @ 110 = 210 over 2 BTC
+        let vwap = calculate_vwap(&asks, Decimal::from(2), true).unwrap();
+        assert_eq!(vwap, Decimal::from(105));
+
+        // Partial fill averages over the 3 BTC actually filled: (100 + 220) / 3
+        let vwap = calculate_vwap(&asks, Decimal::from(10), true).unwrap();
+        assert_eq!(vwap, Decimal::from(320) / Decimal::from(3));
+    }
+
+    #[test]
+    fn test_symbol_endpoints() {
+        let sym = parse_symbol("eth-usd").unwrap();
+        assert_eq!(sym, "ETH-USD");
+        assert_eq!(symbol::base_asset(&sym), "ETH");
+
+        let coinbase = symbol::endpoint("https://api.exchange.coinbase.com/products/{symbol}/book?level=2", &symbol::to_coinbase(&sym));
+        let gemini = symbol::endpoint("https://api.gemini.com/v1/book/{symbol}", &symbol::to_gemini(&sym));
+        assert_eq!(coinbase, "https://api.exchange.coinbase.com/products/ETH-USD/book?level=2");
+        assert_eq!(gemini, "https://api.gemini.com/v1/book/ethusd");
+
+        // Templates without a placeholder are left alone
+        assert_eq!(symbol::endpoint("https://api.gemini.com/v1/book/btcusd", "ethusd"), "https://api.gemini.com/v1/book/btcusd");
+    }
+
+    #[test]
+    fn test_parse_symbol_rejects_malformed() {
+        for bad in ["BTC", "BTCUSD", "BTC-USD-EUR", "BTC-", "B/C-USD", ""] {
+            assert!(parse_symbol(bad).is_err(), "{} should be rejected", bad);
+        }
+    }
+
+    #[cfg(all(feature = "coinbase", feature = "gemini"))]
+    fn sample_books() -> (CoinbaseResult, GeminiResult) {
+        let coinbase: CoinbaseResult = serde_json::from_value(serde_json::json!({
+            "bids": [["99", "1", 1]],
+            "asks": [["101", "1", 1]],
+            "sequence": 1,
+            "auction_mode": false,
+            "auction": null,
+            "time": "2025-11-01T00:00:00Z"
+        })).unwrap();
+        let gemini: GeminiResult = serde_json::from_value(serde_json::json!({
+            "bids": [{"price": "98", "amount": "2", "timestamp": "1761996296"}],
+            "asks": [{"price": "102", "amount": "2", "timestamp": "1761996296"}]
+        })).unwrap();
+        (coinbase, gemini)
+    }
+
+    #[cfg(all(feature = "coinbase", feature = "gemini"))]
+    #[test]
+    fn test_side_buy_skips_sell_branch() {
+        let (coinbase, gemini) = sample_books();
+        let (asks, bids) = merge_books(MergeOptions { side: Side::Buy, ..Default::default() }, vec![coinbase.into(), gemini.into()]);
+        assert_eq!(asks.map(|asks| asks.len()), Some(2));
+        assert!(bids.is_none(), "Bids should not be merged when only buying");
+
+        let (coinbase, gemini) = sample_books();
+        let (asks, bids) = merge_books(MergeOptions { side: Side::Sell, ..Default::default() }, vec![coinbase.into(), gemini.into()]);
+        assert!(asks.is_none(), "Asks should not be merged when only selling");
+        assert_eq!(bids.map(|bids| bids.len()), Some(2));
+    }
+
+    #[test]
+    fn test_json_quote_round_trip() {
+        let quote = JsonQuote {
+            symbol: "BTC-USD".to_string(),
+            quantity: Decimal::from_str_exact("1.5").unwrap(),
+            buy_price: Some(Decimal::from_str_exact("165123.456789").unwrap()),
+            sell_price: None,
+            currency: "USD".to_string(),
+            buy_vwap: None,
+            sell_vwap: None,
+            buy_net: None,
+            sell_net: None,
+            quote_amount: None,
+            buy_quantity: None,
+            sell_quantity: None,
+            buy_book_share: None,
+            sell_book_share: None,
+            ask_size_to_price: None,
+            bid_size_to_price: None,
+            coinbase_sequence: None,
+            coinbase_time: None,
+            best_ask: None,
+            best_bid: None,
+            imbalance: None,
+        };
+
+        let output = serde_json::to_string(&quote).unwrap();
+        assert!(output.contains("\"buy_price\":\"165123.456789\""), "Decimals should serialize as strings: {}", output);
+
+        let parsed: JsonQuote = serde_json::from_str(&output).unwrap();
+        assert_eq!(parsed.symbol, "BTC-USD");
+        assert_eq!(parsed.quantity, quote.quantity);
+        assert_eq!(parsed.buy_price, quote.buy_price);
+        assert_eq!(parsed.sell_price, None);
+        assert_eq!(parsed.currency, "USD");
+    }
+
+    fn sourced_book(source: Exchange, prices: &[u32]) -> Vec<OrderBook> {
+        prices
+            .iter()
+            .map(|price| OrderBook { price: Decimal::from(*price), size: Decimal::ONE, source })
+            .collect()
+    }
+
+    #[test]
+    fn test_merge_asks_three_books() {
+        let merged = merge_asks(vec![
+            sourced_book(Exchange::Coinbase, &[101, 104, 107]),
+            sourced_book(Exchange::Gemini, &[100, 105]),
+            sourced_book(Exchange::Coinbase, &[103, 102, 106]), // unsorted input is sorted first
+        ], false, false);
+
+        let prices: Vec<Decimal> = merged.iter().map(|order| order.price).collect();
+        let expected: Vec<Decimal> = (100..=107).map(Decimal::from).collect();
+        assert_eq!(prices, expected);
+    }
+
+    #[test]
+    fn test_merge_bids_four_books() {
+        let merged = merge_bids(vec![
+            sourced_book(Exchange::Coinbase, &[99, 95]),
+            sourced_book(Exchange::Gemini, &[98, 94, 90]),
+            sourced_book(Exchange::Coinbase, &[]),
+            sourced_book(Exchange::Gemini, &[97, 96, 93, 92, 91]),
+        ], false, false);
+
+        let prices: Vec<Decimal> = merged.iter().map(|order| order.price).collect();
+        let expected: Vec<Decimal> = (90..=99).rev().map(Decimal::from).collect();
+        assert_eq!(prices, expected);
+
+        // Equal levels come out in exchange order, whatever order the books were passed in
+        let merged = merge_bids(vec![sourced_book(Exchange::Gemini, &[50]), sourced_book(Exchange::Coinbase, &[50])], false, false);
+        let sources: Vec<Exchange> = merged.iter().map(|order| order.source).collect();
+        assert_eq!(sources, vec![Exchange::Coinbase, Exchange::Gemini]);
+    }
+
+    #[cfg(all(feature = "coinbase", feature = "gemini"))]
+    #[test]
+    fn test_merge_tracks_source() {
+        let (coinbase, gemini) = sample_books();
+        let (asks, bids) = merge_books(MergeOptions::default(), vec![coinbase.into(), gemini.into()]);
+
+        let asks: Vec<(Decimal, Exchange)> = asks.unwrap().iter().map(|level| (level.price, level.source)).collect();
+        assert_eq!(asks, vec![(Decimal::from(101), Exchange::Coinbase), (Decimal::from(102), Exchange::Gemini)]);
+
+        let bids: Vec<(Decimal, Exchange)> = bids.unwrap().iter().map(|level| (level.price, level.source)).collect();
+        assert_eq!(bids, vec![(Decimal::from(99), Exchange::Coinbase), (Decimal::from(98), Exchange::Gemini)]);
+    }
+
+    #[cfg(all(feature = "coinbase", feature = "gemini"))]
+    #[test]
+    fn test_consolidated_merge_sums_equal_prices() {
+        let coinbase: CoinbaseResult = serde_json::from_value(serde_json::json!({
+            "bids": [["99", "1", 1], ["98", "0.5", 2]],
+            "asks": [["101", "1", 1], ["102", "0.25", 3]],
+            "sequence": 1,
+            "auction_mode": false,
+            "auction": null,
+            "time": "2025-11-01T00:00:00Z"
+        })).unwrap();
+        let gemini: GeminiResult = serde_json::from_value(serde_json::json!({
+            "bids": [{"price": "99", "amount": "2", "timestamp": "1761996296"}],
+            "asks": [{"price": "102", "amount": "0.75", "timestamp": "1761996296"}]
+        })).unwrap();
+
+        let options = MergeOptions { consolidate: true, ..Default::default() };
+        let (asks, bids) = merge_books(options, vec![coinbase.into(), gemini.into()]);
+
+        let asks: Vec<(Decimal, Decimal)> = asks.unwrap().iter().map(|level| (level.price, level.size)).collect();
+        assert_eq!(asks, vec![(Decimal::from(101), Decimal::ONE), (Decimal::from(102), Decimal::ONE)]);
+
+        let bids: Vec<(Decimal, Decimal)> = bids.unwrap().iter().map(|level| (level.price, level.size)).collect();
+        assert_eq!(bids, vec![(Decimal::from(99), Decimal::from(3)), (Decimal::from(98), Decimal::from_str_exact("0.5").unwrap())]);
+    }
+
+    #[test]
+    fn test_spread_and_mid() {
+        let asks = book(&[("101.5", "1"), ("102", "1")]);
+        let bids = book(&[("100", "1"), ("99", "1")]);
+
+        assert_eq!(
+            spread(&asks, &bids),
+            Some((Decimal::from_str_exact("1.5").unwrap(), Decimal::from_str_exact("100.75").unwrap()))
+        );
+
+        assert_eq!(spread(&[], &bids), None);
+        assert_eq!(spread(&asks, &[]), None);
+    }
+
+    #[tokio::test]
+    async fn test_get_data_with_retries_recovers() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/book"))
+            .respond_with(ResponseTemplate::new(503))
+            .up_to_n_times(2)
+            .with_priority(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/book"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({"bids": [], "asks": []})))
+            .mount(&server)
+            .await;
+
+        let client = api_client::create_client(api_client::DEFAULT_TIMEOUT, HeaderMap::new());
+        let data = get_data_with_retries(&client, &format!("{}/book", server.uri()), &HeaderMap::new(), 3).await.unwrap();
+
+        assert_eq!(data["bids"], serde_json::json!([]));
+        assert_eq!(server.received_requests().await.unwrap().len(), 3, "Should retry twice then succeed");
+    }
+
+    #[test]
+    fn test_retry_backoff_is_bounded() {
+        assert_eq!(data_fetcher::backoff(0), Duration::from_millis(100));
+        assert_eq!(data_fetcher::backoff(2), Duration::from_millis(400));
+        assert_eq!(data_fetcher::backoff(7), Duration::from_secs(10));
+        assert_eq!(data_fetcher::backoff(u32::MAX), Duration::from_secs(10));
+
+        assert!(Args::try_parse_from(["ob-aggregator-rs", "--qty", "1", "--retries", "10"]).is_ok());
+        assert!(Args::try_parse_from(["ob-aggregator-rs", "--qty", "1", "--retries", "11"]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_get_data_with_retries_skips_client_errors() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(404))
+            .mount(&server)
+            .await;
+
+        let client = api_client::create_client(api_client::DEFAULT_TIMEOUT, HeaderMap::new());
+        let result = get_data_with_retries(&client, &format!("{}/book", server.uri()), &HeaderMap::new(), 3).await;
+
+        assert!(result.is_err());
+        assert_eq!(server.received_requests().await.unwrap().len(), 1, "4xx errors should not be retried");
+    }
+
+    #[tokio::test]
+    async fn test_client_timeout() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_delay(Duration::from_millis(500)))
+            .mount(&server)
+            .await;
+
+        let client = api_client::create_client(Duration::from_millis(1), HeaderMap::new());
+        let err = get_data(&client, &server.uri(), &HeaderMap::new()).await.unwrap_err();
+
+        let is_timeout = err.downcast_ref::<reqwest::Error>().is_some_and(|e| e.is_timeout());
+        assert!(is_timeout, "Expected a timeout error, got {:?}", err);
+    }
+
+    #[tokio::test]
+    async fn test_rate_limiter_burst() {
+        let rate_limiter = RateLimiter::new_with_burst(5, Duration::from_secs(2)).unwrap();
+        let start = Instant::now();
+
+        // The whole burst is available straight away
+        for i in 0..5 {
+            assert!(rate_limiter.try_acquire().await.is_ok(), "Call {} of the burst should succeed", i + 1);
+        }
+        assert!(start.elapsed() < Duration::from_millis(100), "Burst should not wait, took {:?}", start.elapsed());
+
+        // Then the limiter throttles
+        assert!(rate_limiter.try_acquire().await.is_err(), "Call after the burst should be rate limited");
+    }
+
+    #[tokio::test]
+    async fn test_rate_limiter_peek_tokens() {
+        let rate_limiter = RateLimiter::new_with_burst(3, Duration::from_secs(2)).unwrap();
+        assert_eq!(rate_limiter.peek_tokens().await, Decimal::from(3));
+        rate_limiter.try_acquire().await.unwrap();
+
+        // Looking doesn't spend or bank anything. Refilling at half a token a second, a few ms add almost nothing.
+        for _ in 0..100 {
+            let tokens = rate_limiter.peek_tokens().await;
+            assert!(tokens >= Decimal::TWO && tokens < Decimal::from_str_exact("2.1").unwrap(), "Unexpected count {}", tokens);
+        }
+
+        assert!(rate_limiter.try_acquire().await.is_ok());
+        assert!(rate_limiter.try_acquire().await.is_ok());
+        assert!(rate_limiter.try_acquire().await.is_err(), "Only the two tokens left should be available");
+
+        rate_limiter.penalize(Duration::from_secs(60)).await;
+        assert_eq!(rate_limiter.peek_tokens().await, Decimal::ZERO);
+        // Doesn't overflow the clock
+        rate_limiter.penalize(Duration::MAX).await;
+        assert_eq!(rate_limiter.peek_tokens().await, Decimal::ZERO);
+    }
+
+    #[tokio::test]
+    async fn test_rate_limiter_interval_precision() {
+        let half_second = RateLimiter::new_per_interval(Duration::from_millis(500)).unwrap();
+        assert_eq!(half_second.tokens_per_second().await, Decimal::TWO);
+
+        let ten_minutes = RateLimiter::new_per_interval(Duration::from_secs(600)).unwrap();
+        assert_eq!(ten_minutes.tokens_per_second().await, Decimal::ONE / Decimal::from(600));
+
+        // Sub-microsecond intervals are no longer rounded away
+        let fast = RateLimiter::new_per_interval(Duration::from_nanos(250)).unwrap();
+        assert_eq!(fast.tokens_per_second().await, Decimal::from(4_000_000));
+
+        assert_eq!(RateLimiter::new_per_interval(Duration::ZERO).err(), Some(InvalidInterval(Duration::ZERO)));
+    }
+
+    #[tokio::test]
+    async fn test_rate_limiter_acquire_does_not_spin() {
+        let rate_limiter = RateLimiter::new_per_interval(Duration::from_millis(20)).unwrap();
+
+        for i in 0..10 {
+            let attempts = rate_limiter.acquire().await;
+            // One failed attempt, one sleep, then the token is there
+            assert!(attempts <= 2, "acquire {} spun {} times before getting a token", i, attempts);
+        }
+    }
+
+    #[test]
+    fn test_binance_deserialize() {
+        let payload = r#"{
+            "lastUpdateId": 1027024,
+            "bids": [["4.00000000", "431.00000000"], ["3.99000000", "12.50000000"]],
+            "asks": [["4.00000200", "12.00000000"]]
+        }"#;
+
+        let result: BinanceResult = serde_json::from_str(payload).unwrap();
+        assert_eq!(result.last_update_id, 1027024);
+        assert_eq!(result.bids.len(), 2);
+        assert_eq!(result.bids[1].price, Decimal::from_str_exact("3.99").unwrap());
+        assert_eq!(result.bids[1].qty, Decimal::from_str_exact("12.5").unwrap());
+        assert_eq!(result.asks[0].price, Decimal::from_str_exact("4.000002").unwrap());
+
+        let book = NormalizedBook::from(result);
+        assert!(book.asks.iter().all(|level| level.source == Exchange::Binance));
+
+        // Levels with a missing qty are rejected
+        assert!(serde_json::from_str::<BinanceResult>(r#"{"lastUpdateId": 1, "bids": [["4.0"]], "asks": []}"#).is_err());
+    }
+
+    #[test]
+    fn test_validate_book() {
+        let asks = book(&[("100", "1"), ("100", "2"), ("101", "1")]);
+        assert_eq!(validate_book(&asks, true), Ok(()));
+
+        let bids = book(&[("99", "1"), ("98", "1"), ("97", "1")]);
+        assert_eq!(validate_book(&bids, false), Ok(()));
+
+        // A single inversion is reported at the level that breaks the order
+        let inverted = book(&[("100", "1"), ("102", "1"), ("101", "1"), ("103", "1")]);
+        let err = validate_book(&inverted, true).unwrap_err();
+        assert!(err.contains("Order 1 (price 102) vs Order 2 (price 101)"), "Unexpected error: {}", err);
+    }
+
+    #[cfg(all(feature = "coinbase", feature = "gemini"))]
+    #[test]
+    fn test_merge_skips_empty_levels() {
+        let coinbase: CoinbaseResult = serde_json::from_value(serde_json::json!({
+            "bids": [["99", "0", 1], ["98", "1", 1]],
+            "asks": [["101", "1", 1], ["102", "0", 1]],
+            "sequence": 1,
+            "auction_mode": false,
+            "auction": null,
+            "time": "2025-11-01T00:00:00Z"
+        })).unwrap();
+        let gemini: GeminiResult = serde_json::from_value(serde_json::json!({
+            "bids": [{"price": "97", "amount": "0.0", "timestamp": "1761996296"}],
+            "asks": [{"price": "100", "amount": "0", "timestamp": "1761996296"}, {"price": "103", "amount": "2", "timestamp": "1761996296"}]
+        })).unwrap();
+
+        let options = MergeOptions { skip_empty: true, ..Default::default() };
+        let (asks, bids) = merge_books(options, vec![coinbase.into(), gemini.into()]);
+
+        let asks: Vec<Decimal> = asks.unwrap().iter().map(|level| level.price).collect();
+        assert_eq!(asks, vec![Decimal::from(101), Decimal::from(103)]);
+
+        let bids: Vec<Decimal> = bids.unwrap().iter().map(|level| level.price).collect();
+        assert_eq!(bids, vec![Decimal::from(98)]);
+    }
+
+    #[cfg(feature = "coinbase")]
+    #[test]
+    fn test_depth_limits_merged_levels() {
+        let ladder = |start: u32, step: i32| -> Vec<Vec<serde_json::Value>> {
+            (0..10).map(|i| vec![
+                serde_json::json!((start as i32 + step * i).to_string()),
+                serde_json::json!("1"),
+                serde_json::json!(1),
+            ]).rev().collect()
+        };
+        let coinbase: CoinbaseResult = serde_json::from_value(serde_json::json!({
+            "bids": ladder(99, -1),
+            "asks": ladder(101, 1),
+            "sequence": 1,
+            "auction_mode": false,
+            "auction": null,
+            "time": "2025-11-01T00:00:00Z"
+        })).unwrap();
+
+        let options = MergeOptions { depth: Some(5), ..Default::default() };
+        let (asks, bids) = merge_books(options, vec![coinbase.into()]);
+
+        let asks: Vec<Decimal> = asks.unwrap().iter().map(|level| level.price).collect();
+        assert_eq!(asks, (101..=105).map(Decimal::from).collect::<Vec<_>>());
+
+        let bids: Vec<Decimal> = bids.unwrap().iter().map(|level| level.price).collect();
+        assert_eq!(bids, (95..=99).rev().map(Decimal::from).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_csv_export_round_trip() {
+        let asks = book(&[("100", "0.5"), ("101", "1.5"), ("102", "2")]);
+        let bids = book(&[("99", "1"), ("98", "0.25")]);
+        let path = env::temp_dir().join(format!("ob-aggregator-export-{}.csv", std::process::id()));
+
+        export::write_csv(&path, &asks, &bids).unwrap();
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let lines: Vec<&str> = contents.lines().collect();
+        assert_eq!(lines[0], "side,price,size,cumulative_size");
+        assert_eq!(lines.len(), 1 + asks.len() + bids.len());
+        assert_eq!(lines.iter().filter(|line| line.starts_with("ask,")).count(), 3);
+        assert_eq!(lines.iter().filter(|line| line.starts_with("bid,")).count(), 2);
+        assert_eq!(lines[3], "ask,102,2,4.0");
+        assert_eq!(lines[5], "bid,98,0.25,1.25");
+
+        // An empty book still gets the header
+        let mut out = Vec::new();
+        export::write_book(&mut out, &[], &[]).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "side,price,size,cumulative_size\n");
+    }
+
+    #[test]
+    fn test_missing_api_env_var() {
+        let err = api_template("OB_AGGREGATOR_UNSET_API").unwrap_err();
+        assert_eq!(err.to_string(), "OB_AGGREGATOR_UNSET_API not set; create a .env or export it");
+    }
+
+    // Serves one small Coinbase and Gemini book and returns endpoints pointing at them
+    async fn mock_exchanges() -> (MockServer, Endpoints) {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/coinbase"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "bids": [["99", "1", 1], ["98", "1", 1]],
+                "asks": [["101", "1", 1], ["102", "1", 1]],
+                "sequence": 1,
+                "auction_mode": false,
+                "auction": null,
+                "time": "2025-11-01T00:00:00Z"
+            })))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/gemini"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "bids": [{"price": "99.5", "amount": "0.5", "timestamp": "1761996296"}],
+                "asks": [{"price": "100.5", "amount": "0.5", "timestamp": "1761996296"}]
+            })))
+            .mount(&server)
+            .await;
+
+        let endpoints = Endpoints {
+            coinbase: format!("{}/coinbase", server.uri()),
+            gemini: format!("{}/gemini", server.uri()),
+            binance: None,
+            bitstamp: None,
+            api_keys: ApiKeys::default(),
+        };
+        (server, endpoints)
+    }
+
+    #[cfg(all(feature = "coinbase", feature = "gemini"))]
+    #[tokio::test]
+    async fn test_run_against_mock_exchanges() {
+        let (_server, endpoints) = mock_exchanges().await;
+        let args = Args::parse_from(["ob-aggregator-rs", "--qty", "1", "--burst", "2"]);
+        let client = api_client::create_client(api_client::DEFAULT_TIMEOUT, HeaderMap::new());
+
+        let output = run(&args, &endpoints, &client, Some(&create_rate_limiter(&args).unwrap())).await.unwrap();
+
+        // 0.5 @ 100.5 from Gemini then 0.5 @ 101 from Coinbase
+        assert_eq!(output.buy_price, Some(Ok(Decimal::from_str_exact("100.75").unwrap())));
+        // 0.5 @ 99.5 from Gemini then 0.5 @ 99 from Coinbase
+        assert_eq!(output.sell_price, Some(Ok(Decimal::from_str_exact("99.25").unwrap())));
+        assert_eq!(output.top_of_book, Some((Decimal::ONE, Decimal::from(100))));
+        assert_eq!(format_money(output.buy_price.unwrap().unwrap(), "$", 2, RoundingStrategy::MidpointAwayFromZero, &Locale::en), "$100.75");
+    }
+
+    #[test]
+    fn test_format_money_large_notional() {
+        assert_eq!(format_money(Decimal::from_str_exact("1234567.896").unwrap(), "$", 2, RoundingStrategy::MidpointAwayFromZero, &Locale::en), "$1,234,567.90");
+        assert_eq!(format_money(Decimal::from_str_exact("98765432109876.006").unwrap(), "$", 2, RoundingStrategy::MidpointAwayFromZero, &Locale::en), "$98,765,432,109,876.01");
+        assert_eq!(format_money(Decimal::from_str_exact("0.5").unwrap(), "$", 2, RoundingStrategy::MidpointAwayFromZero, &Locale::en), "$0.50");
+        assert_eq!(format_money(Decimal::from_str_exact("-1.5").unwrap(), "$", 2, RoundingStrategy::MidpointAwayFromZero, &Locale::en), "-$1.50");
+    }
+
+    #[test]
+    fn test_fill_by_quote_two_levels() {
+        let asks = book(&[("100", "1"), ("200", "1")]);
+
+        // $100 takes the whole first level, the other $100 buys half of the second
+        assert_eq!(fill_by_quote(&asks, Decimal::from(200), true), Ok(Decimal::from_str_exact("1.5").unwrap()));
+        assert_eq!(fill_by_quote(&asks, Decimal::from(50), true), Ok(Decimal::from_str_exact("0.5").unwrap()));
+
+        // The whole book is only worth $300
+        assert_eq!(
+            fill_by_quote(&asks, Decimal::from(400), true),
+            Err(PriceError::InsufficientLiquidity { requested: Decimal::from(400), available: Decimal::from(300) })
+        );
+        assert_eq!(fill_by_quote(&[], Decimal::from(400), true), Err(PriceError::EmptyBook));
+
+        assert!(Args::try_parse_from(["ob-aggregator-rs", "--qty", "1", "--quote-amount", "100"]).is_err());
+    }
+
+    #[test]
+    fn test_slippage_across_two_levels() {
+        // 1 @ 100 + 1 @ 102 averages 101, 1% above the best ask
+        let asks = book(&[("100", "1"), ("102", "1")]);
+        assert_eq!(slippage(&asks, Decimal::TWO, true), Ok(Decimal::from_str_exact("0.01").unwrap()));
+
+        // 1 @ 100 + 1 @ 98 averages 99, 1% below the best bid
+        let bids = book(&[("100", "1"), ("98", "1")]);
+        assert_eq!(slippage(&bids, Decimal::TWO, false), Ok(Decimal::from_str_exact("0.01").unwrap()));
+
+        assert_eq!(slippage(&asks, Decimal::ONE, true), Ok(Decimal::ZERO));
+        assert!(slippage(&[], Decimal::ONE, true).is_err());
+    }
+
+    #[test]
+    fn test_slippage_zero_best_price() {
+        let asks = book(&[("0", "1"), ("100", "1")]);
+        assert_eq!(slippage(&asks, Decimal::TWO, true), Err(PriceError::ZeroPrice));
+
+        // An empty level at the top is skipped, the same as the fill skips it
+        let asks = book(&[("99", "0"), ("100", "1"), ("102", "1")]);
+        assert_eq!(slippage(&asks, Decimal::TWO, true), Ok(Decimal::from_str_exact("0.01").unwrap()));
+    }
+
+    #[test]
+    fn test_stream_applies_recorded_updates() {
+        let recorded = [
+            r#"{"channel":"subscriptions","sequence_num":0,"events":[]}"#,
+            r#"{"channel":"l2_data","sequence_num":1,"events":[{"type":"snapshot","updates":[
+                {"side":"bid","price_level":"99","new_quantity":"1"},
+                {"side":"bid","price_level":"98","new_quantity":"2"},
+                {"side":"offer","price_level":"101","new_quantity":"1"},
+                {"side":"offer","price_level":"102","new_quantity":"3"}]}]}"#,
+            r#"{"channel":"l2_data","sequence_num":2,"events":[{"type":"update","updates":[
+                {"side":"offer","price_level":"101","new_quantity":"0"},
+                {"side":"offer","price_level":"100.5","new_quantity":"0.5"},
+                {"side":"bid","price_level":"98","new_quantity":"4"}]}]}"#,
+        ];
+
+        let mut live = LiveBook::new(Exchange::Coinbase);
+        for message in recorded {
+            let (sequence, events) = stream::parse_coinbase(message, false).unwrap();
+            live.check_sequence(sequence.unwrap()).unwrap();
+            events.into_iter().for_each(|event| live.apply(event));
+        }
+
+        let book = live.to_book();
+        let levels = |levels: &[OrderBook]| levels.iter().map(|level| (level.price.to_string(), level.size.to_string())).collect::<Vec<_>>();
+        assert_eq!(levels(&book.asks), [("100.5".to_string(), "0.5".to_string()), ("102".to_string(), "3".to_string())]);
+        assert_eq!(levels(&book.bids), [("99".to_string(), "1".to_string()), ("98".to_string(), "4".to_string())]);
+
+        // A skipped message is reported so the feed can resubscribe
+        let gap = live.check_sequence(4).unwrap_err();
+        assert_eq!((gap.expected, gap.received), (3, 4));
+
+        // Gemini changes land on the same book shape
+        let mut gemini = LiveBook::new(Exchange::Gemini);
+        let events = stream::parse_gemini(r#"{"type":"l2_updates","symbol":"BTCUSD","changes":[["buy","99.5","2"],["sell","100","1"],["sell","100","0"]]}"#, false).unwrap();
+        events.into_iter().for_each(|event| gemini.apply(event));
+        let book = gemini.to_book();
+        assert!(book.asks.is_empty());
+        assert_eq!(book.bids[0].source, Exchange::Gemini);
+    }
+
+    #[test]
+    fn test_live_merge_matches_full_merge_on_every_tick() {
+        let level = |side, price: &str, size: &str| LevelChange { side, price: price.parse().unwrap(), size: size.parse().unwrap() };
+        let ticks = [
+            (Exchange::Coinbase, FeedEvent::Snapshot(vec![level(BookSide::Ask, "101", "1"), level(BookSide::Ask, "102", "2"), level(BookSide::Bid, "99", "1.5")])),
+            (Exchange::Gemini, FeedEvent::Snapshot(vec![level(BookSide::Ask, "101", "0.5"), level(BookSide::Bid, "99", "0.001"), level(BookSide::Bid, "98", "3")])),
+            (Exchange::Coinbase, FeedEvent::Update(vec![level(BookSide::Ask, "101", "0"), level(BookSide::Ask, "100.5", "0.25"), level(BookSide::Ask, "102", "0.005"), level(BookSide::Bid, "99", "2")])),
+            (Exchange::Gemini, FeedEvent::Update(vec![level(BookSide::Bid, "99", "0"), level(BookSide::Ask, "103", "4")])),
+            // A resubscribe replaces the whole book, dropping levels the new snapshot doesn't have
+            (Exchange::Coinbase, FeedEvent::Snapshot(vec![level(BookSide::Ask, "100", "1"), level(BookSide::Bid, "97", "1")])),
+        ];
+
+        for flags in [&[][..], &["--min-size", "0.01"], &["--side", "buy"]] {
+            let args = Args::parse_from(["ob-aggregator-rs", "--qty", "1.5"].iter().chain(flags));
+            let options = MergeOptions::from(&args);
+            let mut live_merge = LiveMerge::new(options).unwrap();
+            let mut books = [LiveBook::new(Exchange::Coinbase), LiveBook::new(Exchange::Gemini)];
+
+            for (source, event) in ticks.clone() {
+                live_merge.apply(source, &event);
+                books.iter_mut().find(|book| book.source() == source).unwrap().apply(event);
+
+                let (asks, bids) = merge_books(options, books.iter().map(LiveBook::to_book).collect());
+                let expected = (
+                    asks.as_deref().map(|asks| calculate_entity_price(asks, args.qty, true, "ASKS")),
+                    bids.as_deref().map(|bids| calculate_entity_price(bids, args.qty, false, "BIDS")),
+                );
+                assert_eq!(live_merge.quote(args.qty), expected, "{:?} after a {:?} tick", flags, source);
+            }
+        }
+
+        // Reshaping options fall back to the full merge
+        let args = Args::parse_from(["ob-aggregator-rs", "--qty", "1", "--depth", "5"]);
+        assert!(LiveMerge::new(MergeOptions::from(&args)).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_metrics_endpoint_after_run() {
+        let addr = metrics::serve(0).await.unwrap();
+        let (_server, endpoints) = mock_exchanges().await;
+        let args = Args::parse_from(["ob-aggregator-rs", "--qty", "1", "--burst", "2"]);
+        let client = api_client::create_client(api_client::DEFAULT_TIMEOUT, HeaderMap::new());
+
+        run(&args, &endpoints, &client, Some(&create_rate_limiter(&args).unwrap())).await.unwrap();
+
+        let scraped = client.get(format!("http://127.0.0.1:{}/metrics", addr.port())).send().await.unwrap().text().await.unwrap();
+        for name in ["ob_fetch_duration_seconds", "ob_fetch_total", "ob_merge_duration_seconds", "ob_merged_levels"] {
+            assert!(scraped.contains(name), "{} missing from:\n{}", name, scraped);
+        }
+        assert!(scraped.contains(r#"outcome="success""#));
+    }
+
+    #[cfg(all(feature = "coinbase", feature = "gemini"))]
+    #[tokio::test]
+    async fn test_cache_used_when_fetch_fails() {
+        let dir = env::temp_dir().join(format!("ob-aggregator-cache-{}", std::process::id()));
+        let cache_dir = dir.to_str().unwrap();
+        let args = Args::parse_from(["ob-aggregator-rs", "--qty", "1", "--burst", "4", "--cache-dir", cache_dir, "--cache-stale-secs", "0"]);
+        let client = api_client::create_client(api_client::DEFAULT_TIMEOUT, HeaderMap::new());
+
+        let (server, endpoints) = mock_exchanges().await;
+        let first = run(&args, &endpoints, &client, Some(&create_rate_limiter(&args).unwrap())).await.unwrap();
+        assert!(first.cache_hits.is_empty());
+
+        // Coinbase answers 500 and Gemini 404 on the second fetch
+        server.reset().await;
+        Mock::given(method("GET")).and(path("/coinbase")).respond_with(ResponseTemplate::new(500)).mount(&server).await;
+        tokio::time::sleep(Duration::from_millis(5)).await;
+
+        let second = run(&args, &endpoints, &client, Some(&create_rate_limiter(&args).unwrap())).await.unwrap();
+
+        assert_eq!(second.cache_hits.len(), 2, "Both failed fetches should fall back to the cache");
+        assert!(second.cache_hits.iter().all(|hit| hit.stale));
+        assert_eq!(second.cache_hits[0].exchange, Exchange::Coinbase);
+        assert_eq!(second.buy_price, first.buy_price);
+
+        // A 200 that doesn't parse is left out and doesn't replace the cached book
+        server.reset().await;
+        Mock::given(method("GET"))
+            .and(path("/coinbase"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({"bids": "oops"})))
+            .mount(&server)
+            .await;
+        let third = run(&args, &endpoints, &client, Some(&create_rate_limiter(&args).unwrap())).await.unwrap();
+        assert_eq!(third.excluded[0].reason, ExclusionReason::ParseError);
+
+        server.reset().await;
+        Mock::given(method("GET")).and(path("/coinbase")).respond_with(ResponseTemplate::new(500)).mount(&server).await;
+        let fourth = run(&args, &endpoints, &client, Some(&create_rate_limiter(&args).unwrap())).await.unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(fourth.cache_hits.len(), 2);
+        assert_eq!(fourth.buy_price, first.buy_price);
+    }
+
+    #[cfg(all(feature = "coinbase", feature = "gemini"))]
+    #[tokio::test]
+    async fn test_poll_runs_each_interval() {
+        let (server, endpoints) = mock_exchanges().await;
+        let args = Args::parse_from(["ob-aggregator-rs", "--qty", "1", "--burst", "4", "--interval", "1"]);
+        let client = api_client::create_client(api_client::DEFAULT_TIMEOUT, HeaderMap::new());
+        let rate_limiter = create_rate_limiter(&args).unwrap();
+
+        // Ticks at 0s and 1s, then stops before the third
+        let mut lines = Vec::new();
+        poll(&args, &endpoints, &client, Some(&rate_limiter), Duration::from_secs(1), &cancel_after(Duration::from_millis(1500)), |output| {
+            lines.push(Quote::new(&args, output.buy_price.clone(), output.sell_price.clone()).to_string());
+            Ok(())
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(lines, ["1 BTC | buy: $100.75 | sell: $99.25"; 2]);
+        assert_eq!(server.received_requests().await.unwrap().len(), 4);
+    }
+
+    #[cfg(all(feature = "coinbase", feature = "gemini"))]
+    #[tokio::test]
+    async fn test_poll_jsonl() {
+        let (_server, endpoints) = mock_exchanges().await;
+        let args = Args::parse_from(["ob-aggregator-rs", "--qty", "1", "--interval", "1", "--format", "jsonl", "--no-rate-limit"]);
+        let client = api_client::create_client(api_client::DEFAULT_TIMEOUT, HeaderMap::new());
+
+        let mut out = Vec::new();
+        poll(&args, &endpoints, &client, None, Duration::from_secs(1), &cancel_after(Duration::from_millis(1500)), |output| {
+            write_cycle(&mut out, &args, output)
+        })
+        .await
+        .unwrap();
+
+        let out = String::from_utf8(out).unwrap();
+        let lines: Vec<Value> = out.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(lines.len(), 2);
+        for line in &lines {
+            assert_eq!(line["buy_price"], "100.75");
+            assert!(humantime::parse_rfc3339(line["timestamp"].as_str().unwrap()).is_ok());
+        }
+    }
+
+    #[cfg(all(feature = "coinbase", feature = "gemini"))]
+    fn cancel_after(delay: Duration) -> CancellationToken {
+        let token = CancellationToken::new();
+        let cancel = token.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(delay).await;
+            cancel.cancel();
+        });
+        token
+    }
+
+    #[cfg(all(feature = "coinbase", feature = "gemini"))]
+    #[tokio::test]
+    async fn test_poll_shutdown_cancels_in_flight_fetches() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({"bids": [], "asks": []})).set_delay(Duration::from_secs(30)))
+            .mount(&server)
+            .await;
+        let endpoints = Endpoints {
+            coinbase: format!("{}/coinbase", server.uri()),
+            gemini: format!("{}/gemini", server.uri()),
+            binance: None,
+            bitstamp: None,
+            api_keys: ApiKeys::default(),
+        };
+        let args = Args::parse_from(["ob-aggregator-rs", "--qty", "1", "--interval", "1", "--no-rate-limit"]);
+        let client = api_client::create_client(api_client::DEFAULT_TIMEOUT, HeaderMap::new());
+        let start = Instant::now();
+
+        let mut outputs = 0;
+        poll(&args, &endpoints, &client, None, Duration::from_secs(1), &cancel_after(Duration::from_millis(200)), |_| {
+            outputs += 1;
+            Ok(())
+        })
+        .await
+        .unwrap();
+
+        // Both requests were sent and then dropped, long before the 30s answer or the client timeout
+        assert!(start.elapsed() < Duration::from_secs(2), "Shutdown took {:?}", start.elapsed());
+        assert_eq!(server.received_requests().await.unwrap().len(), 2);
+        assert_eq!(outputs, 0);
+    }
+
+    #[cfg(feature = "gemini")]
+    #[test]
+    fn test_gemini_book_age() {
+        let orders: Vec<GeminiOrder> = serde_json::from_value(serde_json::json!([
+            {"price": "100", "amount": "1", "timestamp": "1761996296"},
+            {"price": "101", "amount": "1", "timestamp": "1761990000"},
+            {"price": "102", "amount": "1", "timestamp": "1761999999"}
+        ])).unwrap();
+
+        assert_eq!(book_age(&orders), Some((1761990000, 1761999999)));
+        assert_eq!(book_age(&[]), None);
+    }
+
+    #[test]
+    fn test_merge_shuffled_input_matches_sorted_reference() {
+        // Deterministic shuffle: 7 is coprime with 20 so every price shows up once
+        let shuffled = |source: Exchange, offset: u32| -> Vec<OrderBook> {
+            (0..20u32).map(|i| OrderBook { price: Decimal::from((i * 7) % 20 * 2 + offset), size: Decimal::ONE, source }).collect()
+        };
+        let books = || vec![shuffled(Exchange::Coinbase, 100), shuffled(Exchange::Gemini, 101)];
+
+        let mut reference: Vec<OrderBook> = books().into_iter().flatten().collect();
+        reference.sort();
+        let prices = |levels: &[OrderBook]| levels.iter().map(|level| level.price).collect::<Vec<_>>();
+
+        assert_eq!(prices(&merge_asks(books(), false, false)), prices(&reference));
+
+        reference.reverse();
+        assert_eq!(prices(&merge_bids(books(), false, false)), prices(&reference));
+    }
+
+    #[test]
+    fn test_merge_equal_prices_larger_size_first() {
+        let level = |price: u32, size: &str, source: Exchange| OrderBook { price: Decimal::from(price), size: Decimal::from_str_exact(size).unwrap(), source };
+        let coinbase = vec![level(100, "0.5", Exchange::Coinbase), level(101, "1", Exchange::Coinbase)];
+        let gemini = vec![level(100, "2", Exchange::Gemini), level(101, "1", Exchange::Gemini)];
+        let levels = |merged: Vec<OrderBook>| merged.iter().map(|level| (level.price, level.size, level.source)).collect::<Vec<_>>();
+
+        let expected = vec![
+            (Decimal::from(100), Decimal::TWO, Exchange::Gemini),
+            (Decimal::from(100), Decimal::from_str_exact("0.5").unwrap(), Exchange::Coinbase),
+            // Same price and size falls back to the exchange order
+            (Decimal::from(101), Decimal::ONE, Exchange::Coinbase),
+            (Decimal::from(101), Decimal::ONE, Exchange::Gemini),
+        ];
+        assert_eq!(levels(merge_asks(vec![coinbase.clone(), gemini.clone()], false, false)), expected);
+        assert_eq!(levels(merge_asks(vec![gemini, coinbase], false, false)), expected);
+    }
+
+    #[cfg(all(feature = "coinbase", feature = "gemini"))]
+    #[tokio::test]
+    async fn test_run_from_files() {
+        let dir = env!("CARGO_MANIFEST_DIR");
+        let coinbase_file = format!("{}/coinbase.json", dir);
+        let gemini_file = format!("{}/gemini.json", dir);
+        let args = Args::parse_from(["ob-aggregator-rs", "--qty", "1", "--coinbase-file", &coinbase_file, "--gemini-file", &gemini_file]);
+
+        // Nothing listens here, the files must be used instead
+        let endpoints = Endpoints { coinbase: "http://127.0.0.1:9/unused".to_string(), gemini: "http://127.0.0.1:9/unused".to_string(), binance: None, bitstamp: None, api_keys: ApiKeys::default() };
+        let client = api_client::create_client(api_client::DEFAULT_TIMEOUT, HeaderMap::new());
+        let start = Instant::now();
+
+        let output = run(&args, &endpoints, &client, Some(&create_rate_limiter(&args).unwrap())).await.unwrap();
+
+        assert_eq!(output.buy_price, Some(Ok(Decimal::from_str_exact("110027.96744366").unwrap())));
+        assert_eq!(output.sell_price, Some(Ok(Decimal::from_str_exact("110100.5493524960").unwrap())));
+        assert!(start.elapsed() < Duration::from_secs(1), "Files should not wait on the rate limiter");
+    }
+
+    #[cfg(all(feature = "coinbase", feature = "gemini"))]
+    #[tokio::test]
+    async fn test_no_rate_limiter_without_network() {
+        let dir = env!("CARGO_MANIFEST_DIR");
+        let coinbase_file = format!("{}/coinbase.json", dir);
+        let gemini_file = format!("{}/gemini.json", dir);
+        let templates = ApiTemplates {
+            coinbase: "http://127.0.0.1:9/unused".to_string(),
+            gemini: "http://127.0.0.1:9/unused".to_string(),
+            binance: None,
+            bitstamp: None,
+            api_keys: ApiKeys::default(),
+        };
+
+        let files = Args::parse_from(["ob-aggregator-rs", "--qty", "1", "--coinbase-file", &coinbase_file, "--gemini-file", &gemini_file]);
+        assert!(network_rate_limiter(&files, &templates).unwrap().is_none());
+        // Gemini still goes over the network
+        let one_file = Args::parse_from(["ob-aggregator-rs", "--qty", "1", "--coinbase-file", &coinbase_file]);
+        assert!(network_rate_limiter(&one_file, &templates).unwrap().is_some());
+        let opted_out = Args::parse_from(["ob-aggregator-rs", "--qty", "1", "--no-rate-limit"]);
+        assert!(network_rate_limiter(&opted_out, &templates).unwrap().is_none());
+
+        // A limiter allows one request every 2 seconds, so three back to back runs would take 4 seconds with one
+        let endpoints = Endpoints { coinbase: templates.coinbase.clone(), gemini: templates.gemini.clone(), binance: None, bitstamp: None, api_keys: ApiKeys::default() };
+        let client = api_client::create_client(api_client::DEFAULT_TIMEOUT, HeaderMap::new());
+        let rate_limiter = network_rate_limiter(&files, &templates).unwrap();
+        let start = Instant::now();
+        for _ in 0..3 {
+            run(&files, &endpoints, &client, rate_limiter.as_ref()).await.unwrap();
+        }
+        assert!(start.elapsed() < Duration::from_secs(1), "File mode should not wait on a rate limiter");
+    }
+
+    // Serves the captured coinbase.json / gemini.json books, or the given status instead for an exchange that is down
+    #[cfg(all(feature = "coinbase", feature = "gemini"))]
+    async fn fixture_exchanges(coinbase_status: u16, gemini_status: u16) -> (MockServer, Endpoints) {
+        let server = MockServer::start().await;
+        for (route, file, status) in [("/coinbase", "coinbase.json", coinbase_status), ("/gemini", "gemini.json", gemini_status)] {
+            let fixture = std::fs::read_to_string(format!("{}/{}", env!("CARGO_MANIFEST_DIR"), file)).unwrap();
+            let response = match status {
+                200 => ResponseTemplate::new(200).set_body_raw(fixture, "application/json"),
+                status => ResponseTemplate::new(status),
+            };
+            Mock::given(method("GET")).and(path(route)).respond_with(response).mount(&server).await;
+        }
+
+        let endpoints = Endpoints {
+            coinbase: format!("{}/coinbase", server.uri()),
+            gemini: format!("{}/gemini", server.uri()),
+            binance: None,
+            bitstamp: None,
+            api_keys: ApiKeys::default(),
+        };
+        (server, endpoints)
+    }
+
+    #[cfg(all(feature = "coinbase", feature = "gemini"))]
+    #[tokio::test]
+    async fn test_pipeline_with_fixture_books() {
+        let (_server, endpoints) = fixture_exchanges(200, 200).await;
+        let args = Args::parse_from(["ob-aggregator-rs", "--qty", "1", "--burst", "2"]);
+        let client = api_client::create_client(api_client::DEFAULT_TIMEOUT, HeaderMap::new());
+
+        let output = run(&args, &endpoints, &client, Some(&create_rate_limiter(&args).unwrap())).await.unwrap();
+
+        assert_eq!(output.buy_price, Some(Ok(Decimal::from_str_exact("110027.96744366").unwrap())));
+        assert_eq!(output.sell_price, Some(Ok(Decimal::from_str_exact("110100.5493524960").unwrap())));
+        assert_eq!(output.asks.map(|asks| asks.len()), Some(8));
+    }
+
+    #[cfg(all(feature = "coinbase", feature = "gemini"))]
+    #[tokio::test]
+    async fn test_pipeline_continues_when_one_exchange_fails() {
+        let (_server, endpoints) = fixture_exchanges(500, 200).await;
+        let args = Args::parse_from(["ob-aggregator-rs", "--qty", "1", "--burst", "2"]);
+        let client = api_client::create_client(api_client::DEFAULT_TIMEOUT, HeaderMap::new());
+
+        let output = run(&args, &endpoints, &client, Some(&create_rate_limiter(&args).unwrap())).await.unwrap();
+
+        // Only Gemini's levels are left
+        let asks = output.asks.unwrap();
+        assert_eq!(asks.len(), 4);
+        assert!(asks.iter().all(|level| level.source == Exchange::Gemini));
+        // 0.016117 @ 110025.98 then 0.983883 @ 110028.0
+        assert_eq!(output.buy_price, Some(Ok(Decimal::from_str_exact("110027.96744366").unwrap())));
+        // Gemini's five bids only hold 0.0407 BTC
+        assert_eq!(
+            output.sell_price,
+            Some(Err(PriceError::InsufficientLiquidity { requested: Decimal::ONE, available: Decimal::from_str_exact("0.0407").unwrap() }))
+        );
+
+        // With both down there is nothing to price
+        let (_server, endpoints) = fixture_exchanges(500, 500).await;
+        assert!(run(&args, &endpoints, &client, Some(&create_rate_limiter(&args).unwrap())).await.is_err());
+    }
+
+    #[cfg(all(feature = "coinbase", feature = "gemini"))]
+    #[tokio::test]
+    async fn test_min_exchanges() {
+        let (_server, endpoints) = fixture_exchanges(500, 200).await;
+        let args = Args::parse_from(["ob-aggregator-rs", "--qty", "1", "--min-exchanges", "2", "--no-rate-limit"]);
+        let client = api_client::create_client(api_client::DEFAULT_TIMEOUT, HeaderMap::new());
+
+        let err = run(&args, &endpoints, &client, None).await.unwrap_err();
+        assert_eq!(err.to_string(), "Only 1 of 2 exchanges returned a usable book, --min-exchanges is 2");
+
+        let (_server, endpoints) = fixture_exchanges(200, 200).await;
+        assert!(run(&args, &endpoints, &client, None).await.is_ok());
+
+        assert!(Args::try_parse_from(["ob-aggregator-rs", "--qty", "1", "--min-exchanges", "0"]).is_err());
+    }
+
+    #[test]
+    fn test_price_error_variants() {
+        assert_eq!(calculate_entity_price(&[], Decimal::ONE, true, "ASKS"), Err(PriceError::EmptyBook));
+
+        let asks = book(&[("100", "1"), ("101", "1")]);
+        assert_eq!(
+            calculate_entity_price(&asks, Decimal::from(3), true, "ASKS"),
+            Err(PriceError::InsufficientLiquidity { requested: Decimal::from(3), available: Decimal::TWO })
+        );
+
+        // 99 sits below 101 in an ascending book
+        let unsorted = book(&[("100", "1"), ("101", "1"), ("99", "1")]);
+        assert_eq!(calculate_entity_price(&unsorted, Decimal::ONE, true, "ASKS"), Err(PriceError::Unsorted { index: 2 }));
+        assert_eq!(calculate_vwap(&unsorted, Decimal::ONE, true), Err(PriceError::Unsorted { index: 2 }));
+
+        assert_eq!(calculate_entity_price(&asks, Decimal::ONE, true, "ASKS"), Ok(Decimal::from(100)));
+    }
+
+    #[test]
+    fn test_min_size_skips_dust() {
+        let books = || vec![NormalizedBook {
+            asks: book(&[("100", "0.00005"), ("100.5", "0.00005"), ("101", "0.00005"), ("105", "1")]),
+            bids: Vec::new(),
+        }];
+        let price = |options: MergeOptions, qty: &str| {
+            let (asks, _) = merge_books(options, books());
+            calculate_entity_price(&asks.unwrap(), Decimal::from_str_exact(qty).unwrap(), true, "ASKS")
+        };
+        let filtered = MergeOptions { min_size: Decimal::from_str_exact("0.0001").unwrap(), ..Default::default() };
+
+        // The full 1.00015 is only there when the dust is kept
+        assert!(price(MergeOptions::default(), "1.00015").is_ok());
+        assert_eq!(
+            price(filtered, "1.00015"),
+            Err(PriceError::InsufficientLiquidity { requested: Decimal::from_str_exact("1.00015").unwrap(), available: Decimal::ONE })
+        );
+
+        // Without the dust a single BTC is priced entirely at the large level
+        assert_eq!(price(filtered, "1"), Ok(Decimal::from(105)));
+        assert_ne!(price(MergeOptions::default(), "1"), Ok(Decimal::from(105)));
+    }
+
+    #[tokio::test]
+    async fn test_parallel_merge_matches_sequential() {
+        let books = || {
+            let level = |i: u32, source| OrderBook { price: Decimal::from(i % 97 + 1000), size: Decimal::from(i % 7 + 1), source };
+            vec![
+                NormalizedBook { asks: (0..500).map(|i| level(i, Exchange::Coinbase)).collect(), bids: (0..300).map(|i| level(i, Exchange::Coinbase)).collect() },
+                NormalizedBook { asks: (0..400).map(|i| level(i * 3, Exchange::Gemini)).collect(), bids: (0..600).map(|i| level(i * 5, Exchange::Gemini)).collect() },
+            ]
+        };
+        let levels = |side: Option<Vec<OrderBook>>| side.unwrap().iter().map(|level| (level.price, level.size, level.source)).collect::<Vec<_>>();
+
+        for options in [MergeOptions::default(), MergeOptions { consolidate: true, depth: Some(50), ..Default::default() }] {
+            let (asks, bids) = merge_books(options, books());
+            let (parallel_asks, parallel_bids) = merge_books_parallel(options, books()).await.unwrap();
+            assert_eq!(levels(parallel_asks), levels(asks));
+            assert_eq!(levels(parallel_bids), levels(bids));
+        }
+    }
+
+    #[test]
+    fn test_assume_sorted_matches_sorting_merge() {
+        let books = || vec![
+            sourced_book(Exchange::Coinbase, &[100, 102, 104, 104, 110]),
+            sourced_book(Exchange::Gemini, &[101, 102, 103]),
+            sourced_book(Exchange::Binance, &[99, 120]),
+        ];
+        let levels = |merged: Vec<OrderBook>| merged.iter().map(|level| (level.price, level.source)).collect::<Vec<_>>();
+        assert_eq!(levels(merge_asks(books(), false, true)), levels(merge_asks(books(), false, false)));
+
+        let reversed = || books().into_iter().map(|mut book| { book.reverse(); book }).collect::<Vec<_>>();
+        assert_eq!(levels(merge_bids(reversed(), false, true)), levels(merge_bids(reversed(), false, false)));
+    }
+
+    #[cfg(all(feature = "coinbase", feature = "gemini"))]
+    #[tokio::test]
+    async fn test_run_symbols_prices_each_pair() {
+        let server = MockServer::start().await;
+        for (route, ask) in [("/coinbase/BTC-USD", "101"), ("/coinbase/ETH-USD", "3001")] {
+            Mock::given(method("GET"))
+                .and(path(route))
+                .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                    "bids": [], "asks": [[ask, "5", 1]], "sequence": 1, "auction_mode": false, "auction": null, "time": "2025-11-01T00:00:00Z"
+                })))
+                .mount(&server)
+                .await;
+        }
+        for (route, ask) in [("/gemini/btcusd", "100"), ("/gemini/ethusd", "3000")] {
+            Mock::given(method("GET"))
+                .and(path(route))
+                .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                    "bids": [], "asks": [{"price": ask, "amount": "0.5", "timestamp": "1761996296"}]
+                })))
+                .mount(&server)
+                .await;
+        }
+
+        let templates = ApiTemplates {
+            coinbase: format!("{}/coinbase/{{symbol}}", server.uri()),
+            gemini: format!("{}/gemini/{{symbol}}", server.uri()),
+            binance: None,
+            bitstamp: None,
+            api_keys: ApiKeys::default(),
+        };
+        let args = Args::parse_from(["ob-aggregator-rs", "--qty", "1", "--side", "buy", "--burst", "4", "--symbols", "BTC-USD,ETH-USD"]);
+        let client = api_client::create_client(api_client::DEFAULT_TIMEOUT, HeaderMap::new());
+
+        let results = run_symbols(&args, &templates, &client, Some(&create_rate_limiter(&args).unwrap())).await;
+
+        let prices: Vec<(String, Option<Result<Decimal, PriceError>>)> = results
+            .into_iter()
+            .map(|(symbol_args, output)| (symbol_args.symbol, output.unwrap().buy_price))
+            .collect();
+        assert_eq!(prices, vec![
+            ("BTC-USD".to_string(), Some(Ok(Decimal::from_str_exact("100.5").unwrap()))),
+            ("ETH-USD".to_string(), Some(Ok(Decimal::from_str_exact("3000.5").unwrap()))),
+        ]);
+    }
+
+    #[test]
+    fn test_apply_fee_bps() {
+        let notional = Decimal::from(100_000);
+        let bps = Decimal::from(10);
+
+        // 10 bps of $100,000 is $100
+        assert_eq!(apply_fee(notional, bps, true), Ok(Decimal::from(100_100)));
+        assert_eq!(apply_fee(notional, bps, false), Ok(Decimal::from(99_900)));
+        assert_eq!(apply_fee(notional, Decimal::ZERO, true), Ok(notional));
+        assert_eq!(apply_fee(Decimal::MAX, Decimal::from(10_000), true), Err(PriceError::Overflow));
+
+        assert!(Args::try_parse_from(["ob-aggregator-rs", "--qty", "1", "--fee-bps", "0"]).is_ok());
+        assert!(Args::try_parse_from(["ob-aggregator-rs", "--qty", "1", "--fee-bps=-5"]).is_err());
+        assert!(Args::try_parse_from(["ob-aggregator-rs", "--qty", "1", "--fee-bps", "10001"]).is_err());
+    }
+
+    #[test]
+    fn test_fill_breakdown_sums_to_total() {
+        let level = |price: &str, size: &str, source| OrderBook {
+            price: Decimal::from_str_exact(price).unwrap(),
+            size: Decimal::from_str_exact(size).unwrap(),
+            source,
+        };
+        let asks = merge_asks(vec![
+            vec![level("100", "0.5", Exchange::Coinbase), level("102", "1", Exchange::Coinbase)],
+            vec![level("101", "0.25", Exchange::Gemini), level("103", "5", Exchange::Gemini)],
+        ], false, false);
+        let qty = Decimal::TWO;
+
+        let breakdown = calculate_fill_breakdown(&asks, qty, true).unwrap();
+
+        assert_eq!(breakdown[&Exchange::Coinbase], (Decimal::from_str_exact("1.5").unwrap(), Decimal::from(152)));
+        assert_eq!(breakdown[&Exchange::Gemini], (Decimal::from_str_exact("0.5").unwrap(), Decimal::from(51)));
+        assert!(!breakdown.contains_key(&Exchange::Binance));
+
+        let (filled, notional) = breakdown.values().fold((Decimal::ZERO, Decimal::ZERO), |(q, n), (sq, sn)| (q + sq, n + sn));
+        assert_eq!(filled, qty);
+        assert_eq!(Ok(notional), calculate_entity_price(&asks, qty, true, "ASKS"));
+    }
+
+    #[test]
+    fn test_price_overflow_is_an_error() {
+        let asks = vec![
+            OrderBook { price: Decimal::MAX, size: Decimal::from(2), source: Exchange::Coinbase },
+        ];
+        assert_eq!(calculate_entity_price(&asks, Decimal::from(2), true, "BUY"), Err(PriceError::Overflow));
+
+        let near_max = Decimal::MAX - Decimal::from(1);
+        let asks = vec![
+            OrderBook { price: near_max, size: Decimal::from(1), source: Exchange::Coinbase },
+            OrderBook { price: near_max, size: Decimal::from(1), source: Exchange::Gemini },
+        ];
+        assert_eq!(calculate_entity_price(&asks, Decimal::from(2), true, "BUY"), Err(PriceError::Overflow));
+        assert_eq!(calculate_vwap(&asks, Decimal::from(2), true), Err(PriceError::Overflow));
+        assert_eq!(calculate_entity_price(&asks, Decimal::from(1), true, "BUY"), Ok(near_max));
+    }
+
+    #[test]
+    fn test_book_table_aligns_columns() {
+        let asks = vec![
+            OrderBook { price: Decimal::from_str_exact("99.5").unwrap(), size: Decimal::ONE, source: Exchange::Gemini },
+            OrderBook { price: Decimal::from_str_exact("100.25").unwrap(), size: Decimal::from_str_exact("10.5").unwrap(), source: Exchange::Coinbase },
+            OrderBook { price: Decimal::from(1000), size: Decimal::ONE, source: Exchange::Coinbase },
+        ];
+        let bids = vec![
+            OrderBook { price: Decimal::from(99), size: Decimal::from_str_exact("0.125").unwrap(), source: Exchange::Coinbase },
+        ];
+
+        let lines = book_table(&asks, &bids, 2);
+        assert_eq!(lines, vec![
+            "ASK PRICE  ASK CUM SIZE | BID PRICE  BID CUM SIZE",
+            "     99.5             1 |        99         0.125",
+            "   100.25          11.5 |                        ",
+        ]);
+
+        // Every row has the same width and the column separator sits in the same place
+        assert!(lines.iter().all(|line| line.len() == lines[0].len()));
+        assert!(lines.iter().all(|line| line.find('|') == lines[0].find('|')));
+    }
+
+    #[test]
+    fn test_is_crossed() {
+        let asks = book(&[("101", "1"), ("102", "1")]);
+        let bids = book(&[("99", "1"), ("98", "1")]);
+        assert!(!is_crossed(&asks, &bids));
+        assert!(!is_crossed(&asks, &[]));
+        assert!(!is_crossed(&[], &bids));
+
+        let crossed_bids = book(&[("101.5", "1"), ("99", "1")]);
+        assert!(is_crossed(&asks, &crossed_bids));
+        // A locked book is treated as crossed too
+        assert!(is_crossed(&asks, &book(&[("101", "1")])));
+    }
+
+    #[cfg(all(feature = "coinbase", feature = "gemini"))]
+    #[tokio::test]
+    async fn test_strict_rejects_crossed_book() {
+        // The captured Gemini bids sit above the Coinbase asks
+        let (_server, endpoints) = fixture_exchanges(200, 200).await;
+        let args = Args::parse_from(["ob-aggregator-rs", "--qty", "1", "--burst", "2", "--strict"]);
+        let client = api_client::create_client(api_client::DEFAULT_TIMEOUT, HeaderMap::new());
+
+        let err = run(&args, &endpoints, &client, Some(&create_rate_limiter(&args).unwrap())).await.unwrap_err();
+        assert!(err.to_string().contains("crossed"));
+    }
+
+    #[cfg(all(feature = "coinbase", feature = "gemini"))]
+    #[tokio::test]
+    async fn test_requests_carry_user_agent_and_api_keys() {
+        let (server, mut endpoints) = mock_exchanges().await;
+        endpoints.api_keys.coinbase = Some(HeaderValue::from_static("cb-key"));
+        let args = Args::parse_from(["ob-aggregator-rs", "--qty", "1", "--burst", "2", "--header", "X-Desk: rates"]);
+        let client = api_client::create_client(api_client::DEFAULT_TIMEOUT, args.headers.iter().cloned().collect());
+
+        run(&args, &endpoints, &client, Some(&create_rate_limiter(&args).unwrap())).await.unwrap();
+
+        let requests = server.received_requests().await.unwrap();
+        assert_eq!(requests.len(), 2);
+        for request in &requests {
+            assert_eq!(request.headers.get("user-agent").unwrap(), api_client::USER_AGENT);
+            assert!(api_client::USER_AGENT.starts_with(concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"))));
+            assert_eq!(request.headers.get("x-desk").unwrap(), "rates");
+
+            // The Coinbase key must never reach Gemini
+            let key = request.headers.get("cb-access-key");
+            match request.url.path() {
+                "/coinbase" => assert_eq!(key.unwrap(), "cb-key"),
+                _ => assert!(key.is_none()),
+            }
+        }
+    }
+
+    #[test]
+    fn test_parse_header() {
+        let (name, value) = api_client::parse_header("User-Agent:  my-desk/1.0 ").unwrap();
+        assert_eq!(name, "user-agent");
+        assert_eq!(value, "my-desk/1.0");
+
+        assert!(api_client::parse_header("no separator").is_err());
+        assert!(api_client::parse_header("bad name: value").is_err());
+    }
+
+    #[cfg(all(feature = "coinbase", feature = "gemini"))]
+    #[tokio::test]
+    async fn test_output_identifies_coinbase_snapshot() {
+        let (server, endpoints) = mock_exchanges().await;
+        let args = Args::parse_from(["ob-aggregator-rs", "--qty", "1", "--burst", "2"]);
+        let client = api_client::create_client(api_client::DEFAULT_TIMEOUT, HeaderMap::new());
+
+        let output = run(&args, &endpoints, &client, Some(&create_rate_limiter(&args).unwrap())).await.unwrap();
+        assert_eq!(output.coinbase_sequence, Some(1));
+        assert_eq!(output.coinbase_time.as_deref(), Some("2025-11-01T00:00:00Z"));
+
+        let json = serde_json::to_value(json_quote(&args, &output)).unwrap();
+        assert_eq!(json["coinbase_sequence"], 1);
+        assert_eq!(json["coinbase_time"], "2025-11-01T00:00:00Z");
+
+        // Nothing to report when the Coinbase book wasn't priced, even if the response had a sequence
+        server.reset().await;
+        Mock::given(method("GET"))
+            .and(path("/coinbase"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({"bids": "oops", "sequence": 2, "time": "2025-11-01T00:00:01Z"})))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/gemini"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({"bids": [], "asks": [{"price": "100.5", "amount": "1", "timestamp": "1761996296"}]})))
+            .mount(&server)
+            .await;
+        let output = run(&args, &endpoints, &client, Some(&create_rate_limiter(&args).unwrap())).await.unwrap();
+        assert_eq!(output.coinbase_sequence, None);
+    }
+
+    #[cfg(all(feature = "coinbase", feature = "gemini"))]
+    #[tokio::test]
+    async fn test_max_age_drops_stale_books() {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/coinbase"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "bids": [["99", "1", 1]],
+                "asks": [["101", "1", 1]],
+                "sequence": 1,
+                "auction_mode": false,
+                "auction": null,
+                "time": humantime::format_rfc3339_micros(SystemTime::now()).to_string()
+            })))
+            .mount(&server)
+            .await;
+        // Better prices, but nothing on the Gemini book has moved for an hour
+        let stale = (now - 3600).to_string();
+        Mock::given(method("GET"))
+            .and(path("/gemini"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "bids": [{"price": "100", "amount": "1", "timestamp": stale}],
+                "asks": [{"price": "100.5", "amount": "1", "timestamp": stale}]
+            })))
+            .mount(&server)
+            .await;
+        let endpoints = Endpoints {
+            coinbase: format!("{}/coinbase", server.uri()),
+            gemini: format!("{}/gemini", server.uri()),
+            binance: None,
+            bitstamp: None,
+            api_keys: ApiKeys::default(),
+        };
+        let client = api_client::create_client(api_client::DEFAULT_TIMEOUT, HeaderMap::new());
+
+        let args = Args::parse_from(["ob-aggregator-rs", "--qty", "1", "--burst", "2", "--max-age", "60"]);
+        let output = run(&args, &endpoints, &client, Some(&create_rate_limiter(&args).unwrap())).await.unwrap();
+        assert!(output.asks.unwrap().iter().all(|level| level.source == Exchange::Coinbase));
+        assert!(output.bids.unwrap().iter().all(|level| level.source == Exchange::Coinbase));
+        assert_eq!(output.buy_price, Some(Ok(Decimal::from(101))));
+
+        // Without the gate Gemini's stale levels are used
+        let args = Args::parse_from(["ob-aggregator-rs", "--qty", "1", "--burst", "2"]);
+        let output = run(&args, &endpoints, &client, Some(&create_rate_limiter(&args).unwrap())).await.unwrap();
+        assert_eq!(output.buy_price, Some(Ok(Decimal::from_str_exact("100.5").unwrap())));
+    }
+
+    // An exchange with its own response format, to check the pipeline only goes through ExchangeAdapter
+    struct FakeExchange {
+        base_url: String,
+    }
+
+    impl ExchangeAdapter for FakeExchange {
+        fn name(&self) -> Exchange {
+            Exchange::Binance
+        }
+
+        fn endpoint(&self, symbol: &str) -> String {
+            format!("{}/fake/{}", self.base_url, symbol)
+        }
+
+        // {"levels": [[side, price, size], ..]} in no particular order
+        fn parse(&self, data: Value, _strict: bool) -> Result<(Vec<OrderBook>, Vec<OrderBook>)> {
+            let (mut asks, mut bids) = (Vec::new(), Vec::new());
+            for level in data["levels"].as_array().into_iter().flatten() {
+                let parse = |i: usize| Decimal::from_str_exact(level[i].as_str().unwrap_or_default());
+                let order = OrderBook { price: parse(1)?, size: parse(2)?, source: self.name() };
+                match level[0].as_str() {
+                    Some("ask") => asks.push(order),
+                    _ => bids.push(order),
+                }
+            }
+            asks.sort_by_key(|level| level.price);
+            bids.sort_by_key(|level| std::cmp::Reverse(level.price));
+            Ok((asks, bids))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_pipeline_with_fake_exchange() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/fake/ETH-USD"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "levels": [["ask", "2001", "1"], ["bid", "1999", "2"], ["ask", "2000", "0.5"], ["bid", "1998", "1"]]
+            })))
+            .mount(&server)
+            .await;
+        let exchanges: Vec<Box<dyn ExchangeAdapter>> = vec![Box::new(FakeExchange { base_url: server.uri() })];
+        let args = Args::parse_from(["ob-aggregator-rs", "--qty", "1", "--symbol", "ETH-USD", "--burst", "2"]);
+        let client = api_client::create_client(api_client::DEFAULT_TIMEOUT, HeaderMap::new());
+
+        let output = run_exchanges(&args, &exchanges, &ApiKeys::default(), &client, Some(&create_rate_limiter(&args).unwrap()), None, None).await.unwrap();
+
+        // 0.5 @ 2000 then 0.5 @ 2001
+        assert_eq!(output.buy_price, Some(Ok(Decimal::from_str_exact("2000.5").unwrap())));
+        assert_eq!(output.sell_price, Some(Ok(Decimal::from(1999))));
+        assert_eq!(output.asks.unwrap().len(), 2);
+        assert_eq!(output.coinbase_sequence, None);
+    }
+
+    #[test]
+    fn test_detect_outlier() {
+        let dec = |s: &str| Decimal::from_str_exact(s).unwrap();
+        let books = [(Exchange::Coinbase, dec("100")), (Exchange::Gemini, dec("100.5")), (Exchange::Binance, dec("110"))];
+
+        // The median is Gemini's 100.5, which Binance is about 9.5% above
+        assert_eq!(detect_outlier(&books, dec("5")), vec![Exchange::Binance]);
+        assert!(detect_outlier(&books, dec("10")).is_empty());
+        // Two books 10% apart are each about 4.8% from their midpoint, so there is no telling which one is off
+        assert_eq!(detect_outlier(&books[1..], dec("4")), vec![Exchange::Gemini, Exchange::Binance]);
+        assert!(detect_outlier(&books[..1], dec("0.01")).is_empty());
+    }
+
+    #[cfg(all(feature = "coinbase", feature = "gemini"))]
+    #[tokio::test]
+    async fn test_reject_outliers() {
+        let (server, endpoints) = mock_exchanges().await;
+        // Around 10% above the other two on both sides
+        Mock::given(method("GET"))
+            .and(path("/fake/BTC-USD"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({"levels": [["ask", "111", "1"], ["bid", "109", "1"]]})))
+            .mount(&server)
+            .await;
+        let client = api_client::create_client(api_client::DEFAULT_TIMEOUT, HeaderMap::new());
+        let mut exchanges = endpoints.exchanges(&Args::parse_from(["ob-aggregator-rs"]));
+        exchanges.push(Box::new(FakeExchange { base_url: server.uri() }));
+
+        // Flagged, but still merged
+        let args = Args::parse_from(["ob-aggregator-rs", "--qty", "1", "--no-rate-limit"]);
+        let output = run_exchanges(&args, &exchanges, &ApiKeys::default(), &client, None, None, None).await.unwrap();
+        assert!(output.excluded.is_empty());
+        assert!(output.asks.unwrap().iter().any(|level| level.source == Exchange::Binance));
+
+        let args = Args::parse_from(["ob-aggregator-rs", "--qty", "1", "--no-rate-limit", "--reject-outliers", "5"]);
+        let output = run_exchanges(&args, &exchanges, &ApiKeys::default(), &client, None, None, None).await.unwrap();
+        let excluded: Vec<_> = output.excluded.iter().map(|exclusion| (exclusion.exchange, exclusion.reason)).collect();
+        assert_eq!(excluded, vec![(Exchange::Binance, ExclusionReason::Outlier)]);
+        assert!(output.asks.unwrap().iter().all(|level| level.source != Exchange::Binance));
+        // 0.5 @ 100.5 then 0.5 @ 101
+        assert_eq!(output.buy_price, Some(Ok(Decimal::from_str_exact("100.75").unwrap())));
+        assert_eq!(output.coinbase_sequence, Some(1));
+
+        // A dropped Coinbase book has no snapshot to report
+        server.reset().await;
+        Mock::given(method("GET"))
+            .and(path("/coinbase"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "bids": [["89", "1", 1]],
+                "asks": [["91", "1", 1]],
+                "sequence": 2,
+                "auction_mode": false,
+                "auction": null,
+                "time": "2025-11-01T00:00:01Z"
+            })))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/gemini"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "bids": [{"price": "99.5", "amount": "1", "timestamp": "1761996296"}],
+                "asks": [{"price": "100.5", "amount": "1", "timestamp": "1761996296"}]
+            })))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/fake/BTC-USD"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({"levels": [["ask", "101", "1"], ["bid", "99", "1"]]})))
+            .mount(&server)
+            .await;
+        let output = run_exchanges(&args, &exchanges, &ApiKeys::default(), &client, None, None, None).await.unwrap();
+        assert_eq!(output.excluded.iter().map(|exclusion| exclusion.exchange).collect::<Vec<_>>(), vec![Exchange::Coinbase]);
+        assert_eq!(output.coinbase_sequence, None);
+        assert_eq!(output.coinbase_time, None);
+    }
+
+    #[cfg(all(feature = "coinbase", feature = "gemini"))]
+    #[tokio::test]
+    async fn test_reject_outliers_keeps_both_of_two_books() {
+        let (server, endpoints) = mock_exchanges().await;
+        // Coinbase around 10% above Gemini on both sides
+        server.reset().await;
+        Mock::given(method("GET"))
+            .and(path("/coinbase"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "bids": [["109", "1", 1]],
+                "asks": [["111", "1", 1]],
+                "sequence": 1,
+                "auction_mode": false,
+                "auction": null,
+                "time": "2025-11-01T00:00:00Z"
+            })))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/gemini"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "bids": [{"price": "99.5", "amount": "1", "timestamp": "1761996296"}],
+                "asks": [{"price": "100.5", "amount": "1", "timestamp": "1761996296"}]
+            })))
+            .mount(&server)
+            .await;
+        let args = Args::parse_from(["ob-aggregator-rs", "--qty", "1", "--no-rate-limit", "--reject-outliers", "1"]);
+        let client = api_client::create_client(api_client::DEFAULT_TIMEOUT, HeaderMap::new());
+
+        let output = run(&args, &endpoints, &client, None).await.unwrap();
+        assert!(output.excluded.is_empty());
+        assert_eq!(output.buy_price, Some(Ok(Decimal::from_str_exact("100.5").unwrap())));
+        assert_eq!(output.sell_price, Some(Ok(Decimal::from(109))));
+    }
+
+    #[cfg(all(feature = "coinbase", feature = "gemini"))]
+    #[test]
+    fn test_check_config() {
+        let templates = |coinbase: &str| ApiTemplates {
+            coinbase: coinbase.to_string(),
+            gemini: "https://api.gemini.com/v1/book/{symbol}".to_string(),
+            binance: None,
+            bitstamp: None,
+            api_keys: ApiKeys::default(),
+        };
+        let args = Args::parse_from(["ob-aggregator-rs", "--qty", "1", "--check"]);
+
+        assert!(check_config(&args, Ok(templates("https://api.exchange.coinbase.com/products/{symbol}/book?level=2"))).is_ok());
+
+        let err = check_config(&args, Ok(templates("api.exchange.coinbase.com/products/{symbol}/book"))).unwrap_err();
+        assert_eq!(err.to_string(), "COINBASE URL api.exchange.coinbase.com/products/BTC-USD/book is not valid");
+
+        let err = check_config(&args, Ok(templates("ftp://api.exchange.coinbase.com/{symbol}"))).unwrap_err();
+        assert!(err.to_string().contains("must be http or https"), "{}", err);
+
+        // A missing variable is reported as it would be on a real run
+        let err = check_config(&args, Err(anyhow::anyhow!("COINBASE_API not set"))).unwrap_err();
+        assert_eq!(err.to_string(), "COINBASE_API not set");
+    }
+
+    #[test]
+    fn test_parse_qty_errors() {
+        assert_eq!(parse_qty("0"), Err("Value cannot be zero, pass --allow-zero to price an empty order".to_string()));
+        assert_eq!(parse_qty("-1"), Err("Value cannot be negative".to_string()));
+        assert_eq!(parse_qty("inf"), Err("Value must be finite".to_string()));
+        assert_eq!(parse_qty("abc"), Err("Not a valid quantity abc. Error : Failed to parse".to_string()));
+        assert_eq!(parse_qty("1.50"), Ok(Decimal::from_str_exact("1.50").unwrap()));
+
+        // Only --qty takes a zero, and main still requires --allow-zero for it
+        assert_eq!(parse_qty_or_zero("0.0"), Ok(Decimal::ZERO));
+        assert_eq!(parse_qty_or_zero("-1"), Err("Value cannot be negative".to_string()));
+        assert!(Args::try_parse_from(["ob-aggregator-rs", "--quote-amount", "0"]).is_err());
+        let args = Args::try_parse_from(["ob-aggregator-rs", "--qty", "0", "--allow-zero"]).unwrap();
+        assert!(args.qty.is_zero() && args.allow_zero);
+    }
+
+    #[test]
+    fn test_qty_keeps_every_digit() {
+        let args = Args::parse_from(["ob-aggregator-rs", "--qty", "0.000000001"]);
+        assert_eq!(args.qty, Decimal::new(1, 9));
+        assert_eq!(args.qty.to_string(), "0.000000001");
+
+        // More digits than an f64 holds
+        let args = Args::parse_from(["ob-aggregator-rs", "--qty", "12345678901234.567890123456"]);
+        assert_eq!(args.qty.to_string(), "12345678901234.567890123456");
+
+        assert_eq!(parse_qty("2.5e-3"), Ok(Decimal::new(25, 4)));
+        assert_eq!(parse_qty("-0.000000001"), Err("Value cannot be negative".to_string()));
+    }
+
+    // Collects formatted log lines for the tests that look at logging
+    #[cfg(all(feature = "coinbase", feature = "gemini"))]
+    #[derive(Clone, Default)]
+    struct CapturedLogs(Arc<std::sync::Mutex<Vec<u8>>>);
+
+    #[cfg(all(feature = "coinbase", feature = "gemini"))]
+    impl std::io::Write for CapturedLogs {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[cfg(all(feature = "coinbase", feature = "gemini"))]
+    #[tokio::test]
+    async fn test_fetch_logs_carry_the_exchange() {
+        let logs = CapturedLogs::default();
+        let writer = logs.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_max_level(tracing::Level::DEBUG)
+            .with_ansi(false)
+            .with_writer(move || writer.clone())
+            .finish();
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let (_server, endpoints) = mock_exchanges().await;
+        let args = Args::parse_from(["ob-aggregator-rs", "--qty", "1", "--burst", "2"]);
+        let client = api_client::create_client(api_client::DEFAULT_TIMEOUT, HeaderMap::new());
+        run(&args, &endpoints, &client, Some(&create_rate_limiter(&args).unwrap())).await.unwrap();
+
+        let output = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+        let line = |needle: &str| output.lines().find(|line| line.contains(needle)).unwrap_or_else(|| panic!("no {} in\n{}", needle, output)).to_string();
+        assert!(line("/coinbase finished in").contains("fetch{exchange=coinbase}"));
+        assert!(line("/gemini finished in").contains("fetch{exchange=gemini}"));
+        assert!(line("COINBASE bids: 2").contains("parse{exchange=coinbase}"));
+    }
+
+    #[test]
+    fn test_depth_to_price() {
+        let asks = book(&[("100", "1"), ("101", "0.5"), ("102", "2"), ("105", "3")]);
+        assert_eq!(depth_to_price(&asks, Decimal::from_str_exact("101.5").unwrap(), true), Decimal::from_str_exact("1.5").unwrap());
+        assert_eq!(depth_to_price(&asks, Decimal::from(102), true), Decimal::from_str_exact("3.5").unwrap());
+        assert_eq!(depth_to_price(&asks, Decimal::from(99), true), Decimal::ZERO);
+        assert_eq!(depth_to_price(&[], Decimal::from(101), true), Decimal::ZERO);
+
+        let bids = book(&[("99", "1"), ("98", "2"), ("97", "4")]);
+        assert_eq!(depth_to_price(&bids, Decimal::from(98), false), Decimal::from(3));
+        assert_eq!(depth_to_price(&bids, Decimal::from(100), false), Decimal::ZERO);
+    }
+
+    #[test]
+    fn test_simulate_limit() {
+        let asks = book(&[("100", "1"), ("101", "1"), ("102", "1"), ("103", "1")]);
+
+        // 101.5 crosses the first two levels only
+        let (filled, resting, notional) = simulate_limit(&asks, Decimal::from_str_exact("101.5").unwrap(), Decimal::from(3), true).unwrap();
+        assert_eq!((filled, resting, notional), (Decimal::from(2), Decimal::ONE, Decimal::from(201)));
+
+        // Fully filled inside the limit
+        assert_eq!(
+            simulate_limit(&asks, Decimal::from(103), Decimal::from_str_exact("1.5").unwrap(), true),
+            Ok((Decimal::from_str_exact("1.5").unwrap(), Decimal::ZERO, Decimal::from_str_exact("150.5").unwrap()))
+        );
+
+        // Nothing crosses, or there is no book at all
+        assert_eq!(simulate_limit(&asks, Decimal::from(99), Decimal::ONE, true), Ok((Decimal::ZERO, Decimal::ONE, Decimal::ZERO)));
+        assert_eq!(simulate_limit(&[], Decimal::from(99), Decimal::ONE, true), Ok((Decimal::ZERO, Decimal::ONE, Decimal::ZERO)));
+
+        let bids = book(&[("99", "1"), ("98", "1"), ("97", "1")]);
+        assert_eq!(simulate_limit(&bids, Decimal::from(98), Decimal::from(5), false), Ok((Decimal::from(2), Decimal::from(3), Decimal::from(197))));
+    }
+
+    #[test]
+    fn test_empty_side_never_quotes_zero() {
+        let args = Args::parse_from(["ob-aggregator-rs", "--qty", "1"]);
+        let buy = calculate_entity_price(&[], Decimal::ONE, true, "ASKS");
+        assert_eq!(buy, Err(PriceError::EmptyBook));
+
+        let thin = calculate_entity_price(&book(&[("100", "0.5")]), Decimal::ONE, true, "ASKS");
+        assert_eq!(Quote::new(&args, Some(buy), None).to_string(), "1 BTC | buy: empty book | sell: -");
+        assert_eq!(Quote::new(&args, Some(thin), Some(Ok(Decimal::from(99)))).to_string(), "1 BTC | buy: insufficient liquidity | sell: $99.00");
+    }
+
+    #[test]
+    fn test_currency_label() {
+        assert_eq!(symbol::currency_symbol("EUR"), "€");
+        assert_eq!(symbol::currency_symbol("USDT"), "USDT");
+
+        let args = Args::parse_from(["ob-aggregator-rs", "--symbol", "BTC-EUR"]);
+        assert_eq!(currency_symbol(&args), "€");
+        assert_eq!(Quote::new(&args, Some(Ok(Decimal::from(1234))), None).to_string(), "10.0 BTC | buy: €1,234.00 | sell: -");
+
+        let args = Args::parse_from(["ob-aggregator-rs", "--symbol", "BTC-USDT"]);
+        assert_eq!(format_money(Decimal::from_str_exact("-1234.5").unwrap(), &currency_symbol(&args), args.price_dp, RoundingStrategy::MidpointAwayFromZero, &number_locale(&args)), "-USDT 1,234.50");
+
+        // --currency overrides the pair
+        let args = Args::parse_from(["ob-aggregator-rs", "--currency", "gbp"]);
+        assert_eq!(currency_symbol(&args), "£");
+        assert_eq!(currency_code(&args), "GBP");
+    }
+
+    #[test]
+    fn test_jittered_wait_stays_in_range() {
+        use rand::SeedableRng;
+        let mut rng = rand::rngs::StdRng::seed_from_u64(7);
+        let wait = Duration::from_millis(1000);
+
+        let waits: Vec<Duration> = (0..1000).map(|_| rate_limiter::jittered_wait(wait, 0.25, &mut rng)).collect();
+        assert!(waits.iter().all(|w| *w >= wait && *w < Duration::from_millis(1250)));
+        // Spread across the range rather than stuck at one end
+        let mean = waits.iter().sum::<Duration>() / waits.len() as u32;
+        assert!(mean > Duration::from_millis(1100) && mean < Duration::from_millis(1150), "mean {:?}", mean);
+        assert!(waits.iter().any(|w| *w < Duration::from_millis(1025)) && waits.iter().any(|w| *w > Duration::from_millis(1225)));
+
+        assert_eq!(rate_limiter::jittered_wait(wait, 0.0, &mut rng), wait);
+    }
+
+    #[tokio::test]
+    async fn test_jittered_limiter_still_limits() {
+        let limiter = RateLimiter::new_with_jitter(1, Duration::from_millis(100), 0.5).unwrap();
+        let start = Instant::now();
+        limiter.acquire().await;
+        limiter.acquire().await;
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(100) && elapsed < Duration::from_millis(250), "elapsed {:?}", elapsed);
+        assert!(parse_jitter("1.5").is_err());
+    }
+
+    #[test]
+    fn test_order_book_display() {
+        let level = OrderBook { price: Decimal::from_str_exact("110025.98").unwrap(), size: Decimal::from_str_exact("0.016117").unwrap(), source: Exchange::Gemini };
+        assert_eq!(level.to_string(), "110025.98 @ 0.016117 (GEMINI)");
+    }
+
+    #[cfg(all(feature = "coinbase", feature = "gemini"))]
+    #[test]
+    fn test_best_level_matches_full_merge() {
+        let (coinbase, gemini) = sample_books();
+        let books: Vec<NormalizedBook> = vec![coinbase.into(), gemini.into()];
+        let (ask_books, bid_books) = split_sides(books);
+        let best_ask = best_level(&ask_books, true);
+        let best_bid = best_level(&bid_books, false);
+
+        let (coinbase, gemini) = sample_books();
+        let (asks, bids) = merge_books(MergeOptions::default(), vec![coinbase.into(), gemini.into()]);
+        assert_eq!(best_ask.as_ref(), asks.as_ref().and_then(|asks| asks.first()));
+        assert_eq!(best_bid.as_ref(), bids.as_ref().and_then(|bids| bids.first()));
+        assert_eq!(best_ask.map(|level| level.price), Some(Decimal::from(101)));
+        assert_eq!(best_bid.map(|level| level.price), Some(Decimal::from(99)));
+
+        // A tie on price goes to the larger size, then the exchange order, like the merge does
+        let level = |size, source| OrderBook { price: Decimal::ONE, size, source };
+        let tied = vec![vec![level(Decimal::ONE, Exchange::Gemini)], vec![level(Decimal::TWO, Exchange::Coinbase)]];
+        for is_ascending in [true, false] {
+            let merged = if is_ascending { merge_asks(tied.clone(), false, false) } else { merge_bids(tied.clone(), false, false) };
+            assert_eq!(best_level(&tied, is_ascending).as_ref(), merged.first());
+            assert_eq!(best_level(&tied, is_ascending).map(|level| level.source), Some(Exchange::Coinbase));
+        }
+        let same_size = vec![vec![level(Decimal::ONE, Exchange::Gemini)], vec![level(Decimal::ONE, Exchange::Coinbase)]];
+        assert_eq!(best_level(&same_size, true).as_ref(), merge_asks(same_size.clone(), false, false).first());
+        assert_eq!(best_level(&[], true), None);
+    }
+
+    #[cfg(all(feature = "coinbase", feature = "gemini"))]
+    #[test]
+    fn test_strict_precision() {
+        use crate::helpers::types;
+        // 40 significant digits, a dozen more than a Decimal holds
+        let long = "1234.567890123456789012345678901234567891";
+        let err = types::parse_decimal(long, true).unwrap_err();
+        assert!(err.contains("more digits than a Decimal holds"), "{}", err);
+        let rounded = types::parse_decimal(long, false).unwrap();
+        assert_eq!(rounded.to_string(), "1234.5678901234567890123456789");
+
+        // Trailing zeros and ordinary prices lose nothing
+        assert_eq!(types::parse_decimal("110027.960000000000000000000000000000", true), Ok(Decimal::from_str_exact("110027.96").unwrap()));
+        assert_eq!(types::parse_decimal("0.00000001", true), Ok(Decimal::from_str_exact("0.00000001").unwrap()));
+        assert!(types::parse_decimal("abc", false).is_err());
+
+        let coinbase = serde_json::json!({
+            "bids": [["99", "1", 1]],
+            "asks": [[long, "2", 1]],
+            "sequence": 1,
+            "auction_mode": false,
+            "auction": null,
+            "time": "2025-11-01T00:00:00Z"
+        });
+        let gemini = serde_json::json!({"bids": [{"price": long, "amount": "1", "timestamp": "1761996296"}], "asks": []});
+        let coinbase_adapter = exchange::Coinbase { template: String::new() };
+        let gemini_adapter = exchange::Gemini { template: String::new(), stale_secs: None };
+        assert_eq!(coinbase_adapter.parse(coinbase.clone(), false).unwrap().0[0].price, rounded);
+        assert_eq!(gemini_adapter.parse(gemini.clone(), false).unwrap().1[0].price, rounded);
+
+        assert!(coinbase_adapter.parse(coinbase, true).unwrap_err().to_string().contains("would be rounded"));
+        assert!(gemini_adapter.parse(gemini, true).is_err());
+
+        // The stream parsers take the same flag
+        let update = format!(r#"{{"type":"l2_updates","symbol":"BTCUSD","changes":[["sell","{}","1"]]}}"#, long);
+        assert!(stream::parse_gemini(&update, false).is_ok());
+        assert!(stream::parse_gemini(&update, true).is_err());
+    }
+
+    #[cfg(feature = "coinbase")]
+    #[test]
+    fn test_coinbase_level2_and_level3_levels() {
+        let level2: CoinbaseResult = serde_json::from_value(serde_json::json!({
+            "bids": [["99", "1.5", 3]],
+            "asks": [["101", "2", 1]],
+            "sequence": 1,
+            "auction_mode": false,
+            "auction": null,
+            "time": "2025-11-01T00:00:00Z"
+        })).unwrap();
+        assert_eq!((level2.bids[0].num_orders, level2.bids[0].order_id.as_deref()), (3, None));
+        assert_eq!(level2.bids[0].size, Decimal::from_str_exact("1.5").unwrap());
+
+        let level3: CoinbaseResult = serde_json::from_value(serde_json::json!({
+            "bids": [["99", "1", "b3c5a2f0-8c1e-4d6a-9f2b-7e4d1c0a9b8e"], ["99", "0.5", "0f1e2d3c-4b5a-6978-8a9b-cdef01234567"]],
+            "asks": [["101", "2", "7a6b5c4d-3e2f-1a0b-9c8d-7e6f5a4b3c2d"]],
+            "sequence": 2,
+            "auction_mode": false,
+            "auction": null,
+            "time": "2025-11-01T00:00:00Z"
+        })).unwrap();
+        assert_eq!((level3.bids[0].num_orders, level3.bids[0].order_id.as_deref()), (1, Some("b3c5a2f0-8c1e-4d6a-9f2b-7e4d1c0a9b8e")));
+        assert_eq!(level3.bids[1].size, Decimal::from_str_exact("0.5").unwrap());
+
+        let book: NormalizedBook = level3.into();
+        assert_eq!((book.bids.len(), book.asks.len()), (2, 1));
+
+        assert!(serde_json::from_value::<CoinbaseResult>(serde_json::json!({
+            "bids": [["99", "1", true]], "asks": [], "sequence": 3, "auction_mode": false, "auction": null, "time": ""
+        })).is_err());
+    }
+
+    #[cfg(all(feature = "coinbase", feature = "gemini"))]
+    #[tokio::test]
+    async fn test_run_status() {
+        let args = Args::parse_from(["ob-aggregator-rs", "--qty", "1", "--burst", "4"]);
+        let client = api_client::create_client(api_client::DEFAULT_TIMEOUT, HeaderMap::new());
+        let rate_limiter = create_rate_limiter(&args).unwrap();
+
+        let (_server, endpoints) = fixture_exchanges(200, 200).await;
+        let output = run(&args, &endpoints, &client, Some(&rate_limiter)).await.unwrap();
+        assert_eq!(output.status, RunStatus::Complete);
+        assert_eq!(output.status.exit_code(), 0);
+
+        // Coinbase fails, Gemini alone still gives a quote
+        let (_server, endpoints) = fixture_exchanges(500, 200).await;
+        let output = run(&args, &endpoints, &client, Some(&rate_limiter)).await.unwrap();
+        assert_eq!(output.status, RunStatus::Partial);
+        assert_eq!(output.status.exit_code(), 2);
+    }
+
+    #[test]
+    fn test_round_to_tick() {
+        let tick = Decimal::from_str_exact("0.01").unwrap();
+        let price = |s: &str| Decimal::from_str_exact(s).unwrap();
+        // 0.005 apart, both land on 100.01
+        assert_eq!(round_to_tick(price("100.005"), tick), price("100.01"));
+        assert_eq!(round_to_tick(price("100.010"), tick), price("100.01"));
+        assert_eq!(round_to_tick(price("100.004"), tick), price("100.00"));
+        assert_eq!(round_to_tick(price("123"), Decimal::from(5)), Decimal::from(125));
+
+        let books = vec![
+            NormalizedBook { asks: book(&[("100.005", "1")]), bids: Vec::new() },
+            NormalizedBook { asks: book(&[("100.010", "2")]), bids: Vec::new() },
+        ];
+        let (asks, _) = merge_books(MergeOptions { tick: Some(tick), ..Default::default() }, books);
+        let asks = asks.unwrap();
+        assert_eq!(asks.len(), 1, "Both levels should share the 100.01 tick: {:?}", asks);
+        assert_eq!((asks[0].price, asks[0].size), (price("100.01"), Decimal::from(3)));
+
+        assert!(parse_tick("0").is_err());
+        assert!(parse_tick("-0.01").is_err());
+    }
+
+    #[cfg(all(feature = "coinbase", feature = "gemini"))]
+    #[tokio::test]
+    async fn test_selftest_reports_each_exchange() {
+        let (_server, endpoints) = fixture_exchanges(200, 500).await;
+        let args = Args::parse_from(["ob-aggregator-rs", "selftest"]);
+        assert_eq!(args.command, Some(Command::Selftest));
+        let client = api_client::create_client(api_client::DEFAULT_TIMEOUT, HeaderMap::new());
+
+        let probes = selftest(&args, &endpoints, &client).await;
+
+        assert_eq!(probes.iter().map(|probe| probe.exchange).collect::<Vec<_>>(), vec![Exchange::Coinbase, Exchange::Gemini]);
+        assert!(probes[0].result.is_ok());
+        assert!(probes[1].result.as_ref().is_err_and(|e| e.contains("500")), "{:?}", probes[1]);
+        assert!(probes.iter().all(|probe| probe.latency < api_client::DEFAULT_TIMEOUT));
+
+        let line = probes[0].to_string();
+        assert!(line.starts_with("COINBASE | ok | ") && line.ends_with(" ms"), "{}", line);
+        assert!(probes[1].to_string().starts_with("GEMINI | failed: Request failed: 500"), "{}", probes[1]);
+    }
+
+    #[tokio::test]
+    async fn test_get_data_detailed_keeps_rate_limit_headers() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/book"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_json(serde_json::json!({"bids": [], "asks": []}))
+                    .insert_header("X-RateLimit-Limit", "60")
+                    .insert_header("X-RateLimit-Remaining", "42")
+                    .insert_header("X-Request-Id", "abc"),
+            )
+            .mount(&server)
+            .await;
+        let client = api_client::create_client(api_client::DEFAULT_TIMEOUT, HeaderMap::new());
+
+        let response = get_data_detailed(&client, &format!("{}/book", server.uri()), &HeaderMap::new()).await.unwrap();
+
+        assert_eq!(response.status, reqwest::StatusCode::OK);
+        assert_eq!(response.data, serde_json::json!({"bids": [], "asks": []}));
+        assert_eq!(response.rate_limit_remaining(), Some(42));
+        assert_eq!(response.headers.get("x-ratelimit-limit").unwrap(), "60");
+        assert!(response.headers.get("x-ratelimit-reset").is_none());
+        assert!(response.headers.get("x-request-id").is_none(), "Only the rate limit headers should be kept");
+    }
+
+    #[cfg(all(feature = "coinbase", feature = "gemini"))]
+    #[tokio::test]
+    async fn test_output_file_round_trip() {
+        let (_server, endpoints) = mock_exchanges().await;
+        let path = env::temp_dir().join(format!("ob-aggregator-quote-{}.json", std::process::id()));
+        let path_arg = path.to_str().unwrap();
+        let args = Args::parse_from(["ob-aggregator-rs", "--qty", "1", "--burst", "2", "--format", "json", "--output-file", path_arg]);
+        let client = api_client::create_client(api_client::DEFAULT_TIMEOUT, HeaderMap::new());
+        let output = run(&args, &endpoints, &client, Some(&create_rate_limiter(&args).unwrap())).await.unwrap();
+
+        write_output_file(&args, &render(&args, &output).unwrap()).unwrap();
+
+        let quote: JsonQuote = serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(quote.symbol, "BTC-USD");
+        assert_eq!(quote.buy_price, Some(Decimal::from_str_exact("100.75").unwrap()));
+        assert_eq!(quote.sell_price, Some(Decimal::from_str_exact("99.25").unwrap()));
+
+        let missing = env::temp_dir().join("ob-aggregator-missing-dir").join("quote.json");
+        let args = Args::parse_from(["ob-aggregator-rs", "--output-file", missing.to_str().unwrap()]);
+        let err = write_output_file(&args, "{}").unwrap_err();
+        assert!(err.to_string().contains("does not exist"), "{}", err);
+    }
+
+    #[cfg(all(feature = "coinbase", feature = "gemini"))]
+    #[tokio::test]
+    async fn test_price_dp() {
+        let (_server, endpoints) = mock_exchanges().await;
+        let args = Args::parse_from(["ob-aggregator-rs", "--qty", "1", "--burst", "2", "--price-dp", "4"]);
+        let client = api_client::create_client(api_client::DEFAULT_TIMEOUT, HeaderMap::new());
+        let output = run(&args, &endpoints, &client, Some(&create_rate_limiter(&args).unwrap())).await.unwrap();
+
+        let report = render(&args, &output).unwrap();
+        assert!(report.contains("To buy 1 BTC: $100.7500"), "{}", report);
+        assert!(report.contains("To sell 1 BTC: $99.2500"), "{}", report);
+        assert_eq!(Quote::new(&args, output.buy_price, output.sell_price).to_string(), "1 BTC | buy: $100.7500 | sell: $99.2500");
+
+        assert_eq!(format_money(Decimal::from_str_exact("1234.567891").unwrap(), "$", 4, RoundingStrategy::MidpointAwayFromZero, &Locale::en), "$1,234.5679");
+        assert_eq!(format_money(Decimal::from_str_exact("-0.00016").unwrap(), "USDT", 4, RoundingStrategy::MidpointAwayFromZero, &Locale::en), "-USDT 0.0002");
+        assert_eq!(format_money(Decimal::from_str_exact("1234.6").unwrap(), "$", 0, RoundingStrategy::MidpointAwayFromZero, &Locale::en), "$1,235");
+    }
+
+    #[test]
+    fn test_locale() {
+        let large = Decimal::from_str_exact("1234567.896").unwrap();
+        assert_eq!(format_money(large, "$", 2, RoundingStrategy::MidpointAwayFromZero, &Locale::de), "$1.234.567,90");
+        assert_eq!(format_money(large, "USDT", 0, RoundingStrategy::MidpointAwayFromZero, &Locale::de), "USDT 1.234.568");
+
+        let args = Args::parse_from(["ob-aggregator-rs", "--qty", "1", "--locale", "de"]);
+        let quote = Quote::new(&args, Some(Ok(large)), None);
+        assert_eq!(quote.to_string(), "1 BTC | buy: $1.234.567,90 | sell: -");
+
+        // Unknown codes format like the default
+        let args = Args::parse_from(["ob-aggregator-rs", "--locale", "not-a-locale"]);
+        assert_eq!(number_locale(&args), Locale::en);
+    }
+
+    #[test]
+    fn test_verbose_sets_the_log_level() {
+        let level = |flags: &[&str], rust_log: Option<&str>| {
+            let args = Args::parse_from(["ob-aggregator-rs"].iter().chain(flags));
+            log_filter(args.verbose, rust_log).max_level_hint()
+        };
+
+        assert_eq!(level(&[], None), Some(LevelFilter::WARN));
+        assert_eq!(level(&["-v"], None), Some(LevelFilter::INFO));
+        assert_eq!(level(&["-vv"], None), Some(LevelFilter::DEBUG));
+        assert_eq!(level(&["-v", "-v", "-v"], None), Some(LevelFilter::TRACE));
+        assert_eq!(level(&["selftest", "-vvvv"], None), Some(LevelFilter::TRACE));
+
+        // Without -v RUST_LOG decides. With it -v sets the overall level and RUST_LOG's targets stay
+        assert_eq!(level(&[], Some("debug")), Some(LevelFilter::DEBUG));
+        assert_eq!(level(&["-v"], Some("error")), Some(LevelFilter::INFO));
+        assert_eq!(level(&["-v"], Some("reqwest=trace")), Some(LevelFilter::TRACE));
+    }
+
+    #[cfg(all(feature = "coinbase", feature = "gemini"))]
+    #[tokio::test]
+    async fn test_deadline_prices_the_fast_exchange() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/coinbase"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "bids": [["99", "1", 1]],
+                "asks": [["101", "1", 1]],
+                "sequence": 1,
+                "auction_mode": false,
+                "auction": null,
+                "time": "2025-11-01T00:00:00Z"
+            })))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/gemini"))
+            .respond_with(ResponseTemplate::new(200).set_delay(Duration::from_secs(5)).set_body_json(serde_json::json!({
+                "bids": [{"price": "99.5", "amount": "1", "timestamp": "1761996296"}],
+                "asks": [{"price": "100.5", "amount": "1", "timestamp": "1761996296"}]
+            })))
+            .mount(&server)
+            .await;
+        let endpoints = Endpoints {
+            coinbase: format!("{}/coinbase", server.uri()),
+            gemini: format!("{}/gemini", server.uri()),
+            binance: None,
+            bitstamp: None,
+            api_keys: ApiKeys::default(),
+        };
+        let args = Args::parse_from(["ob-aggregator-rs", "--qty", "1", "--burst", "2", "--deadline", "1"]);
+        let client = api_client::create_client(api_client::DEFAULT_TIMEOUT, HeaderMap::new());
+
+        let started = Instant::now();
+        let output = run(&args, &endpoints, &client, Some(&create_rate_limiter(&args).unwrap())).await.unwrap();
+
+        assert!(started.elapsed() < Duration::from_secs(3), "The run should not wait for Gemini: {:?}", started.elapsed());
+        assert!(output.asks.unwrap().iter().all(|level| level.source == Exchange::Coinbase));
+        assert_eq!(output.buy_price, Some(Ok(Decimal::from(101))));
+        assert_eq!(output.status, RunStatus::Partial);
+    }
+
+    #[cfg(all(feature = "coinbase", feature = "gemini"))]
+    #[test]
+    fn test_merge_detailed_keeps_order_counts() {
+        let (coinbase, gemini) = sample_books();
+        let asks = merge_detailed(
+            vec![
+                coinbase.asks.into_iter().map(OrderBookDetailed::from).collect(),
+                gemini.asks.into_iter().map(OrderBookDetailed::from).collect(),
+            ],
+            true,
+        );
+        let bids = merge_detailed(
+            vec![
+                coinbase.bids.into_iter().map(OrderBookDetailed::from).collect(),
+                gemini.bids.into_iter().map(OrderBookDetailed::from).collect(),
+            ],
+            false,
+        );
+
+        let summary = |levels: &[OrderBookDetailed]| levels.iter().map(|level| (level.price, level.num_orders, level.source)).collect::<Vec<_>>();
+        assert_eq!(summary(&asks), vec![(Decimal::from(101), Some(1), Exchange::Coinbase), (Decimal::from(102), None, Exchange::Gemini)]);
+        assert_eq!(summary(&bids), vec![(Decimal::from(99), Some(1), Exchange::Coinbase), (Decimal::from(98), None, Exchange::Gemini)]);
+
+        // Same order as the plain merge
+        let (coinbase, gemini) = sample_books();
+        let (plain_asks, _) = merge_books(MergeOptions::default(), vec![coinbase.into(), gemini.into()]);
+        let plain_prices: Vec<Decimal> = plain_asks.unwrap().iter().map(|level| level.price).collect();
+        assert_eq!(asks.iter().map(|level| level.price).collect::<Vec<_>>(), plain_prices);
+    }
+
+    #[tokio::test]
+    async fn test_retry_after_penalizes_rate_limiter() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/book"))
+            .respond_with(ResponseTemplate::new(429).insert_header("Retry-After", "1"))
+            .mount(&server)
+            .await;
+        let client = api_client::create_client(api_client::DEFAULT_TIMEOUT, HeaderMap::new());
+        // Plenty of local budget, so only the penalty can hold the next request back
+        let rate_limiter = RateLimiter::new_with_burst(5, Duration::from_millis(10)).unwrap();
+
+        let result = get_data_rate_limited(&client, &format!("{}/book", server.uri()), &HeaderMap::new(), &rate_limiter, false, 0).await;
+        assert!(result.is_err_and(|e| is_rate_limited(&e)));
+
+        assert!(rate_limiter.try_acquire().await.is_err(), "The bucket should be empty after the 429");
+        let started = Instant::now();
+        rate_limiter.acquire().await;
+        assert!(started.elapsed() >= Duration::from_millis(900), "acquire should wait out the Retry-After: {:?}", started.elapsed());
+    }
+
+    #[tokio::test]
+    async fn test_retry_after_is_waited_once_per_429() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/book"))
+            .respond_with(ResponseTemplate::new(429).insert_header("Retry-After", "1"))
+            .up_to_n_times(1)
+            .with_priority(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/book"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({"bids": [], "asks": []})))
+            .mount(&server)
+            .await;
+        let client = api_client::create_client(api_client::DEFAULT_TIMEOUT, HeaderMap::new());
+        let rate_limiter = RateLimiter::new_with_burst(5, Duration::from_millis(10)).unwrap();
+
+        // The retry goes through the limiter, which waits the Retry-After out, and isn't slept on a second time
+        let started = Instant::now();
+        let data = get_data_rate_limited(&client, &format!("{}/book", server.uri()), &HeaderMap::new(), &rate_limiter, false, 2).await.unwrap();
+        let elapsed = started.elapsed();
+        assert_eq!(data["bids"], serde_json::json!([]));
+        assert_eq!(server.received_requests().await.unwrap().len(), 2);
+        assert!(elapsed >= Duration::from_millis(900) && elapsed < Duration::from_millis(1800), "Waited {:?}", elapsed);
+    }
+
+    #[tokio::test]
+    async fn test_adaptive_tightens_on_every_429() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET")).and(path("/book")).respond_with(ResponseTemplate::new(429)).mount(&server).await;
+        let client = api_client::create_client(api_client::DEFAULT_TIMEOUT, HeaderMap::new());
+        let rate_limiter = RateLimiter::new_with_burst(5, Duration::from_millis(10)).unwrap();
+        let base_rate = rate_limiter.tokens_per_second().await;
+
+        let result = get_data_rate_limited(&client, &format!("{}/book", server.uri()), &HeaderMap::new(), &rate_limiter, true, 3).await;
+        assert!(result.is_err_and(|e| is_rate_limited(&e)));
+
+        // One request plus the rate limited retries, each after halving the rate. --retries doesn't add more 429s.
+        assert_eq!(server.received_requests().await.unwrap().len(), 4);
+        assert_eq!(rate_limiter.tokens_per_second().await, base_rate / Decimal::from(8));
+    }
+
+    #[tokio::test]
+    async fn test_retry_after_is_capped() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/book"))
+            .respond_with(ResponseTemplate::new(429).insert_header("Retry-After", u64::MAX.to_string()))
+            .mount(&server)
+            .await;
+        let client = api_client::create_client(api_client::DEFAULT_TIMEOUT, HeaderMap::new());
+
+        let err = get_data_detailed(&client, &format!("{}/book", server.uri()), &HeaderMap::new()).await.unwrap_err();
+        let failed = err.downcast_ref::<data_fetcher::RequestFailed>().unwrap();
+        assert_eq!(failed.retry_after, Some(data_fetcher::MAX_RETRY_AFTER));
+    }
+
+    #[cfg(all(feature = "coinbase", feature = "gemini"))]
+    #[tokio::test]
+    async fn test_serve_mock_books() {
+        let fixture = |file: &str| PathBuf::from(env!("CARGO_MANIFEST_DIR")).join(file);
+        let books = mock_server::MockBooks::load(&fixture("coinbase.json"), &fixture("gemini.json")).unwrap();
+        let addr = mock_server::serve(0, books).await.unwrap();
+        let client = api_client::create_client(api_client::DEFAULT_TIMEOUT, HeaderMap::new());
+
+        let coinbase: CoinbaseResult = serde_json::from_value(get_data(&client, &format!("http://{}/coinbase", addr), &HeaderMap::new()).await.unwrap()).unwrap();
+        assert_eq!(coinbase.sequence, 114629983704);
+        let gemini: GeminiResult = serde_json::from_value(get_data(&client, &format!("http://{}/gemini?limit_bids=50", addr), &HeaderMap::new()).await.unwrap()).unwrap();
+        assert_eq!(gemini.asks[0].price, Decimal::from_str_exact("110025.98").unwrap());
+
+        let missing = get_data(&client, &format!("http://{}/binance", addr), &HeaderMap::new()).await.unwrap_err();
+        assert!(missing.to_string().contains("404"), "{}", missing);
+
+        let args = Args::parse_from(["ob-aggregator-rs", "serve-mock", "--port", "9000"]);
+        assert_eq!(args.command, Some(Command::ServeMock { port: 9000, coinbase_book: "coinbase.json".into(), gemini_book: "gemini.json".into() }));
+    }
+
+    #[cfg(all(feature = "coinbase", feature = "gemini"))]
+    #[tokio::test]
+    async fn test_compare_with_saved_quote() {
+        let dir = env::temp_dir().join(format!("ob-aggregator-compare-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let saved = dir.join("previous.json");
+        std::fs::write(&saved, r#"{"symbol":"BTC-USD","quantity":"1","buy_price":"100","sell_price":"99.5","currency":"USD","best_ask":"100.25","best_bid":"99"}"#).unwrap();
+
+        let (_server, endpoints) = mock_exchanges().await;
+        let args = Args::parse_from(["ob-aggregator-rs", "--qty", "1", "--burst", "2", "--compare", saved.to_str().unwrap()]);
+        let client = api_client::create_client(api_client::DEFAULT_TIMEOUT, HeaderMap::new());
+        let output = run(&args, &endpoints, &client, Some(&create_rate_limiter(&args).unwrap())).await.unwrap();
+
+        let previous = load_quote(&saved).unwrap();
+        let change = QuoteChange::between(&previous, &json_quote(&args, &output));
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        // Now best ask 100.5, best bid 99.5, buy 100.75 and sell 99.25
+        let price = |s: &str| Some(Decimal::from_str_exact(s).unwrap());
+        assert_eq!(change, QuoteChange { best_ask: price("0.25"), best_bid: price("0.5"), buy_price: price("0.75"), sell_price: price("-0.25") });
+
+        let mut report = String::new();
+        write_changes(&mut report, &args, Path::new("previous.json"), &change).unwrap();
+        assert_eq!(report, "Since previous.json:\nBest ask: +$0.25 | Best bid: +$0.50\nBuy price: +$0.75 | Sell price: -$0.25\n");
+
+        // A figure missing from either quote has no change
+        let without_book: JsonQuote = serde_json::from_str(r#"{"symbol":"BTC-USD","quantity":"1","buy_price":null,"sell_price":null,"currency":"USD"}"#).unwrap();
+        assert_eq!(QuoteChange::between(&without_book, &json_quote(&args, &output)), QuoteChange::default());
+    }
+
+    #[cfg(feature = "gemini")]
+    #[test]
+    fn test_gemini_skips_malformed_levels() {
+        let gemini: GeminiResult = serde_json::from_value(serde_json::json!({
+            "bids": [
+                {"price": "99", "amount": "1", "timestamp": "1761996296"},
+                {"price": "98", "timestamp": "1761996296"},
+                {"price": "97", "amount": "lots", "timestamp": "1761996296"},
+                {"price": "96", "amount": "2", "timestamp": "1761996296"}
+            ],
+            "asks": [{"price": "101", "amount": null, "timestamp": "1761996296"}, {"price": "102", "amount": "3", "timestamp": "1761996296"}]
+        })).unwrap();
+
+        let prices = |orders: &[GeminiOrder]| orders.iter().map(|order| order.price).collect::<Vec<_>>();
+        assert_eq!(prices(&gemini.bids), vec![Decimal::from(99), Decimal::from(96)]);
+        assert_eq!(prices(&gemini.asks), vec![Decimal::from(102)]);
+
+        // A side that isn't a list at all still fails the book
+        assert!(serde_json::from_value::<GeminiResult>(serde_json::json!({"bids": {}, "asks": []})).is_err());
+    }
+
+    #[cfg(all(feature = "coinbase", feature = "gemini"))]
+    #[tokio::test]
+    async fn test_quantity_ladder() {
+        let (_server, endpoints) = fixture_exchanges(200, 200).await;
+        let args = Args::parse_from(["ob-aggregator-rs", "--qty", "1", "--burst", "2", "--quantities", "0.1,1,2,3"]);
+        let client = api_client::create_client(api_client::DEFAULT_TIMEOUT, HeaderMap::new());
+        let output = run(&args, &endpoints, &client, Some(&create_rate_limiter(&args).unwrap())).await.unwrap();
+
+        assert_eq!(output.ladder.iter().map(|row| row.quantity.to_string()).collect::<Vec<_>>(), ["0.1", "1", "2", "3"]);
+        // The main quote is the 1 BTC rung
+        assert_eq!(output.ladder[1].buy, output.buy_price);
+
+        // Larger orders reach deeper, pricier asks. The fixture asks hold about 3.08 BTC.
+        let avg_buys: Vec<Decimal> = output.ladder.iter().map(|row| row.buy.clone().unwrap().unwrap() / row.quantity).collect();
+        assert!(avg_buys.windows(2).all(|pair| pair[0] <= pair[1]), "{:?}", avg_buys);
+        assert!(avg_buys[0] < avg_buys[3], "{:?}", avg_buys);
+
+        let table = ladder_table(&output.ladder, |value, _| format_money(value, "$", 2, RoundingStrategy::MidpointAwayFromZero, &Locale::en));
+        assert_eq!(table.len(), 5);
+        assert!(table[0].trim_start().starts_with("QTY |") && table[0].ends_with("AVG SELL"), "{}", table[0]);
+        assert!(table.iter().all(|line| line.len() == table[0].len()), "{:#?}", table);
+    }
+
+    #[cfg(all(feature = "coinbase", feature = "gemini"))]
+    #[tokio::test]
+    async fn test_record_raw_responses() {
+        let (_server, endpoints) = mock_exchanges().await;
+        let dir = env::temp_dir().join(format!("ob-aggregator-record-{}", std::process::id()));
+        let args = Args::parse_from(["ob-aggregator-rs", "--qty", "1", "--burst", "2", "--record", dir.to_str().unwrap()]);
+        let client = api_client::create_client(api_client::DEFAULT_TIMEOUT, HeaderMap::new());
+
+        let output = run(&args, &endpoints, &client, Some(&create_rate_limiter(&args).unwrap())).await.unwrap();
+        assert_eq!(output.buy_price, Some(Ok(Decimal::from_str_exact("100.75").unwrap())));
+
+        let mut recorded: Vec<PathBuf> = std::fs::read_dir(&dir).unwrap().map(|entry| entry.unwrap().path()).collect();
+        recorded.sort();
+        let names: Vec<String> = recorded.iter().map(|path| path.file_name().unwrap().to_string_lossy().into_owned()).collect();
+        assert_eq!(names.len(), 2, "{:?}", names);
+        assert!(names[0].starts_with("coinbase-BTC-USD-") && names[1].starts_with("gemini-BTC-USD-"), "{:?}", names);
+
+        let coinbase: Value = serde_json::from_str(&std::fs::read_to_string(&recorded[0]).unwrap()).unwrap();
+        let fetched = get_data(&client, &endpoints.coinbase, &HeaderMap::new()).await.unwrap();
+        assert_eq!(coinbase, fetched);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        // A directory that can't be created doesn't stop the run
+        let blocked = env::temp_dir().join(format!("ob-aggregator-record-file-{}", std::process::id()));
+        std::fs::write(&blocked, "").unwrap();
+        let args = Args::parse_from(["ob-aggregator-rs", "--qty", "1", "--burst", "2", "--record", blocked.join("sub").to_str().unwrap()]);
+        assert!(run(&args, &endpoints, &client, Some(&create_rate_limiter(&args).unwrap())).await.is_ok());
+        std::fs::remove_file(&blocked).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_run_symbols_max_concurrency_caps_requests_in_flight() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        // Each request is held open for a while, counting how many are being served at once
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let in_flight = std::sync::Arc::new(AtomicUsize::new(0));
+        let most = std::sync::Arc::new(AtomicUsize::new(0));
+        let (server_in_flight, server_most) = (in_flight.clone(), most.clone());
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let (in_flight, most) = (server_in_flight.clone(), server_most.clone());
+                tokio::spawn(async move {
+                    let mut request = vec![0; 4096];
+                    let n = socket.read(&mut request).await.unwrap();
+                    let now = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                    most.fetch_max(now, Ordering::SeqCst);
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    let body = if String::from_utf8_lossy(&request[..n]).starts_with("GET /coinbase") {
+                        r#"{"bids": [], "asks": [["101", "5", 1]], "sequence": 1, "auction_mode": false, "auction": null, "time": "2025-11-01T00:00:00Z"}"#
+                    } else {
+                        r#"{"bids": [], "asks": [{"price": "100", "amount": "0.5", "timestamp": "1761996296"}]}"#
+                    };
+                    in_flight.fetch_sub(1, Ordering::SeqCst);
+                    let response = format!(
+                        "HTTP/1.1 200 OK\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+                        body.len(),
+                        body
+                    );
+                    socket.write_all(response.as_bytes()).await.unwrap();
+                });
+            }
+        });
+
+        let templates = ApiTemplates {
+            coinbase: format!("http://{}/coinbase/{{symbol}}", addr),
+            gemini: format!("http://{}/gemini/{{symbol}}", addr),
+            binance: None,
+            bitstamp: None,
+            api_keys: ApiKeys::default(),
+        };
+        let args = Args::parse_from([
+            "ob-aggregator-rs", "--qty", "1", "--side", "buy", "--burst", "12", "--max-concurrency", "2",
+            "--symbols", "BTC-USD,ETH-USD,SOL-USD,LTC-USD,BCH-USD,LINK-USD",
+        ]);
+        let client = api_client::create_client(api_client::DEFAULT_TIMEOUT, HeaderMap::new());
+
+        let results = run_symbols(&args, &templates, &client, Some(&create_rate_limiter(&args).unwrap())).await;
+
+        assert!(results.iter().all(|(_, output)| output.is_ok()));
+        assert_eq!(most.load(Ordering::SeqCst), 2, "--max-concurrency 2 should keep two requests in flight at most");
+    }
+
+    #[test]
+    fn test_book_share() {
+        let asks = book(&[("100", "10"), ("101", "25"), ("102", "5")]);
+
+        assert_eq!(book_share(&asks, Decimal::from(5)), Some(Decimal::from_str_exact("12.5").unwrap()));
+        assert_eq!(book_share(&asks, Decimal::from(80)), Some(Decimal::from(200)));
+        assert_eq!(book_share(&[], Decimal::ONE), None);
+
+        let args = Args::parse_from(["ob-aggregator-rs", "--qty", "5"]);
+        let output = RunOutput {
+            buy_price: calculate_entity_price(&asks, Decimal::from(5), true, "ASKS").ok().map(Ok),
+            buy_book_share: book_share(&asks, Decimal::from(5)),
+            ..Default::default()
+        };
+        let report = render(&args, &output).unwrap();
+        assert!(report.contains("To buy 5 BTC: $500.00 (12.5% of the visible ask liquidity)"), "{}", report);
+    }
+
+    #[cfg(all(feature = "coinbase", feature = "gemini"))]
+    #[test]
+    fn test_url_flags_override_env() {
+        // SAFETY: no other test reads or writes these variables
+        unsafe {
+            env::set_var("COINBASE_API", "https://env.example/coinbase/{symbol}");
+            env::set_var("GEMINI_API", "https://env.example/gemini/{symbol}");
+        }
+
+        let args = Args::parse_from(["ob-aggregator-rs", "--coinbase-url", "http://127.0.0.1:8080/coinbase/{symbol}"]);
+        let templates = ApiTemplates::from_env(&args).unwrap();
+        assert_eq!(templates.coinbase, "http://127.0.0.1:8080/coinbase/{symbol}");
+        assert_eq!(templates.gemini, "https://env.example/gemini/{symbol}");
+
+        assert!(Args::try_parse_from(["ob-aggregator-rs", "--coinbase-url", "http://a", "--coinbase-file", "coinbase.json"]).is_err());
+    }
+
+    #[test]
+    fn test_micro_price_leans_toward_the_heavier_side() {
+        // Far more size bid than offered, so the fair price sits above the mid toward the ask
+        let asks = book(&[("101", "1"), ("102", "50")]);
+        let bids = book(&[("99", "3"), ("98", "50")]);
+
+        assert_eq!(micro_price(&asks, &bids), Some(Decimal::from_str_exact("100.5").unwrap()));
+        // And below it when the asks are heavier
+        assert_eq!(micro_price(&book(&[("101", "3")]), &book(&[("99", "1")])), Some(Decimal::from_str_exact("99.5").unwrap()));
+        assert_eq!(micro_price(&asks, &[]), None);
+        assert_eq!(micro_price(&book(&[("101", "0")]), &book(&[("99", "0")])), None);
+        // Overflowing the weighting or the total size gives no micro price instead of panicking
+        let max = Decimal::MAX.to_string();
+        assert_eq!(micro_price(&book(&[("101", &max)]), &book(&[("99", "1")])), None);
+        assert_eq!(micro_price(&book(&[("0.0001", &max)]), &book(&[("0.0001", &max)])), None);
+
+        let args = Args::parse_from(["ob-aggregator-rs", "--qty", "1"]);
+        let output = RunOutput { top_of_book: spread(&asks, &bids), micro_price: micro_price(&asks, &bids), ..Default::default() };
+        let report = render(&args, &output).unwrap();
+        assert!(report.contains("Spread: $2.00 | Mid: $100.00 | Micro: $100.50"), "{}", report);
+    }
+
+    #[cfg(all(feature = "coinbase", feature = "gemini"))]
+    #[tokio::test]
+    async fn test_excluded_exchanges_carry_a_reason() {
+        let (server, mut endpoints) = mock_exchanges().await;
+        Mock::given(method("GET"))
+            .and(path("/coinbase-malformed"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("{\"bids\": [[\"99\", "))
+            .mount(&server)
+            .await;
+        endpoints.coinbase = format!("{}/coinbase-malformed", server.uri());
+        let args = Args::parse_from(["ob-aggregator-rs", "--qty", "0.5", "--burst", "2"]);
+        let client = api_client::create_client(api_client::DEFAULT_TIMEOUT, HeaderMap::new());
+
+        let output = run(&args, &endpoints, &client, Some(&create_rate_limiter(&args).unwrap())).await.unwrap();
+
+        assert_eq!(output.status, RunStatus::Partial);
+        let reasons: Vec<(Exchange, ExclusionReason)> = output.excluded.iter().map(|e| (e.exchange, e.reason)).collect();
+        assert_eq!(reasons, vec![(Exchange::Coinbase, ExclusionReason::ParseError)]);
+        assert!(render(&args, &output).unwrap().contains("Excluded COINBASE: parse error"));
+
+        // A request the exchange refuses is a network error, not a parse error
+        endpoints.coinbase = format!("{}/coinbase-missing", server.uri());
+        let output = run(&args, &endpoints, &client, Some(&create_rate_limiter(&args).unwrap())).await.unwrap();
+        assert_eq!(output.excluded.iter().map(|e| e.reason).collect::<Vec<_>>(), vec![ExclusionReason::NetworkError]);
+    }
+
+    #[cfg(feature = "compression")]
+    #[tokio::test]
+    async fn test_get_data_decodes_gzip() {
+        use std::io::Write as _;
+        use wiremock::matchers::header_regex;
+
+        let book = serde_json::json!({"bids": [["99", "1", 1]], "asks": [["101", "1", 1]], "sequence": 1});
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(book.to_string().as_bytes()).unwrap();
+
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/book"))
+            .and(header_regex("accept-encoding", "gzip"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .insert_header("content-encoding", "gzip")
+                    .insert_header("content-type", "application/json")
+                    .set_body_bytes(encoder.finish().unwrap()),
+            )
+            .mount(&server)
+            .await;
+        let client = api_client::create_client(api_client::DEFAULT_TIMEOUT, HeaderMap::new());
+
+        let data = get_data(&client, &format!("{}/book", server.uri()), &HeaderMap::new()).await.unwrap();
+
+        assert_eq!(data, book);
+    }
+
+    #[test]
+    fn test_rounding_modes_at_the_half() {
+        let half = Decimal::from_str_exact("100.125").unwrap();
+        let round = |mode: Rounding, is_buy: Option<bool>| mode.round(half, 2, is_buy).to_string();
+
+        for is_buy in [Some(true), Some(false), None] {
+            assert_eq!(round(Rounding::Nearest, is_buy), "100.13");
+            assert_eq!(round(Rounding::Up, is_buy), "100.13");
+            assert_eq!(round(Rounding::Down, is_buy), "100.12");
+        }
+        assert_eq!(round(Rounding::Conservative, Some(true)), "100.13");
+        assert_eq!(round(Rounding::Conservative, Some(false)), "100.12");
+        assert_eq!(round(Rounding::Conservative, None), "100.13");
+
+        // Conservative by default, so the quote never looks better than the book
+        let args = Args::parse_from(["ob-aggregator-rs", "--qty", "1"]);
+        assert_eq!(Quote::new(&args, Some(Ok(half)), Some(Ok(half))).to_string(), "1 BTC | buy: $100.13 | sell: $100.12");
+        let args = Args::parse_from(["ob-aggregator-rs", "--qty", "1", "--rounding", "down"]);
+        assert_eq!(Quote::new(&args, Some(Ok(half)), Some(Ok(half))).to_string(), "1 BTC | buy: $100.12 | sell: $100.12");
+
+        // Price changes and the --curve follow --rounding too
+        let change = QuoteChange { buy_price: Some(Decimal::from_str_exact("0.125").unwrap()), ..QuoteChange::default() };
+        let output = RunOutput { asks: Some(book(&[("100.125", "1")])), bids: Some(book(&[("100.125", "1")])), ..RunOutput::default() };
+        let report = |flags: &[&str]| {
+            let args = Args::parse_from(["ob-aggregator-rs", "--qty", "1", "--curve", "1"].iter().chain(flags));
+            let (mut changes, mut text) = (String::new(), String::new());
+            write_changes(&mut changes, &args, Path::new("previous.json"), &change).unwrap();
+            write_text(&mut text, &args, &output).unwrap();
+            (changes.lines().nth(2).unwrap().to_string(), text.lines().take(2).collect::<Vec<_>>().join("\n"))
+        };
+        assert_eq!(report(&[]), ("Buy price: +$0.13 | Sell price: -".to_string(), "ASK curve | qty: 1 | avg price: 100.13\nBID curve | qty: 1 | avg price: 100.12".to_string()));
+        assert_eq!(report(&["--rounding", "down"]), ("Buy price: +$0.12 | Sell price: -".to_string(), "ASK curve | qty: 1 | avg price: 100.12\nBID curve | qty: 1 | avg price: 100.12".to_string()));
+    }
+
+    #[test]
+    fn test_prefix_book_fill_matches_the_linear_walk() {
+        let level = |price: &str, size: &str, source: Exchange| OrderBook {
+            price: Decimal::from_str_exact(price).unwrap(),
+            size: Decimal::from_str_exact(size).unwrap(),
+            source,
+        };
+        let quantities = ["0", "0.1", "1", "1.5", "2.25", "3", "4.75", "100"].map(|qty| Decimal::from_str_exact(qty).unwrap());
+        let assert_matches = |book: &PrefixBook, is_ascending: bool| {
+            for qty in quantities {
+                let label = if is_ascending { "ASKS" } else { "BIDS" };
+                assert_eq!(book.fill(qty), calculate_entity_price(book.levels(), qty, is_ascending, label), "qty {}", qty);
+            }
+        };
+
+        let mut asks = PrefixBook::from_levels(
+            vec![level("102", "1", Exchange::Gemini), level("100", "0.5", Exchange::Coinbase), level("101", "1.25", Exchange::Coinbase)],
+            true,
+        );
+        assert_matches(&asks, true);
+
+        // A new level, another exchange at an existing price, and a replaced size
+        asks.insert(level("99.5", "0.25", Exchange::Gemini));
+        asks.insert(level("101", "0.5", Exchange::Gemini));
+        asks.insert(level("100", "2", Exchange::Coinbase));
+        let prices: Vec<String> = asks.levels().iter().map(|level| format!("{} {}", level.price, level.size)).collect();
+        assert_eq!(prices, vec!["99.5 0.25", "100 2", "101 1.25", "101 0.5", "102 1"]);
+        assert_matches(&asks, true);
+
+        assert_eq!(asks.remove(Decimal::from(101), Exchange::Coinbase), Some(level("101", "1.25", Exchange::Coinbase)));
+        assert_eq!(asks.remove(Decimal::from(101), Exchange::Coinbase), None);
+        assert_matches(&asks, true);
+
+        let mut bids = PrefixBook::new(false);
+        for bid in [level("98", "1", Exchange::Coinbase), level("99", "0.75", Exchange::Gemini), level("97", "3", Exchange::Gemini)] {
+            bids.insert(bid);
+        }
+        assert_eq!(bids.levels().first().map(|level| level.price), Some(Decimal::from(99)));
+        assert_matches(&bids, false);
+
+        assert_eq!(PrefixBook::new(true).fill(Decimal::ONE), Err(PriceError::EmptyBook));
+    }
+
+    #[test]
+    fn test_cum_book_cost_matches_the_linear_walk() {
+        use rand::{Rng, SeedableRng};
+        let mut rng = rand::rngs::StdRng::seed_from_u64(11);
+
+        // Asks with random gaps and sizes, a few of them empty
+        let mut price = Decimal::from(100);
+        let asks: Vec<OrderBook> = (0..200)
+            .map(|i| {
+                price += Decimal::new(rng.random_range(1..500), 2);
+                let size = if i % 37 == 0 { Decimal::ZERO } else { Decimal::new(rng.random_range(1..100_000), 5) };
+                OrderBook { price, size, source: if i % 2 == 0 { Exchange::Coinbase } else { Exchange::Gemini } }
+            })
+            .collect();
+        let bids: Vec<OrderBook> = asks.iter().map(|level| OrderBook { price: Decimal::from(2000) - level.price, ..level.clone() }).collect();
+        let total = total_size(&asks);
+        let (cum_asks, cum_bids) = (build_cumulative(&asks), build_cumulative(&bids));
+
+        for _ in 0..1000 {
+            let quantity = Decimal::new(rng.random_range(0..1_200_000_000), 7);
+            assert_eq!(cum_asks.cost(quantity), calculate_entity_price(&asks, quantity, true, "ASKS"), "qty {}", quantity);
+            assert_eq!(cum_bids.cost(quantity), calculate_entity_price(&bids, quantity, false, "BIDS"), "qty {}", quantity);
+        }
+        // The exact total, and just over it
+        assert_eq!(cum_asks.cost(total), calculate_entity_price(&asks, total, true, "ASKS"));
+        assert!(matches!(cum_asks.cost(total + Decimal::new(1, 8)), Err(PriceError::InsufficientLiquidity { .. })));
+        assert_eq!(build_cumulative(&[]).cost(Decimal::ONE), Err(PriceError::EmptyBook));
+
+        // Overflow only once the fill has to go past the level where the running cost overflowed
+        let huge = book(&[("1", "1"), ("79228162514264337593543950335", "2")]);
+        let cum = build_cumulative(&huge);
+        for qty in ["1", "1.5", "2.5"] {
+            let qty = Decimal::from_str_exact(qty).unwrap();
+            assert_eq!(cum.cost(qty), calculate_entity_price(&huge, qty, true, "ASKS"), "qty {}", qty);
+        }
+    }
+
+    #[test]
+    fn test_bitstamp_deserialize() {
+        // Trimmed from /api/v2/order_book/btcusd/
+        let payload = r#"{
+            "timestamp": "1762000000",
+            "microtimestamp": "1762000000123456",
+            "bids": [["110015", "0.02000000"], ["110014", "0.45454000"]],
+            "asks": [["110021", "0.05000000"], ["110023.5", "1.36000000"], ["110030", "0.00090900"]]
+        }"#;
+
+        let result: BitstampResult = serde_json::from_str(payload).unwrap();
+        assert_eq!(result.timestamp, 1762000000);
+        assert_eq!(result.microtimestamp, 1762000000123456);
+        assert_eq!(result.bids.len(), 2);
+        assert_eq!(result.bids[1].price, Decimal::from(110014));
+        assert_eq!(result.bids[1].amount, Decimal::from_str_exact("0.45454").unwrap());
+        assert_eq!(result.asks[1].price, Decimal::from_str_exact("110023.5").unwrap());
+        assert_eq!(result.asks[2].amount, Decimal::from_str_exact("0.000909").unwrap());
+
+        let bitstamp = exchange::Bitstamp { template: "https://www.bitstamp.net/api/v2/order_book/{symbol}/".to_string() };
+        assert_eq!(bitstamp.endpoint("BTC-USD"), "https://www.bitstamp.net/api/v2/order_book/btcusd/");
+        let data: Value = serde_json::from_str(payload).unwrap();
+        assert_eq!(bitstamp.last_updated(&data), Some(1762000000));
+        let (asks, bids) = bitstamp.parse(data, false).unwrap();
+        assert_eq!((asks.len(), bids.len()), (3, 2));
+        assert!(asks.iter().chain(&bids).all(|level| level.source == Exchange::Bitstamp));
+
+        // Levels must be exactly [price, amount]
+        let extra = r#"{"timestamp": "1", "microtimestamp": "1", "bids": [["1", "2", "3"]], "asks": []}"#;
+        assert!(serde_json::from_str::<BitstampResult>(extra).is_err());
+    }
+
+    #[test]
+    fn test_duplicate_levels_are_collapsed() {
+        let level = |price: i64, size: &str, source: Exchange| OrderBook { price: Decimal::from(price), size: Decimal::from_str_exact(size).unwrap(), source };
+        let coinbase = NormalizedBook {
+            asks: vec![level(100, "1", Exchange::Coinbase), level(100, "1", Exchange::Coinbase), level(101, "2", Exchange::Coinbase), level(101, "2", Exchange::Coinbase)],
+            bids: vec![],
+        };
+        // Same price and size, but a different exchange, so both are real liquidity
+        let gemini = NormalizedBook { asks: vec![level(100, "1", Exchange::Gemini), level(102, "1", Exchange::Gemini)], bids: vec![] };
+
+        let (asks, _) = merge_books(MergeOptions { dedup: true, ..Default::default() }, vec![coinbase, gemini]);
+
+        assert_eq!(asks.unwrap(), vec![
+            level(100, "1", Exchange::Coinbase),
+            level(100, "1", Exchange::Gemini),
+            level(101, "2", Exchange::Coinbase),
+            level(102, "1", Exchange::Gemini),
+        ]);
+        // A different size is a different level
+        let levels = vec![level(100, "1", Exchange::Coinbase), level(100, "1.5", Exchange::Coinbase)];
+        assert_eq!(dedup_levels(levels.clone()), levels);
+    }
+
+    #[test]
+    fn test_effective_spread_widens_with_size() {
+        // Mirror images around 100
+        let asks = book(&[("100.5", "1"), ("101", "2"), ("102", "5")]);
+        let bids = book(&[("99.5", "1"), ("99", "2"), ("98", "5")]);
+        let (top_spread, _) = spread(&asks, &bids).unwrap();
+
+        assert_eq!(effective_spread(&asks, &bids, Decimal::ONE), Ok(top_spread));
+        let three = effective_spread(&asks, &bids, Decimal::from(3)).unwrap();
+        // (100.5 + 2 * 101) / 3 - (99.5 + 2 * 99) / 3
+        assert_eq!(three.round_dp(10), Decimal::from_str_exact("1.6666666667").unwrap());
+        assert!(three > top_spread);
+        assert!(effective_spread(&asks, &bids, Decimal::from(8)).unwrap() > three);
+
+        // Either side running out fails rather than pricing a partial fill
+        let thin_bids = book(&[("99.5", "1")]);
+        assert_eq!(
+            effective_spread(&asks, &thin_bids, Decimal::from(2)),
+            Err(PriceError::InsufficientLiquidity { requested: Decimal::from(2), available: Decimal::ONE })
+        );
+        assert_eq!(effective_spread(&[], &bids, Decimal::ONE), Err(PriceError::EmptyBook));
+
+        let args = Args::parse_from(["ob-aggregator-rs", "--round-trip", "3"]);
+        let output = RunOutput { round_trip: Some(Ok(three)), ..Default::default() };
+        assert!(render(&args, &output).unwrap().contains("Effective spread for 3 BTC: $1.67"));
+    }
+
+    #[test]
+    fn test_imbalance() {
+        let asks = book(&[("101", "1"), ("102", "0.5"), ("103", "10")]);
+        let bids = book(&[("99", "2"), ("98", "1"), ("97", "1")]);
+
+        // 3 bid against 1.5 ask over the best two levels, the third level on each side left out
+        assert_eq!(imbalance(&asks, &bids, 2), Some(Decimal::TWO));
+        assert_eq!(imbalance(&asks, &bids, 1), Some(Decimal::TWO));
+        assert_eq!(imbalance(&[], &bids, 2), None);
+        assert_eq!(imbalance(&book(&[("101", "0")]), &bids, 2), None);
+        assert_eq!(imbalance(&asks, &[], 2), Some(Decimal::ZERO));
+
+        let args = Args::parse_from(["ob-aggregator-rs", "--imbalance"]);
+        assert_eq!(args.imbalance, Some(10));
+        let output = RunOutput { imbalance: Some(Decimal::TWO), ..Default::default() };
+        assert!(render(&args, &output).unwrap().contains("Bid/ask size imbalance over the best 10 levels: 2.00"));
+    }
+
+    #[test]
+    fn test_large_unsorted_books_merge_like_a_sequential_sort() {
+        use rand::{Rng, SeedableRng};
+        let mut rng = rand::rngs::StdRng::seed_from_u64(3);
+
+        // Over the parallel sort threshold, with plenty of repeated prices and a mix of sources in one book
+        let book = |rng: &mut rand::rngs::StdRng, sources: &[Exchange]| -> Vec<OrderBook> {
+            (0..PARALLEL_SORT_THRESHOLD + 1000)
+                .map(|i| OrderBook {
+                    price: Decimal::new(rng.random_range(10_000..12_000), 2),
+                    size: Decimal::from(rng.random_range(1..4)),
+                    source: sources[i % sources.len()],
+                })
+                .collect()
+        };
+        let books = vec![book(&mut rng, &[Exchange::Coinbase, Exchange::Binance]), book(&mut rng, &[Exchange::Gemini])];
+
+        for is_ascending in [true, false] {
+            let expected_input: Vec<Vec<OrderBook>> = books
+                .iter()
+                .map(|book| {
+                    let mut sorted = book.clone();
+                    sorted.sort_by(|a, b| {
+                        let by_price = if is_ascending { a.price.cmp(&b.price) } else { b.price.cmp(&a.price) };
+                        by_price.then_with(|| b.size.cmp(&a.size))
+                    });
+                    sorted
+                })
+                .collect();
+
+            let (merged, expected) = if is_ascending {
+                (merge_asks(books.clone(), false, false), merge_asks(expected_input, false, true))
+            } else {
+                (merge_bids(books.clone(), false, false), merge_bids(expected_input, false, true))
+            };
+            assert!(merged == expected, "the {} merge differs from a sequential stable sort", if is_ascending { "ask" } else { "bid" });
+        }
+    }
+}
+
+// Built with only one of the coinbase and gemini features, e.g. `cargo test --no-default-features --features gemini`
+#[cfg(all(test, not(all(feature = "coinbase", feature = "gemini"))))]
+mod single_exchange_tests {
+    use super::*;
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+    use wiremock::matchers::{method, path};
+
+    #[tokio::test]
+    async fn test_run_merges_only_the_compiled_exchange() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/coinbase"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "bids": [["99", "1", 1]],
+                "asks": [["101", "1", 1]],
+                "sequence": 1,
+                "auction_mode": false,
+                "auction": null,
+                "time": "2025-11-01T00:00:00Z"
+            })))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/gemini"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "bids": [{"price": "99.5", "amount": "1", "timestamp": "1761996296"}],
+                "asks": [{"price": "100.5", "amount": "1", "timestamp": "1761996296"}]
+            })))
+            .mount(&server)
+            .await;
+
+        let endpoints = Endpoints {
+            coinbase: format!("{}/coinbase", server.uri()),
+            gemini: format!("{}/gemini", server.uri()),
+            binance: None,
+            bitstamp: None,
+            api_keys: ApiKeys::default(),
+        };
+        let args = Args::parse_from(["ob-aggregator-rs", "--qty", "1", "--burst", "2"]);
+        let client = api_client::create_client(api_client::DEFAULT_TIMEOUT, HeaderMap::new());
+
+        assert_eq!(endpoints.exchanges(&args).len(), 1);
+        let output = run(&args, &endpoints, &client, Some(&create_rate_limiter(&args).unwrap())).await.unwrap();
+
+        let asks = output.asks.unwrap();
+        assert_eq!(asks.len(), 1);
+        assert!(compiled_in(asks[0].source), "{} is not part of this build", asks[0].source);
+        let expected = if cfg!(feature = "coinbase") { "101" } else { "100.5" };
+        assert_eq!(output.buy_price, Some(Ok(Decimal::from_str_exact(expected).unwrap())));
+    }
+
+}